@@ -0,0 +1,310 @@
+use crate::camera::Camera;
+use crate::configs::{CamConfig, PostProcessConfig, RenderConfig, SequenceConfig};
+use crate::geometry::{Motion, Scene};
+use crate::postprocess;
+use crate::raytracer::embree::EmbreeRayTracerBuilder;
+use crate::renderer::Renderer;
+use anyhow::{Context, Result, bail};
+use glam::{EulerRot, Mat4, Quat, Vec3};
+use image::RgbImage;
+use image::buffer::ConvertBuffer;
+use std::f32::consts::TAU;
+use std::ops::{Add, Mul, Range, Sub};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Interpolation {
+    /// holds the value of the last keyframe until the next one
+    Step,
+    Linear,
+    /// catmull-rom spline through the keyframes, the speed does not jump at every keyframe
+    Smooth,
+}
+
+/// Values at given frames, interpolated in between.
+/// Before the first and after the last keyframe the value stays the same
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keyframes: Vec<(f32, T)>,
+    pub interpolation: Interpolation,
+}
+
+impl<T> Track<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    /// keyframes are (frame, value), in any order
+    pub fn new(mut keyframes: Vec<(f32, T)>, interpolation: Interpolation) -> Result<Self> {
+        if keyframes.is_empty() {
+            bail!("Animation track needs at least one keyframe");
+        }
+        keyframes.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        Ok(Self {
+            keyframes,
+            interpolation,
+        })
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self {
+            keyframes: vec![(0.0, value)],
+            interpolation: Interpolation::Step,
+        }
+    }
+
+    pub fn sample(&self, frame: f32) -> T {
+        let keys = &self.keyframes;
+        // first keyframe after this frame
+        let next = keys.partition_point(|(f, _)| *f <= frame);
+        if next == 0 {
+            return keys[0].1;
+        }
+        if next == keys.len() {
+            return keys[next - 1].1;
+        }
+
+        let (f1, v1) = keys[next - 1];
+        let (f2, v2) = keys[next];
+        let t = (frame - f1) / (f2 - f1);
+
+        match self.interpolation {
+            Interpolation::Step => v1,
+            Interpolation::Linear => v1 + (v2 - v1) * t,
+            Interpolation::Smooth => {
+                // the ends are extended so that the curve keeps going straight
+                let v0 = if next >= 2 {
+                    keys[next - 2].1
+                } else {
+                    v1 + (v1 - v2)
+                };
+                let v3 = keys.get(next + 1).map_or(v2 + (v2 - v1), |(_, v)| *v);
+                catmull_rom(v0, v1, v2, v3, t)
+            }
+        }
+    }
+}
+
+fn catmull_rom<T>(p0: T, p1: T, p2: T, p3: T, t: f32) -> T
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p3 - p0 + (p1 - p2) * 3.0) * t3)
+        * 0.5
+}
+
+pub struct CameraAnimation {
+    pub pos: Track<Vec3>,
+    pub lookat: Track<Vec3>,
+    /// None keeps the fov of the config
+    pub fov: Option<Track<f32>>,
+}
+
+impl CameraAnimation {
+    /// the camera moves towards where it is on the next frame, so that the shutter blurs it
+    pub fn config_at(&self, config: CamConfig, frame: f32) -> CamConfig {
+        let mut config = config;
+        config.pos = self.pos.sample(frame);
+        config.lookat = self.lookat.sample(frame);
+        if let Some(fov) = &self.fov {
+            config.fov = fov.sample(frame);
+        }
+
+        config.with_motion(
+            self.pos.sample(frame + 1.0),
+            self.lookat.sample(frame + 1.0),
+        )
+    }
+}
+
+/// Moves geometry that was already added to the scene
+pub struct ObjectAnimation {
+    /// indices into `Scene::geometry`, in the order they were added
+    pub geometry: Range<usize>,
+    /// rotation and scale are around this point
+    pub pivot: Vec3,
+    pub translation: Track<Vec3>,
+    /// euler angles in radians, in XYZ order
+    pub rotation: Track<Vec3>,
+    pub scale: Track<Vec3>,
+}
+
+impl ObjectAnimation {
+    pub fn new(geometry: Range<usize>, pivot: Vec3) -> Self {
+        Self {
+            geometry,
+            pivot,
+            translation: Track::constant(Vec3::ZERO),
+            rotation: Track::constant(Vec3::ZERO),
+            scale: Track::constant(Vec3::ONE),
+        }
+    }
+
+    /// one full turn around the vertical axis every `frames`
+    pub fn turntable(geometry: Range<usize>, pivot: Vec3, frames: u32) -> Self {
+        Self::new(geometry, pivot).with_rotation(Track {
+            keyframes: vec![(0.0, Vec3::ZERO), (frames as f32, Vec3::new(0.0, TAU, 0.0))],
+            interpolation: Interpolation::Linear,
+        })
+    }
+
+    pub fn with_rotation(mut self, rotation: Track<Vec3>) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// relative to where the geometry was added
+    pub fn transform_at(&self, frame: f32) -> Mat4 {
+        let rotation = self.rotation.sample(frame);
+        let rotation = Quat::from_euler(EulerRot::XYZ, rotation.x, rotation.y, rotation.z);
+
+        Mat4::from_translation(self.pivot + self.translation.sample(frame))
+            * Mat4::from_scale_rotation_translation(self.scale.sample(frame), rotation, Vec3::ZERO)
+            * Mat4::from_translation(-self.pivot)
+    }
+}
+
+#[derive(Default)]
+pub struct Animation {
+    pub camera: Option<CameraAnimation>,
+    pub objects: Vec<ObjectAnimation>,
+}
+
+impl Animation {
+    pub fn camera_at(&self, config: CamConfig, frame: u32) -> CamConfig {
+        match &self.camera {
+            Some(camera) => camera.config_at(config, frame as f32),
+            None => config,
+        }
+    }
+
+    // where every object is at the start and end of the frame
+    fn object_transforms(&self, frame: u32) -> Vec<[Mat4; 2]> {
+        let frame = frame as f32;
+        self.objects
+            .iter()
+            .map(|object| [object.transform_at(frame), object.transform_at(frame + 1.0)])
+            .collect()
+    }
+
+    fn apply(&self, scene: &mut Scene, transforms: &[[Mat4; 2]]) {
+        for (object, [start, end]) in self.objects.iter().zip(transforms) {
+            for geometry in &mut scene.geometry[object.geometry.clone()] {
+                geometry.motion = Some(Motion::linear(*start, *end));
+            }
+        }
+    }
+}
+
+/// Renders every frame of the sequence to its own file.
+/// The acceleration structure is only built again when an object moved since the last frame
+pub fn render_sequence(
+    device: &embree4_rs::Device,
+    mut scene: Scene,
+    animation: &Animation,
+    sequence: &SequenceConfig,
+    camconfig: CamConfig,
+    renderconfig: RenderConfig,
+    postconfig: &PostProcessConfig,
+) -> Result<()> {
+    std::fs::create_dir_all(sequence.output_dir)
+        .with_context(|| format!("Error creating output directory {}", sequence.output_dir))?;
+
+    let mut frame = sequence.frames.start;
+    while frame < sequence.frames.end {
+        let instant = std::time::Instant::now();
+
+        let transforms = animation.object_transforms(frame);
+        animation.apply(&mut scene, &transforms);
+
+        let mut raytracer_builder = EmbreeRayTracerBuilder::new(device);
+        let built = scene
+            .build_scene(&mut raytracer_builder)
+            .context("Error building scene")?;
+        let renderer = Renderer::new(built, renderconfig.clone());
+
+        println!(
+            "Building scene for frame {frame} took: {:?}",
+            instant.elapsed()
+        );
+
+        loop {
+            let instant = std::time::Instant::now();
+            let camera = Camera::new(animation.camera_at(camconfig, frame));
+
+            let mut image = renderer.render_par(&camera);
+            postprocess::apply(&mut image, &postconfig.stages);
+
+            let path = sequence.frame_path(frame);
+            let image: RgbImage = image.convert();
+            image
+                .save(&path)
+                .with_context(|| format!("Error saving frame {}", path.display()))?;
+
+            println!("Frame {frame} rendered in: {:?}", instant.elapsed());
+            // no point in rendering more frames with missing textures
+            renderer.check_textures()?;
+
+            frame += 1;
+            if frame >= sequence.frames.end || animation.object_transforms(frame) != transforms {
+                break;
+            }
+        }
+
+        scene = renderer.into_scene();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(interpolation: Interpolation) -> Track<f32> {
+        // out of order on purpose
+        Track::new(vec![(10.0, 4.0), (0.0, 0.0), (20.0, 4.0)], interpolation).unwrap()
+    }
+
+    #[test]
+    fn tracks_hold_their_ends() {
+        assert!(Track::<f32>::new(Vec::new(), Interpolation::Linear).is_err());
+        for interpolation in [
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::Smooth,
+        ] {
+            let track = track(interpolation);
+            assert_eq!(track.sample(-5.0), 0.0);
+            assert_eq!(track.sample(25.0), 4.0);
+            // every interpolation goes through the keyframes
+            assert!((track.sample(10.0) - 4.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn interpolation_between_keyframes() {
+        assert_eq!(track(Interpolation::Step).sample(5.0), 0.0);
+        assert_eq!(track(Interpolation::Linear).sample(5.0), 2.0);
+        // overshoots the flat part a little instead of stopping dead at the keyframe
+        let smooth = track(Interpolation::Smooth);
+        assert!(smooth.sample(5.0) > 2.0);
+        assert!(smooth.sample(15.0) > 4.0);
+    }
+
+    #[test]
+    fn objects_turn_around_the_pivot() {
+        let pivot = Vec3::new(10.0, 0.0, 0.0);
+        let object = ObjectAnimation::turntable(0..1, pivot, 4);
+        // a quarter turn after a quarter of the frames
+        let transform = object.transform_at(1.0);
+        assert!(transform.transform_point3(pivot).abs_diff_eq(pivot, 1e-4));
+        let moved = transform.transform_point3(pivot + Vec3::X);
+        assert!(moved.abs_diff_eq(pivot - Vec3::Z, 1e-4), "{moved}");
+    }
+}
@@ -1,11 +1,19 @@
-use crate::configs::CamConfig;
-use crate::raytracer::Ray;
-use glam::Vec3;
+use crate::configs::{CamConfig, FisheyeMapping, Projection};
+use crate::raytracer::{Ray, RayCone};
+use glam::{Vec2, Vec3};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
 
 #[derive(Debug)]
 pub struct Camera {
     pub config: CamConfig,
-    // pub up: Vec3,
+    /// where the camera is at time 0.0, see `frame_at` for moving cameras
+    pub frame: CameraFrame,
+}
+
+/// Everything needed to generate rays from a given camera position
+#[derive(Debug, Clone, Copy)]
+pub struct CameraFrame {
+    pub pos: Vec3,
     // pub at_point: Vec3,
     /// Location of pixel 0, 0
     pub pixel00_loc: Vec3,
@@ -14,16 +22,20 @@ pub struct Camera {
     /// Offset to pixel below
     pub pixel_delta_v: Vec3,
     // pub tan_halfh: f32,
+    /// camera axes, for the lens and the non perspective projections
+    pub forward: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
 }
 
-impl Camera {
-    pub fn new(config: CamConfig) -> Self {
+impl CameraFrame {
+    pub fn new(config: &CamConfig, pos: Vec3, lookat: Vec3) -> Self {
         let w = config.w as f32;
         let h = config.h as f32;
 
         const UP: Vec3 = Vec3::Y;
 
-        let forward = (config.lookat - config.pos).normalize();
+        let forward = (lookat - pos).normalize();
         let right = forward.cross(UP).normalize();
         // recompute UP exactly as the cross product  right X forward
         let up = right.cross(forward).normalize();
@@ -42,43 +54,231 @@ impl Camera {
         let pixel_delta_v = vp_v / h;
 
         // Calculate the location of the upper left pixel.
-        let vp_upper_left = (config.pos + forward) - ((vp_u / 2.0) + (vp_v / 2.0));
+        let vp_upper_left = (pos + forward) - ((vp_u / 2.0) + (vp_v / 2.0));
 
         let pixel00_loc = vp_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
 
-        // println!("{:#?}", Self {
-        //     pos,
-        //     up,
-        //     at_point,
-        //     pixel00_loc,
-        //     pixel_delta_u,
-        //     pixel_delta_v,
-        //     tan_halfh,
-        //     w: w_u32,
-        //     h: h_u32,
-        // });
-        // panic!();
-
         Self {
-            config,
-            // up,
-            // at_point,
+            pos,
             pixel00_loc,
             pixel_delta_u,
             pixel_delta_v,
-            // tan_halfh,
-            // TODO: REMOVE
-            // background: LinearRgba::new(0.1, 0.1, 0.8, 1.0),
+            forward,
+            right,
+            up,
         }
     }
+}
+
+impl Camera {
+    pub fn new(config: CamConfig) -> Self {
+        let frame = CameraFrame::new(&config, config.pos, config.lookat);
+
+        Self { config, frame }
+    }
 
-    pub fn generate_ray(&self, x: u32, y: u32, (j1, j2): (f32, f32)) -> Ray {
+    /// the camera moves linearly from its config to `motion` over the frame
+    pub fn frame_at(&self, time: f32) -> CameraFrame {
+        match &self.config.motion {
+            Some(motion) => CameraFrame::new(
+                &self.config,
+                self.config.pos.lerp(motion.pos, time),
+                self.config.lookat.lerp(motion.lookat, time),
+            ),
+            None => self.frame,
+        }
+    }
+
+    /// jitter moves the sample inside the pixel, lens picks a point in the aperture,
+    /// and time picks when the ray is shot while the shutter is open.
+    /// None for pixels that do not see the scene (outside of the fisheye circle)
+    pub fn generate_ray(
+        &self,
+        x: u32,
+        y: u32,
+        jitter: (f32, f32),
+        lens: (f32, f32),
+        time: f32,
+    ) -> Option<Ray> {
+        let time = self.config.shutter_open
+            + time * (self.config.shutter_close - self.config.shutter_open);
+        let frame = self.frame_at(time);
+
+        let w = self.config.w as f32;
+        let h = self.config.h as f32;
+        // position in the image, in [0, 1]
+        let u = (x as f32 + jitter.0) / w;
+        let v = (y as f32 + jitter.1) / h;
+
+        let ray = match self.config.projection {
+            Projection::Perspective => self.perspective_ray(&frame, x, y, jitter, lens),
+            Projection::Orthographic { view_width } => {
+                let view_height = view_width * h / w;
+                let origin = frame.pos + frame.right * (u - 0.5) * view_width
+                    - frame.up * (v - 0.5) * view_height;
+                Ray::new(origin, frame.forward)
+            }
+            Projection::Equirectangular => {
+                // longitude 0 is straight ahead, latitude goes from up to down
+                let phi = (u - 0.5) * 2.0 * PI;
+                let theta = (0.5 - v) * PI;
+                let dir = theta.cos() * (phi.sin() * frame.right + phi.cos() * frame.forward)
+                    + theta.sin() * frame.up;
+                Ray::new(frame.pos, dir.normalize())
+            }
+            Projection::CubeMap { stereo } => Self::cube_map_ray(&frame, u, v, stereo),
+            Projection::Fisheye { mapping, fov } => {
+                // unit circle inscribed in the image
+                let radius = w.min(h) / 2.0;
+                let nx = (x as f32 + jitter.0 - w / 2.0) / radius;
+                let ny = (h / 2.0 - y as f32 - jitter.1) / radius;
+                let r = (nx * nx + ny * ny).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta_max = fov / 2.0;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r * theta_max,
+                    // r = 2 sin(theta / 2), normalized so that the border is at theta_max
+                    FisheyeMapping::Equisolid => {
+                        2.0 * (r * (theta_max / 2.0).sin()).clamp(-1.0, 1.0).asin()
+                    }
+                };
+                let phi = ny.atan2(nx);
+                let dir = theta.cos() * frame.forward
+                    + theta.sin() * (phi.cos() * frame.right + phi.sin() * frame.up);
+                Ray::new(frame.pos, dir.normalize())
+            }
+        };
+
+        Some(ray.with_time(time).with_cone(self.pixel_cone()))
+    }
+
+    /// cone covering a single pixel, the size of the texture footprint of camera rays
+    pub fn pixel_cone(&self) -> RayCone {
+        let w = self.config.w as f32;
+        let h = self.config.h as f32;
+
+        match self.config.projection {
+            Projection::Perspective => {
+                // a lens focuses the rays, so it is the same cone as the pinhole one on the focus plane
+                RayCone::new(0.0, 2.0 * (self.config.fov / 2.0).tan() / h)
+            }
+            Projection::Orthographic { view_width } => RayCone::new(view_width / w, 0.0),
+            Projection::Equirectangular => RayCone::new(0.0, 2.0 * PI / w),
+            // every face is 90 degrees
+            Projection::CubeMap { .. } => RayCone::new(0.0, FRAC_PI_2 / (w / 3.0)),
+            Projection::Fisheye { fov, .. } => RayCone::new(0.0, fov / w.min(h)),
+        }
+    }
+
+    fn perspective_ray(
+        &self,
+        frame: &CameraFrame,
+        x: u32,
+        y: u32,
+        (j1, j2): (f32, f32),
+        (l1, l2): (f32, f32),
+    ) -> Ray {
         let pc = Vec3::new((x as f32) + j1, (y as f32) + j2, 0.0);
 
         let pixel_sample =
-            self.pixel00_loc + (pc.x * self.pixel_delta_u) + (pc.y * self.pixel_delta_v);
-        let dir = (pixel_sample - self.config.pos).normalize();
+            frame.pixel00_loc + (pc.x * frame.pixel_delta_u) + (pc.y * frame.pixel_delta_v);
+        let dir = (pixel_sample - frame.pos).normalize();
+
+        if self.config.aperture <= 0.0 {
+            return Ray::new(frame.pos, dir);
+        }
+
+        // thin lens: every ray through the same pixel converges on the focus plane
+        // pixel_sample is on the plane at distance 1.0, so scaling gets us to the focus plane
+        let focus_point = frame.pos + (pixel_sample - frame.pos) * self.config.focus_distance;
+
+        let lens = self.sample_aperture(l1, l2) * self.config.aperture;
+        let origin = frame.pos + lens.x * frame.right + lens.y * frame.up;
+
+        Ray::new(origin, (focus_point - origin).normalize())
+    }
+
+    // faces are laid out in a 3x2 grid: +x -x +y / -y +z -z
+    // stereo stacks the left eye on top of the right one
+    fn cube_map_ray(frame: &CameraFrame, u: f32, v: f32, stereo: Option<f32>) -> Ray {
+        let (v, eye) = match stereo {
+            Some(_) if v < 0.5 => (v * 2.0, -1.0),
+            Some(_) => (v * 2.0 - 1.0, 1.0),
+            None => (v, 0.0),
+        };
+
+        let column = ((u * 3.0) as usize).min(2);
+        let row = ((v * 2.0) as usize).min(1);
+        // position inside the face, in [-1, 1]
+        let a = (u * 3.0 - column as f32) * 2.0 - 1.0;
+        let b = (v * 2.0 - row as f32) * 2.0 - 1.0;
+
+        let (f, r, u) = (frame.forward, frame.right, frame.up);
+        // (face forward, face right, face up), as seen from inside the cube
+        let (face_forward, face_right, face_up) = match (row, column) {
+            (0, 0) => (r, -f, u),
+            (0, 1) => (-r, f, u),
+            (0, _) => (u, r, -f),
+            (_, 0) => (-u, r, f),
+            (_, 1) => (f, r, u),
+            (_, _) => (-f, -r, u),
+        };
+        let dir = (face_forward + a * face_right - b * face_up).normalize();
+
+        let origin = match stereo {
+            // omni-directional stereo: the eyes rotate around the camera to face the direction of every ray,
+            // so the offset is perpendicular to the horizontal part of it
+            Some(separation) => {
+                let horizontal = dir - dir.dot(frame.up) * frame.up;
+                let side = horizontal.cross(frame.up).normalize_or_zero();
+                frame.pos + side * eye * separation / 2.0
+            }
+            None => frame.pos,
+        };
 
-        Ray::new(self.config.pos, dir)
+        Ray::new(origin, dir)
     }
+
+    /// point in the unit aperture, either a disk or a regular polygon with one vertex per blade
+    fn sample_aperture(&self, u1: f32, u2: f32) -> Vec2 {
+        let blades = self.config.blades;
+        if blades < 3 {
+            return sample_concentric_disk(u1, u2);
+        }
+
+        // pick a blade, then a point in the triangle between the center and its two vertices
+        let scaled = u1 * blades as f32;
+        let blade = (scaled as u32).min(blades - 1);
+        let u1 = scaled - blade as f32;
+
+        let angle_step = 2.0 * PI / blades as f32;
+        let a0 = self.config.blade_rotation + blade as f32 * angle_step;
+        let v0 = Vec2::from_angle(a0);
+        let v1 = Vec2::from_angle(a0 + angle_step);
+
+        // uniform point in the triangle (0, v0, v1)
+        let su = u1.sqrt();
+        let b0 = su * (1.0 - u2);
+        let b1 = su * u2;
+        v0 * b0 + v1 * b1
+    }
+}
+
+// Shirley and Chiu's mapping, keeps the stratification of u1 and u2
+fn sample_concentric_disk(u1: f32, u2: f32) -> Vec2 {
+    let offset = Vec2::new(u1, u2) * 2.0 - 1.0;
+    if offset == Vec2::ZERO {
+        return Vec2::ZERO;
+    }
+
+    let (r, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, FRAC_PI_4 * (offset.y / offset.x))
+    } else {
+        (offset.y, FRAC_PI_2 - FRAC_PI_4 * (offset.x / offset.y))
+    };
+
+    Vec2::from_angle(theta) * r
 }
@@ -21,6 +21,18 @@ impl Rgba {
     pub const fn rgb(r: f32, g: f32, b: f32) -> Rgba {
         Self::new(r, g, b, 1.0)
     }
+
+    pub const fn from_array([r, g, b, a]: [f32; 4]) -> Self {
+        Self::new(r, g, b, a)
+    }
+
+    pub const fn to_array(self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        Self::from_array(self.to_array().map(f))
+    }
 }
 
 impl Add for Rgba {
@@ -36,6 +48,19 @@ impl Add for Rgba {
     }
 }
 
+impl Div for Rgba {
+    type Output = Rgba;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        Rgba {
+            r: self.r / rhs.r,
+            g: self.g / rhs.g,
+            b: self.b / rhs.b,
+            a: self.a / rhs.a,
+        }
+    }
+}
+
 impl Div<f32> for Rgba {
     type Output = Rgba;
 
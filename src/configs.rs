@@ -1,9 +1,14 @@
 #![allow(dead_code)] // many presets available, not all used
 
-use glam::Vec3;
+use crate::geometry::{Material, PointShape, TextureFilter};
+use crate::medium::Medium;
+use crate::postprocess::{BloomConfig, PostProcess};
+use glam::{Mat4, Vec3};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 // terrible name for both the enum itself and the things inside
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RayTransportConfig {
     // ray can EITHER reflect, refract or scatter
     MonteCarloSingle,
@@ -13,7 +18,7 @@ pub enum RayTransportConfig {
     LoopScatter(u32), // number of scatters, > 0
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RenderConfig {
     pub min_depth: u32,
     /// inverse of the probability of still going deeper when if depth >= min_depth
@@ -23,6 +28,10 @@ pub struct RenderConfig {
     pub rays_per_pixel: u32,
     pub diffuse_strength: f32,
     pub ray_transport: RayTransportConfig,
+    /// trace wavelengths instead of rgb, needed for dispersion
+    pub spectral: bool,
+    /// only matters for meshes, other shapes have no ray footprint on their uvs
+    pub texture_filter: TextureFilter,
 }
 
 impl RenderConfig {
@@ -46,9 +55,21 @@ impl RenderConfig {
             rays_per_pixel,
             diffuse_strength,
             ray_transport,
+            spectral: false,
+            texture_filter: TextureFilter::Trilinear,
         }
     }
 
+    pub const fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    pub const fn with_texture_filter(mut self, texture_filter: TextureFilter) -> Self {
+        self.texture_filter = texture_filter;
+        self
+    }
+
     pub const fn fastest() -> Self {
         Self::new(
             4,
@@ -99,18 +120,66 @@ impl RenderConfig {
     }
 
     // does not use a lot of monte carlo approaches
+    // the sharpest textures at grazing angles too
     pub const fn slowest() -> Self {
         Self::new(5, 0.8, true, 4, 20, 1.0, RayTransportConfig::LoopScatter(5))
+            .with_texture_filter(TextureFilter::Ewa)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
+pub enum FisheyeMapping {
+    /// distance from the center of the image is proportional to the angle
+    Equidistant,
+    /// preserves areas, like most real fisheye lenses
+    Equisolid,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Projection {
+    /// uses CamConfig::fov, the only one with depth of field
+    Perspective,
+    /// parallel rays, view_width is the width of the image in scene units
+    Orthographic { view_width: f32 },
+    /// full 360x180 panorama, the image should be 2:1
+    Equirectangular,
+    /// 6 square faces in a 3x2 grid: +x -x +y / -y +z -z, relative to the camera
+    /// with stereo, the left eye is on the top half and the right eye on the bottom half (image should be 3:4)
+    /// eye_separation uses omni-directional stereo, so it works in every direction
+    CubeMap { stereo: Option<f32> },
+    /// circular image inscribed in the frame, covering fov (radians, can be over 180 degrees)
+    Fisheye { mapping: FisheyeMapping, fov: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct CamConfig {
     pub w: u32,
     pub h: u32,
     pub pos: Vec3,
     pub lookat: Vec3,
     pub fov: f32,
+    pub projection: Projection,
+    /// radius of the lens, 0.0 is a pinhole camera with everything in focus
+    pub aperture: f32,
+    /// distance from pos to the plane that is in focus
+    pub focus_distance: f32,
+    /// number of aperture blades, bokeh takes this shape. 0 (or less than 3) is a perfect circle
+    pub blades: u32,
+    /// rotation of the aperture polygon, in radians
+    pub blade_rotation: f32,
+    /// part of the frame the shutter is open for. time goes from 0.0 to 1.0 over the frame,
+    /// and each ray is shot at a random time in [shutter_open, shutter_close]
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    /// where the camera is at the end of the frame, for camera motion blur
+    pub motion: Option<CameraMotion>,
+}
+
+/// The camera moves linearly from `CamConfig::pos` and `CamConfig::lookat` to these over the frame
+#[derive(Debug, Clone, Copy)]
+pub struct CameraMotion {
+    pub pos: Vec3,
+    pub lookat: Vec3,
 }
 
 impl CamConfig {
@@ -120,6 +189,14 @@ impl CamConfig {
         pos: Vec3::new(280.0, 265.0, -500.0),
         lookat: Vec3::new(280.0, 260.0, 0.0),
         fov: 60.0_f32.to_radians(),
+        projection: Projection::Perspective,
+        aperture: 0.0,
+        focus_distance: 500.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
     };
 
     pub const BALANCED: Self = Self {
@@ -128,6 +205,14 @@ impl CamConfig {
         pos: Vec3::new(280.0, 265.0, -500.0),
         lookat: Vec3::new(280.0, 260.0, 0.0),
         fov: 60.0_f32.to_radians(),
+        projection: Projection::Perspective,
+        aperture: 0.0,
+        focus_distance: 500.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
     };
 
     pub const SLOW: Self = Self {
@@ -136,5 +221,257 @@ impl CamConfig {
         pos: Vec3::new(280.0, 265.0, -480.0),
         lookat: Vec3::new(280.0, 260.0, 0.0),
         fov: 65.0_f32.to_radians(),
+        projection: Projection::Perspective,
+        aperture: 0.0,
+        focus_distance: 480.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
+    };
+
+    // the suzanne on the right is in focus, the back wall is not
+    pub const DEPTH_OF_FIELD: Self = Self {
+        w: 1920,
+        h: 1080,
+        pos: Vec3::new(280.0, 265.0, -500.0),
+        lookat: Vec3::new(280.0, 260.0, 0.0),
+        fov: 60.0_f32.to_radians(),
+        projection: Projection::Perspective,
+        aperture: 8.0,
+        focus_distance: 650.0,
+        blades: 6,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
     };
+
+    // VR panorama from the middle of the box
+    pub const PANORAMA: Self = Self {
+        w: 4096,
+        h: 2048,
+        pos: Vec3::new(280.0, 275.0, 280.0),
+        lookat: Vec3::new(280.0, 275.0, 560.0),
+        fov: 90.0_f32.to_radians(),
+        projection: Projection::Equirectangular,
+        aperture: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
+    };
+
+    pub const STEREO_CUBE_MAP: Self = Self {
+        w: 3 * 1024,
+        h: 4 * 1024,
+        pos: Vec3::new(280.0, 275.0, 280.0),
+        lookat: Vec3::new(280.0, 275.0, 560.0),
+        fov: 90.0_f32.to_radians(),
+        projection: Projection::CubeMap { stereo: Some(6.4) },
+        aperture: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
+    };
+
+    pub const FISHEYE: Self = Self {
+        w: 1080,
+        h: 1080,
+        pos: Vec3::new(280.0, 265.0, -100.0),
+        lookat: Vec3::new(280.0, 260.0, 0.0),
+        fov: 60.0_f32.to_radians(),
+        projection: Projection::Fisheye {
+            mapping: FisheyeMapping::Equisolid,
+            fov: 180.0_f32.to_radians(),
+        },
+        aperture: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
+    };
+
+    // technical view of the box, from the front
+    pub const ORTHOGRAPHIC: Self = Self {
+        w: 1080,
+        h: 1080,
+        pos: Vec3::new(278.0, 274.0, -500.0),
+        lookat: Vec3::new(278.0, 274.0, 0.0),
+        fov: 60.0_f32.to_radians(),
+        projection: Projection::Orthographic { view_width: 600.0 },
+        aperture: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        blade_rotation: 0.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        motion: None,
+    };
+
+    /// aperture from an f-number, like a real lens: radius = focal length / (2 * N)
+    /// focal_length is in the same units as the scene
+    pub const fn with_f_stop(mut self, focal_length: f32, f_stop: f32) -> Self {
+        self.aperture = focal_length / (2.0 * f_stop);
+        self
+    }
+
+    pub const fn with_focus(mut self, focus_distance: f32) -> Self {
+        self.focus_distance = focus_distance;
+        self
+    }
+
+    pub const fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter_open = open;
+        self.shutter_close = close;
+        self
+    }
+
+    pub const fn with_motion(mut self, pos: Vec3, lookat: Vec3) -> Self {
+        self.motion = Some(CameraMotion { pos, lookat });
+        self
+    }
+}
+
+/// ordered list of post processing stages applied after rendering
+#[derive(Debug)]
+pub struct PostProcessConfig {
+    pub stages: Vec<PostProcess>,
+}
+
+impl PostProcessConfig {
+    pub fn new(stages: Vec<PostProcess>) -> Self {
+        Self { stages }
+    }
+
+    // what the renderer always did before the chain existed
+    pub fn tonemap_only() -> Self {
+        Self::new(vec![PostProcess::Tonemap])
+    }
+
+    /// for sequences, auto exposure would change from frame to frame
+    pub fn fixed_exposure(stops: f32) -> Self {
+        Self::new(vec![PostProcess::Exposure(stops), PostProcess::Tonemap])
+    }
+
+    pub fn auto_exposure() -> Self {
+        Self::new(vec![
+            PostProcess::AutoExposure {
+                key: 0.18,
+                min_scale: 0.01,
+                max_scale: 100.0,
+            },
+            PostProcess::Tonemap,
+        ])
+    }
+
+    // bloom is applied in HDR, before tonemapping, so that only really bright emitters glare
+    pub fn cinematic() -> Self {
+        Self::new(vec![
+            PostProcess::AutoExposure {
+                key: 0.18,
+                min_scale: 0.01,
+                max_scale: 100.0,
+            },
+            PostProcess::Bloom(BloomConfig {
+                threshold: 1.5,
+                intensity: 0.15,
+                radius: 2.0,
+                octaves: 5,
+            }),
+            PostProcess::Vignette { strength: 0.4 },
+            PostProcess::Tonemap,
+            PostProcess::FilmGrain { strength: 0.03 },
+        ])
+    }
+}
+
+/// renders the frames in [frames.start, frames.end) to numbered images in output_dir
+#[derive(Debug, Clone)]
+pub struct SequenceConfig {
+    pub frames: Range<u32>,
+    pub output_dir: &'static str,
+}
+
+impl SequenceConfig {
+    pub const TURNTABLE: Self = Self {
+        frames: 0..120,
+        output_dir: "frames",
+    };
+
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        Path::new(self.output_dir).join(format!("frame_{frame:04}.png"))
+    }
+}
+
+/// what is rendered
+#[derive(Debug, Clone, Copy)]
+pub enum SceneConfig {
+    /// the cornell box with suzanne in it
+    CornellBox,
+    /// outdoors, with the shapes and materials the box does not have
+    Outdoor,
+}
+
+/// a model file added to the scene, see `loaders::add_model`
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub path: &'static str,
+    pub transform: Mat4,
+    /// for PLY meshes and point clouds, OBJ files have their own materials
+    pub material: Material,
+    /// Some loads the vertices as a point cloud with this radius (where the file has none), faces are ignored
+    pub point_radius: Option<f32>,
+    /// what the points are drawn as, the files do not say
+    pub point_shape: PointShape,
+}
+
+impl ModelConfig {
+    pub fn mesh(path: &'static str, transform: Mat4) -> Self {
+        Self {
+            path,
+            transform,
+            material: Material::WHITE_MATERIAL,
+            point_radius: None,
+            point_shape: PointShape::Disc,
+        }
+    }
+
+    pub fn points(path: &'static str, transform: Mat4, radius: f32) -> Self {
+        Self {
+            path,
+            transform,
+            material: Material::WHITE_MATERIAL,
+            point_radius: Some(radius),
+            point_shape: PointShape::Disc,
+        }
+    }
+
+    pub fn with_material(mut self, material: Material) -> Self {
+        self.material = material;
+        self
+    }
+
+    pub fn with_point_shape(mut self, shape: PointShape) -> Self {
+        self.point_shape = shape;
+        self
+    }
+}
+
+/// A raw voxel grid file filling a box of the scene, see `VoxelGrid::load_raw`
+#[derive(Debug, Clone)]
+pub struct VolumeConfig {
+    pub path: &'static str,
+    pub min: Vec3,
+    pub max: Vec3,
+    /// the coefficients at density 1
+    pub medium: Medium,
 }
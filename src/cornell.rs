@@ -1,13 +1,12 @@
 use crate::color::Rgba;
+use crate::configs::{CamConfig, Projection};
 use crate::geometry::*;
 use GeomInfo::Mesh;
 use anyhow::*;
-use glam::{Mat4, Quat, Vec2, Vec3, Vec4};
-use gltf::{
-    // Gltf,
-    // buffer::Data,
-    mesh::util::*,
-};
+use fxhash::FxHashMap;
+use glam::{Mat4, Quat, Vec2, Vec3};
+use gltf::mesh::Mode;
+use image::Rgba32FImage;
 
 pub fn cornell_box(store: &mut Scene) -> Result<()> {
     let mut ceiling_mesh = MeshGeometry::default();
@@ -43,10 +42,10 @@ pub fn cornell_box(store: &mut Scene) -> Result<()> {
     left_mesh.verts.push((0., 548.8, 559.2));
     left_mesh.verts.push((0., 548.8, 0.));
 
-    left_mesh.tex_coords.push(Vec2::new(1.0, 0.0));
-    left_mesh.tex_coords.push(Vec2::new(0.0, 0.0));
-    left_mesh.tex_coords.push(Vec2::new(0.0, 1.0));
     left_mesh.tex_coords.push(Vec2::new(1.0, 1.0));
+    left_mesh.tex_coords.push(Vec2::new(0.0, 1.0));
+    left_mesh.tex_coords.push(Vec2::new(0.0, 0.0));
+    left_mesh.tex_coords.push(Vec2::new(1.0, 0.0));
 
     left_mesh.indices.push((0, 2, 1));
     left_mesh.indices.push((0, 3, 2));
@@ -217,19 +216,24 @@ pub fn cornell_box(store: &mut Scene) -> Result<()> {
     store.add_geometry(mirror);
     store.add_geometry(sphere_geometry);
 
-    let (cube_gltf_doc, cube_gltf_buff, _) = gltf::import("assets/cube.glb")?;
-    let cube_mesh = get_gltf_meshes(&cube_gltf_doc, &cube_gltf_buff)[0].clone();
+    let (cube_gltf_doc, cube_gltf_buff, _) =
+        gltf::import("assets/cube.glb").context("Error importing assets/cube.glb")?;
+    let cube_mesh = get_gltf_meshes(&cube_gltf_doc, &cube_gltf_buff)
+        .context("Error loading assets/cube.glb")?
+        .into_iter()
+        .next()
+        .context("assets/cube.glb has no meshes")?;
+    let cube_mesh = std::sync::Arc::new(cube_mesh);
 
     let transform = glam::Mat4::from_scale_rotation_translation(
         Vec3::splat(50.0),
         Quat::default(),
         Vec3::new(350.0, 50.0, 75.0),
     );
-    let mut bright_red_cube = cube_mesh.clone();
-    bright_red_cube.transform(transform);
+    let bright_red_cube = MeshInstance::new(cube_mesh.clone(), transform);
     store.add_geometry(Geometry::with_material(
         Material::EMISSIVE_MATERIAL,
-        Mesh(bright_red_cube),
+        GeomInfo::Instance(bright_red_cube),
     ));
 
     // let ambient = Light {
@@ -272,287 +276,565 @@ pub fn cornell_box(store: &mut Scene) -> Result<()> {
     Ok(())
 }
 
-// WARN: adds meshes one by one. ignores children. assumes all primitives are triangles
+/// Problems with a glTF primitive that stop it from becoming a triangle mesh
+#[derive(Debug)]
+pub enum GltfError {
+    /// points and lines can not be rendered
+    UnsupportedMode {
+        mesh: String,
+        primitive: usize,
+        mode: Mode,
+    },
+    MissingPositions {
+        mesh: String,
+        primitive: usize,
+    },
+    /// the number of indices does not make whole triangles
+    IncompleteTriangles {
+        mesh: String,
+        primitive: usize,
+        indices: usize,
+    },
+    IndexOutOfBounds {
+        mesh: String,
+        primitive: usize,
+        index: u32,
+        vertices: usize,
+    },
+    /// an orthographic camera needs a view of positive size
+    InvalidOrthographic {
+        camera: String,
+        xmag: f32,
+        ymag: f32,
+    },
+}
+
+impl std::fmt::Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedMode {
+                mesh,
+                primitive,
+                mode,
+            } => write!(
+                f,
+                "Primitive {primitive} of mesh {mesh} uses mode {mode:?}, only triangles, strips and fans are supported"
+            ),
+            Self::MissingPositions { mesh, primitive } => {
+                write!(f, "Primitive {primitive} of mesh {mesh} has no positions")
+            }
+            Self::IncompleteTriangles {
+                mesh,
+                primitive,
+                indices,
+            } => write!(
+                f,
+                "Primitive {primitive} of mesh {mesh} has {indices} indices, which is not a multiple of 3"
+            ),
+            Self::IndexOutOfBounds {
+                mesh,
+                primitive,
+                index,
+                vertices,
+            } => write!(
+                f,
+                "Primitive {primitive} of mesh {mesh} uses vertex {index}, but only has {vertices}"
+            ),
+            Self::InvalidOrthographic { camera, xmag, ymag } => write!(
+                f,
+                "Orthographic camera {camera} has xmag {xmag} and ymag {ymag}, both must be positive"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+// WARN: adds meshes one by one. ignores children (and node transforms)
+/// One mesh per primitive. TEXCOORD_0 goes to `tex_coords`, the other sets to `extra_tex_coords`,
+/// textures are sampled with the set of the base color texture
 pub fn get_gltf_meshes(
     gltf_doc: &gltf::Document,
     gltf_buff: &Vec<gltf::buffer::Data>,
-    // transform: &Transform,
-) -> Vec<MeshGeometry> {
-    // let matrix = transform.compute_matrix();
-
-    // for scene in gltf.scenes() {
-    //     for node in scene.nodes() {
-    //         if let Some(mesh) = node.mesh() {
-    //             for primitive in mesh.primitives() {
-    //             }
-    //         }
-    //     }
-    // }
-
+) -> Result<Vec<MeshGeometry>> {
     let meshes_iter = gltf_doc.meshes();
 
     let mut meshes: Vec<MeshGeometry> = Vec::with_capacity(meshes_iter.len());
 
     for mesh in meshes_iter {
-        for primitive in mesh.primitives() {
-            let mut verts: Vec<(f32, f32, f32)> = Vec::new();
-            let mut indices: Vec<u32> = Vec::new();
-            let mut tex_coords: Vec<Vec2> = Vec::new();
+        let mesh_name = mesh
+            .name()
+            .map_or_else(|| format!("#{}", mesh.index()), str::to_string);
 
+        for primitive in mesh.primitives() {
+            let primitive_index = primitive.index();
             let reader = primitive.reader(|buffer| Some(&gltf_buff[buffer.index()]));
-            if let Some(iter) = reader.read_positions() {
-                for vertex_position in iter {
-                    // let pos = Vec4::new(
-                    //     vertex_position[0],
-                    //     vertex_position[1],
-                    //     vertex_position[2],
-                    //     1.0,
-                    // );
-                    // let transformed = matrix * pos;
-                    // verts.push((transformed.x, transformed.y, transformed.z));
-                    verts.push((vertex_position[0], vertex_position[1], vertex_position[2]));
-                }
-            }
-            if let Some(iter) = reader.read_indices() {
-                match iter {
-                    ReadIndices::U8(inner) => {
-                        for index in inner {
-                            indices.push(index as u32);
-                        }
-                    }
-                    ReadIndices::U16(inner) => {
-                        for index in inner {
-                            indices.push(index as u32);
-                        }
-                    }
-                    ReadIndices::U32(inner) => {
-                        for index in inner {
-                            indices.push(index);
-                        }
-                    }
+
+            let verts: Vec<(f32, f32, f32)> = reader
+                .read_positions()
+                .ok_or_else(|| GltfError::MissingPositions {
+                    mesh: mesh_name.clone(),
+                    primitive: primitive_index,
+                })?
+                .map(|p| (p[0], p[1], p[2]))
+                .collect();
+
+            // non indexed primitives use every vertex in order
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(iter) => iter.into_u32().collect(),
+                None => (0..verts.len() as u32).collect(),
+            };
+
+            if let Some(index) = indices.iter().find(|i| **i as usize >= verts.len()) {
+                return Err(GltfError::IndexOutOfBounds {
+                    mesh: mesh_name,
+                    primitive: primitive_index,
+                    index: *index,
+                    vertices: verts.len(),
                 }
+                .into());
             }
 
-            if let Some(iter) = reader.read_tex_coords(0) {
-                match iter {
-                    ReadTexCoords::U8(inner) => {
-                        for uv in inner {
-                            tex_coords.push(Vec2::new(uv[0] as f32, uv[1] as f32));
+            let triangle_indices: Vec<(u32, u32, u32)> = match primitive.mode() {
+                Mode::Triangles => {
+                    if !indices.len().is_multiple_of(3) {
+                        return Err(GltfError::IncompleteTriangles {
+                            mesh: mesh_name,
+                            primitive: primitive_index,
+                            indices: indices.len(),
                         }
+                        .into());
                     }
-                    ReadTexCoords::U16(inner) => {
-                        for uv in inner {
-                            tex_coords.push(Vec2::new(uv[0] as f32, uv[1] as f32));
-                        }
-                    }
-                    ReadTexCoords::F32(inner) => {
-                        for uv in inner {
-                            tex_coords.push(Vec2::new(uv[0], uv[1]));
+                    indices
+                        .chunks_exact(3)
+                        .map(|chunk| (chunk[0], chunk[1], chunk[2]))
+                        .collect()
+                }
+                // every other triangle is flipped to keep the winding consistent
+                Mode::TriangleStrip => indices
+                    .windows(3)
+                    .enumerate()
+                    .map(|(i, w)| {
+                        if i % 2 == 0 {
+                            (w[0], w[1], w[2])
+                        } else {
+                            (w[1], w[0], w[2])
                         }
+                    })
+                    .collect(),
+                Mode::TriangleFan => indices
+                    .windows(2)
+                    .skip(1)
+                    .map(|w| (indices[0], w[0], w[1]))
+                    .collect(),
+                mode => {
+                    return Err(GltfError::UnsupportedMode {
+                        mesh: mesh_name,
+                        primitive: primitive_index,
+                        mode,
                     }
+                    .into());
                 }
-            }
+            };
 
-            // panic if not divisible by 3?????
-            let triangle_indices: Vec<(u32, u32, u32)> = indices
-                .chunks(3)
-                // .filter(|chunk| chunk.len() == 3) // Only keep complete groups of 3
-                .map(|chunk| (chunk[0], chunk[1], chunk[2]))
-                .collect();
+            // every vertex needs a uv, even if the file does not have them
+            let mut uv_sets: Vec<Vec<Vec2>> = Vec::new();
+            while let Some(iter) = reader.read_tex_coords(uv_sets.len() as u32) {
+                let mut tex_coords: Vec<Vec2> =
+                    iter.into_f32().map(|uv| Vec2::new(uv[0], uv[1])).collect();
+                tex_coords.resize(verts.len(), Vec2::ZERO);
+                uv_sets.push(tex_coords);
+            }
+            let tex_coords = if uv_sets.is_empty() {
+                vec![Vec2::ZERO; verts.len()]
+            } else {
+                uv_sets.remove(0)
+            };
 
-            let new_mesh = MeshGeometry {
+            let mut mesh = MeshGeometry {
                 verts,
                 indices: triangle_indices,
                 tex_coords,
+                extra_tex_coords: uv_sets,
+                tex_coord_set: 0,
             };
-            meshes.push(new_mesh);
+            // the base color texture says which set it is mapped with
+            if let Some(info) = primitive
+                .material()
+                .pbr_metallic_roughness()
+                .base_color_texture()
+            {
+                mesh.use_tex_coord_set(info.tex_coord() as usize)
+                    .with_context(|| format!("Primitive {primitive_index} of mesh {mesh_name}"))?;
+            }
+            meshes.push(mesh);
         }
     }
 
-    meshes
+    Ok(meshes)
 }
 
+/// Every mesh with `material`, plus the base color texture and the alpha mode of the glTF material
 pub fn add_gltf(
     store: &mut Scene,
     gltf_doc: &gltf::Document,
     gltf_buff: &Vec<gltf::buffer::Data>,
+    gltf_images: &[gltf::image::Data],
     matrix: Mat4,
     material: &Material,
 ) -> Result<()> {
-    for mut mesh in get_gltf_meshes(gltf_doc, gltf_buff) {
+    // in the same order as the meshes
+    let primitives = gltf_doc.meshes().flat_map(|mesh| mesh.primitives());
+    // texture of every image already added, the pixels are only stored once
+    let mut textures = FxHashMap::default();
+
+    for (mut mesh, primitive) in get_gltf_meshes(gltf_doc, gltf_buff)?
+        .into_iter()
+        .zip(primitives)
+    {
         mesh.transform(matrix);
-        let geometry = Geometry::with_material(material.clone(), Mesh(mesh));
+        let material = gltf_material(
+            store,
+            &primitive.material(),
+            gltf_images,
+            &mut textures,
+            material,
+        )?;
+        let geometry = Geometry::with_material(material, Mesh(mesh));
         let _ = store.add_geometry(geometry);
     }
 
+    add_gltf_lights(store, gltf_doc, matrix);
+
     Ok(())
 }
 
+// glTF lights are photometric (candela, lux), ours are radiometric
+const LUMENS_PER_WATT: f32 = 683.0;
+
+// calls f with every node of the default scene and its matrix, children included
+fn visit_gltf_nodes(
+    gltf_doc: &gltf::Document,
+    matrix: Mat4,
+    f: &mut impl FnMut(&gltf::Node, Mat4),
+) {
+    fn visit(node: gltf::Node, parent: Mat4, f: &mut impl FnMut(&gltf::Node, Mat4)) {
+        let world = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+        f(&node, world);
+        for child in node.children() {
+            visit(child, world, f);
+        }
+    }
+
+    if let Some(scene) = gltf_doc
+        .default_scene()
+        .or_else(|| gltf_doc.scenes().next())
+    {
+        for node in scene.nodes() {
+            visit(node, matrix, f);
+        }
+    }
+}
+
+/// KHR_lights_punctual lights. range is ignored, lights always fall off with the distance squared
+// `base` with the alpha mode and the base color texture, read with the sampler and KHR_texture_transform of the file
+fn gltf_material(
+    store: &mut Scene,
+    gltf_material: &gltf::Material,
+    gltf_images: &[gltf::image::Data],
+    textures: &mut FxHashMap<usize, u32>,
+    base: &Material,
+) -> Result<Material> {
+    let mut material = Material {
+        alpha_mode: AlphaMode::from_gltf(gltf_material),
+        ..base.clone()
+    };
+    let Some(info) = gltf_material.pbr_metallic_roughness().base_color_texture() else {
+        return Ok(material);
+    };
+
+    let source = info.texture().source().index();
+    let sampler = Sampler::from_gltf(&info);
+    let id = match textures.get(&source) {
+        // the same image with maybe another sampler
+        Some(id) => store.textures.add_view(*id, sampler),
+        None => {
+            let data = gltf_images
+                .get(source)
+                .with_context(|| format!("glTF image {source} is missing"))?;
+            let image = gltf_image(data).with_context(|| format!("Invalid glTF image {source}"))?;
+            let id = store.textures.add_image(image, sampler);
+            textures.insert(source, id);
+            id
+        }
+    };
+    material.texture = Texture::Image(id);
+
+    Ok(material)
+}
+
+// 8 and 16 bit channels go to [0, 1], one channel is gray and two are gray and alpha
+fn gltf_image(data: &gltf::image::Data) -> Result<Rgba32FImage> {
+    use gltf::image::Format;
+
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let values: Vec<f32> = data
+        .pixels
+        .chunks_exact(bytes)
+        .map(|value| match *value {
+            [x] => x as f32 / 255.0,
+            [a, b] => u16::from_le_bytes([a, b]) as f32 / 65535.0,
+            [a, b, c, d] => f32::from_le_bytes([a, b, c, d]),
+            _ => unreachable!("Chunks are 1, 2 or 4 bytes"),
+        })
+        .collect();
+
+    let (w, h) = (data.width, data.height);
+    let expected = w as usize * h as usize * channels;
+    if values.len() != expected {
+        bail!(
+            "{w}x{h} image has {} values, needs {expected}",
+            values.len()
+        );
+    }
+
+    Ok(Rgba32FImage::from_fn(w, h, |x, y| {
+        let i = (y as usize * w as usize + x as usize) * channels;
+        image::Rgba(match values[i..i + channels] {
+            [l] => [l, l, l, 1.0],
+            [l, a] => [l, l, l, a],
+            [r, g, b] => [r, g, b, 1.0],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!("Images have 1 to 4 channels"),
+        })
+    }))
+}
+
+pub fn add_gltf_lights(store: &mut Scene, gltf_doc: &gltf::Document, matrix: Mat4) {
+    use gltf::khr_lights_punctual::Kind;
+
+    // glTF is in meters, the intensity of point lights has to follow the scale of the scene
+    // so that they light things at the same (scaled) distance the same way
+    let scale = (matrix.x_axis.truncate().length()
+        + matrix.y_axis.truncate().length()
+        + matrix.z_axis.truncate().length())
+        / 3.0;
+
+    visit_gltf_nodes(gltf_doc, matrix, &mut |node, world| {
+        let Some(light) = node.light() else {
+            return;
+        };
+
+        let [r, g, b] = light.color();
+        let color = Rgba::rgb(r, g, b) * (light.intensity() / LUMENS_PER_WATT);
+        // lights point down their -Z
+        let pos = world.transform_point3(Vec3::ZERO);
+        let direction = world.transform_vector3(Vec3::NEG_Z).normalize();
+
+        let (light_type, color) = match light.kind() {
+            Kind::Point => (LightType::Point(pos), color * scale * scale),
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (
+                LightType::Spot {
+                    pos,
+                    direction,
+                    inner_cone: inner_cone_angle,
+                    outer_cone: outer_cone_angle,
+                },
+                color * scale * scale,
+            ),
+            Kind::Directional => (LightType::Directional(direction), color),
+        };
+
+        store.lights.push(Light { light_type, color });
+    });
+}
+
+/// One config per camera in the file, the rest of the settings (resolution height, lens, shutter) come from `base`.
+/// Cameras can not roll, the up vector is always +Y
+pub fn get_gltf_cameras(
+    gltf_doc: &gltf::Document,
+    matrix: Mat4,
+    base: CamConfig,
+) -> Result<Vec<CamConfig>> {
+    let mut cameras = Vec::new();
+    let mut error = None;
+
+    visit_gltf_nodes(gltf_doc, matrix, &mut |node, world| {
+        let Some(camera) = node.camera() else {
+            return;
+        };
+        if error.is_some() {
+            return;
+        }
+
+        let mut config = base;
+        // cameras look down their -Z
+        config.pos = world.transform_point3(Vec3::ZERO);
+        config.lookat = config.pos + world.transform_vector3(Vec3::NEG_Z).normalize();
+
+        match camera.projection() {
+            gltf::camera::Projection::Perspective(perspective) => {
+                config.projection = Projection::Perspective;
+                config.fov = perspective.yfov();
+                if let Some(aspect_ratio) = perspective.aspect_ratio() {
+                    config.w = (config.h as f32 * aspect_ratio).round() as u32;
+                }
+            }
+            gltf::camera::Projection::Orthographic(orthographic) => {
+                let (xmag, ymag) = (orthographic.xmag(), orthographic.ymag());
+                // also catches NaN
+                if !(xmag > 0.0 && ymag > 0.0) {
+                    error = Some(GltfError::InvalidOrthographic {
+                        camera: camera
+                            .name()
+                            .map_or_else(|| format!("#{}", camera.index()), str::to_string),
+                        xmag,
+                        ymag,
+                    });
+                    return;
+                }
+
+                // xmag and ymag are half of the size of the view
+                let view_width = 2.0 * world.transform_vector3(Vec3::X * xmag).length();
+                config.projection = Projection::Orthographic { view_width };
+                config.w = ((config.h as f32 * xmag / ymag).round() as u32).max(1);
+            }
+        }
+
+        cameras.push(config);
+    });
+
+    if let Some(error) = error {
+        return Err(error.into());
+    }
+    Ok(cameras)
+}
+
 pub fn add_skybox(store: &mut Scene) -> Result<()> {
-    let mut front = MeshGeometry::default();
-    let mut back = MeshGeometry::default();
-    let mut right = MeshGeometry::default();
-    let mut left = MeshGeometry::default();
-    let mut top = MeshGeometry::default();
-    let mut bottom = MeshGeometry::default();
-
-    let mut front_material = Material::CUBEMAP_MATERIAL;
-    front_material.emissive = Texture::Image(1);
-    let mut back_material = Material::CUBEMAP_MATERIAL;
-    back_material.emissive = Texture::Image(2);
-    let mut right_material = Material::CUBEMAP_MATERIAL;
-    right_material.emissive = Texture::Image(3);
-    let mut left_material = Material::CUBEMAP_MATERIAL;
-    left_material.emissive = Texture::Image(4);
-    let mut top_material = Material::CUBEMAP_MATERIAL;
-    top_material.emissive = Texture::Image(5);
-    let mut bottom_material = Material::CUBEMAP_MATERIAL;
-    bottom_material.emissive = Texture::Image(6);
-
-    let transform = Mat4::from_scale_rotation_translation(
-        Vec3::splat(10000.0),
-        Default::default(),
-        Default::default(),
-    );
+    // in the order of the faces of a cube map
+    let faces = ["right", "left", "top", "bottom", "front", "back"];
+    let paths = faces.map(|face| format!("assets/textures/skybox/{face}.jpg"));
+    let id = store
+        .textures
+        .add_cube_map(&paths, TextureOptions::DEFAULT)
+        .context("Error loading the skybox")?;
 
-    let bottom_left_front = transform * Vec4::new(-0.5, -0.5, 0.5, 1.0);
-    let top_left_front = transform * Vec4::new(-0.5, 0.5, 0.5, 1.0);
-    let top_right_front = transform * Vec4::new(0.5, 0.5, 0.5, 1.0);
-    let bottom_right_front = transform * Vec4::new(0.5, -0.5, 0.5, 1.0);
-    let bottom_left_back = transform * Vec4::new(-0.5, -0.5, -0.5, 1.0);
-    let top_left_back = transform * Vec4::new(-0.5, 0.5, -0.5, 1.0);
-    let top_right_back = transform * Vec4::new(0.5, 0.5, -0.5, 1.0);
-    let bottom_right_back = transform * Vec4::new(0.5, -0.5, -0.5, 1.0);
-
-    front.indices.push((0, 1, 2));
-    front.indices.push((0, 2, 3));
-    back.indices.push((0, 1, 2));
-    back.indices.push((0, 2, 3));
-    right.indices.push((0, 1, 2));
-    right.indices.push((0, 2, 3));
-    left.indices.push((0, 1, 2));
-    left.indices.push((0, 2, 3));
-    top.indices.push((0, 1, 2));
-    top.indices.push((0, 2, 3));
-    bottom.indices.push((0, 1, 2));
-    bottom.indices.push((0, 2, 3));
-
-    front.verts.push((
-        bottom_left_front.x,
-        bottom_left_front.y,
-        bottom_left_front.z,
-    ));
-    front.verts.push((
-        bottom_right_front.x,
-        bottom_right_front.y,
-        bottom_right_front.z,
-    ));
-    front
-        .verts
-        .push((top_right_front.x, top_right_front.y, top_right_front.z));
-    front
-        .verts
-        .push((top_left_front.x, top_left_front.y, top_left_front.z));
-    front.tex_coords.push(Vec2::new(0.0, 0.0));
-    front.tex_coords.push(Vec2::new(1.0, 0.0));
-    front.tex_coords.push(Vec2::new(1.0, 1.0));
-    front.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    back.verts.push((
-        bottom_right_back.x,
-        bottom_right_back.y,
-        bottom_right_back.z,
-    ));
-    back.verts
-        .push((bottom_left_back.x, bottom_left_back.y, bottom_left_back.z));
-    back.verts
-        .push((top_left_back.x, top_left_back.y, top_left_back.z));
-    back.verts
-        .push((top_right_back.x, top_right_back.y, top_right_back.z));
-    back.tex_coords.push(Vec2::new(0.0, 0.0));
-    back.tex_coords.push(Vec2::new(1.0, 0.0));
-    back.tex_coords.push(Vec2::new(1.0, 1.0));
-    back.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    right.verts.push((
-        bottom_right_front.x,
-        bottom_right_front.y,
-        bottom_right_front.z,
-    ));
-    right.verts.push((
-        bottom_right_back.x,
-        bottom_right_back.y,
-        bottom_right_back.z,
-    ));
-    right
-        .verts
-        .push((top_right_back.x, top_right_back.y, top_right_back.z));
-    right
-        .verts
-        .push((top_right_front.x, top_right_front.y, top_right_front.z));
-    right.tex_coords.push(Vec2::new(0.0, 0.0));
-    right.tex_coords.push(Vec2::new(1.0, 0.0));
-    right.tex_coords.push(Vec2::new(1.0, 1.0));
-    right.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    left.verts
-        .push((bottom_left_back.x, bottom_left_back.y, bottom_left_back.z));
-    left.verts.push((
-        bottom_left_front.x,
-        bottom_left_front.y,
-        bottom_left_front.z,
-    ));
-    left.verts
-        .push((top_left_front.x, top_left_front.y, top_left_front.z));
-    left.verts
-        .push((top_left_back.x, top_left_back.y, top_left_back.z));
-    left.tex_coords.push(Vec2::new(0.0, 0.0));
-    left.tex_coords.push(Vec2::new(1.0, 0.0));
-    left.tex_coords.push(Vec2::new(1.0, 1.0));
-    left.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    top.verts
-        .push((top_left_front.x, top_left_front.y, top_left_front.z));
-    top.verts
-        .push((top_right_front.x, top_right_front.y, top_right_front.z));
-    top.verts
-        .push((top_right_back.x, top_right_back.y, top_right_back.z));
-    top.verts
-        .push((top_left_back.x, top_left_back.y, top_left_back.z));
-    top.tex_coords.push(Vec2::new(0.0, 0.0));
-    top.tex_coords.push(Vec2::new(1.0, 0.0));
-    top.tex_coords.push(Vec2::new(1.0, 1.0));
-    top.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    bottom
-        .verts
-        .push((bottom_left_back.x, bottom_left_back.y, bottom_left_back.z));
-    bottom.verts.push((
-        bottom_right_back.x,
-        bottom_right_back.y,
-        bottom_right_back.z,
-    ));
-    bottom.verts.push((
-        bottom_right_front.x,
-        bottom_right_front.y,
-        bottom_right_front.z,
-    ));
-    bottom.verts.push((
-        bottom_left_front.x,
-        bottom_left_front.y,
-        bottom_left_front.z,
-    ));
-    bottom.tex_coords.push(Vec2::new(0.0, 0.0));
-    bottom.tex_coords.push(Vec2::new(1.0, 0.0));
-    bottom.tex_coords.push(Vec2::new(1.0, 1.0));
-    bottom.tex_coords.push(Vec2::new(0.0, 1.0));
-
-    store.add_geometry(Geometry::with_material(front_material, Mesh(front)));
-    store.add_geometry(Geometry::with_material(back_material, Mesh(back)));
-    store.add_geometry(Geometry::with_material(right_material, Mesh(right)));
-    store.add_geometry(Geometry::with_material(left_material, Mesh(left)));
-    store.add_geometry(Geometry::with_material(top_material, Mesh(top)));
-    store.add_geometry(Geometry::with_material(bottom_material, Mesh(bottom)));
+    store.background = Some(Background::CubeMap { id, intensity: 1.0 });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(xmag: f32, ymag: f32) -> gltf::Document {
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [{{ "camera": 0, "translation": [0, 0, 5] }}],
+                "cameras": [{{
+                    "name": "top",
+                    "type": "orthographic",
+                    "orthographic": {{ "xmag": {xmag}, "ymag": {ymag}, "znear": 0.1, "zfar": 100 }}
+                }}]
+            }}"#
+        );
+        gltf::Gltf::from_slice(json.as_bytes()).unwrap().document
+    }
+
+    #[test]
+    fn orthographic_cameras() {
+        let base = CamConfig::BALANCED;
+        let cameras = get_gltf_cameras(&document(2.0, 1.0), Mat4::IDENTITY, base).unwrap();
+        assert_eq!(cameras.len(), 1);
+        assert_eq!(cameras[0].w, base.h * 2);
+        assert!(matches!(
+            cameras[0].projection,
+            Projection::Orthographic { view_width } if (view_width - 4.0).abs() < 1e-5
+        ));
+
+        for (xmag, ymag) in [(0.0, 1.0), (1.0, 0.0), (-1.0, 1.0)] {
+            let error = get_gltf_cameras(&document(xmag, ymag), Mat4::IDENTITY, base).unwrap_err();
+            assert!(error.to_string().contains("camera top"), "{error}");
+        }
+    }
+
+    #[test]
+    fn gltf_materials() {
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "extensionsUsed": ["KHR_texture_transform"],
+            "images": [{ "uri": "texture.png" }],
+            "samplers": [{ "wrapS": 33071, "wrapT": 33648 }],
+            "textures": [{ "source": 0, "sampler": 0 }, { "source": 0 }],
+            "materials": [
+                { "alphaMode": "MASK", "alphaCutoff": 0.25, "pbrMetallicRoughness": { "baseColorTexture": {
+                    "index": 0,
+                    "extensions": { "KHR_texture_transform": { "offset": [0.5, 0], "scale": [2, 2] } }
+                } } },
+                { "alphaMode": "BLEND", "pbrMetallicRoughness": { "baseColorTexture": { "index": 1 } } },
+                { }
+            ]
+        }"#;
+        let doc = gltf::Gltf::from_slice(json.as_bytes()).unwrap().document;
+        // 2x1, 16 bit gray
+        let images = [gltf::image::Data {
+            pixels: [0u16, 65535].iter().flat_map(|x| x.to_le_bytes()).collect(),
+            format: gltf::image::Format::R16,
+            width: 2,
+            height: 1,
+        }];
+
+        let mut store = Scene::new();
+        let mut textures = FxHashMap::default();
+        let mut material = |index: usize| {
+            let file_material = doc.materials().nth(index).unwrap();
+            let base = Material::WHITE_MATERIAL;
+            gltf_material(&mut store, &file_material, &images, &mut textures, &base).unwrap()
+        };
+        let materials = [material(0), material(1), material(2)];
+        let alpha_modes = materials.each_ref().map(|material| material.alpha_mode);
+        assert_eq!(
+            alpha_modes,
+            [AlphaMode::Mask(0.25), AlphaMode::Blend, AlphaMode::Opaque]
+        );
+        let [
+            Texture::Image(clamped),
+            Texture::Image(repeated),
+            Texture::Solid(_),
+        ] = materials.map(|material| material.texture)
+        else {
+            panic!("only the first two materials have a texture");
+        };
+
+        let clamped = store.textures.get(clamped);
+        assert_eq!(clamped.sampler.wrap_u, WrapMode::ClampToEdge);
+        assert_eq!(clamped.sampler.wrap_v, WrapMode::MirroredRepeat);
+        assert_eq!(clamped.sampler.transform.offset, Vec2::new(0.5, 0.0));
+        assert_eq!(clamped.sampler.transform.scale, Vec2::splat(2.0));
+
+        let repeated = store.textures.get(repeated);
+        assert_eq!(repeated.sampler, Sampler::REPEAT);
+        // the same pixels under both
+        assert!(std::sync::Arc::ptr_eq(&clamped.image, &repeated.image));
+        let right = repeated.sample(Vec2::new(0.75, 0.5), None, TextureFilter::Bilinear);
+        assert_eq!(right.to_array(), [1.0; 4]);
+    }
+}
@@ -0,0 +1,172 @@
+use super::{
+    AlphaMode, GeomInfo, Geometry, ImageTexture, MeshGeometry, Texture, TextureFilter,
+    TextureRegistry,
+};
+use anyhow::{Result, bail};
+
+/// What the ray tracer needs to know to skip the transparent parts of a surface while intersecting,
+/// see `AlphaMode`. Made by the scene for every geometry that is not opaque
+pub struct AlphaMask {
+    mode: AlphaMode,
+    alpha: Alpha,
+    // only the uvs and the indices, the vertices are in the ray tracer
+    uvs: MeshGeometry,
+}
+
+enum Alpha {
+    Constant(f32),
+    Image(ImageTexture),
+}
+
+impl AlphaMask {
+    /// None for opaque materials, an error for shapes other than meshes and instances
+    /// and for procedural textures, evaluating them for every candidate hit would be too slow
+    pub fn new(geometry: &Geometry, textures: &TextureRegistry) -> Result<Option<Self>> {
+        let material = &geometry.material;
+        if material.alpha_mode == AlphaMode::Opaque {
+            return Ok(None);
+        }
+
+        let mesh = match geometry.info {
+            GeomInfo::Mesh(ref mesh) => mesh,
+            GeomInfo::Instance(ref instance) => &*instance.mesh,
+            GeomInfo::Sphere(_)
+            | GeomInfo::Quadric(_)
+            | GeomInfo::Curves(_)
+            | GeomInfo::Points(_) => {
+                bail!("Only meshes and instances can have holes, the alpha mode has to be opaque")
+            }
+        };
+
+        let alpha = match material.texture {
+            Texture::Solid(color) => Alpha::Constant(color.to_array()[3]),
+            Texture::Image(id) => Alpha::Image(textures.get(id).clone()),
+            Texture::Procedural(_) => {
+                bail!("Procedural textures can not have holes, the alpha mode has to be opaque")
+            }
+        };
+
+        Ok(Some(Self {
+            mode: material.alpha_mode,
+            alpha,
+            uvs: MeshGeometry {
+                verts: Vec::new(),
+                indices: mesh.indices.clone(),
+                tex_coords: mesh.active_tex_coords().to_vec(),
+                extra_tex_coords: Vec::new(),
+                tex_coord_set: 0,
+            },
+        }))
+    }
+
+    /// if the hit counts. u and v are the barycentrics given by the ray tracer
+    pub fn passes(&self, prim_id: u32, u: f32, v: f32) -> bool {
+        let alpha = match &self.alpha {
+            Alpha::Constant(alpha) => *alpha,
+            Alpha::Image(texture) => {
+                let uv = self.uvs.compute_uv(u, v, prim_id);
+                // no footprint here, the full resolution keeps the edges of the cutout sharp
+                texture.sample(uv, None, TextureFilter::Bilinear).to_array()[3]
+            }
+        };
+
+        match self.mode {
+            AlphaMode::Opaque => true,
+            AlphaMode::Mask(cutoff) => alpha >= cutoff,
+            AlphaMode::Blend => fastrand::f32() < alpha,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Rgba;
+    use crate::geometry::{Material, Sampler, SphereGeometry};
+    use glam::{Vec2, Vec3};
+    use image::Rgba32FImage;
+
+    fn triangle(material: Material) -> Geometry {
+        let mesh = MeshGeometry {
+            verts: vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+            indices: vec![(0, 1, 2)],
+            tex_coords: vec![
+                Vec2::new(0.0, 0.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(0.0, 1.0),
+            ],
+            extra_tex_coords: Vec::new(),
+            tex_coord_set: 0,
+        };
+        Geometry::with_material(material, GeomInfo::Mesh(mesh))
+    }
+
+    fn material(alpha_mode: AlphaMode, texture: Texture) -> Material {
+        Material {
+            alpha_mode,
+            texture,
+            ..Material::WHITE_MATERIAL
+        }
+    }
+
+    #[test]
+    fn opaque_has_no_mask() {
+        let textures = TextureRegistry::new();
+        let geometry = triangle(Material::WHITE_MATERIAL);
+        assert!(AlphaMask::new(&geometry, &textures).unwrap().is_none());
+    }
+
+    #[test]
+    fn cutoff_of_the_texture_alpha() {
+        let mut textures = TextureRegistry::new();
+        // opaque on the left half, clear on the right
+        let image = Rgba32FImage::from_fn(8, 1, |x, _| {
+            image::Rgba([1.0, 1.0, 1.0, if x < 4 { 1.0 } else { 0.0 }])
+        });
+        let id = textures.add_image(image, Sampler::REPEAT);
+        let geometry = triangle(material(AlphaMode::Mask(0.5), Texture::Image(id)));
+
+        let mask = AlphaMask::new(&geometry, &textures).unwrap().unwrap();
+        assert!(mask.passes(0, 0.1, 0.0));
+        assert!(!mask.passes(0, 0.9, 0.0));
+    }
+
+    #[test]
+    fn blend_of_a_constant_alpha() {
+        let textures = TextureRegistry::new();
+        let clear = Texture::Solid(Rgba::new(1.0, 1.0, 1.0, 0.0));
+        let solid = Texture::Solid(Rgba::WHITE);
+
+        let clear = AlphaMask::new(&triangle(material(AlphaMode::Blend, clear)), &textures);
+        let clear = clear.unwrap().unwrap();
+        assert!((0..100).all(|_| !clear.passes(0, 0.3, 0.3)));
+        let solid = AlphaMask::new(&triangle(material(AlphaMode::Blend, solid)), &textures);
+        let solid = solid.unwrap().unwrap();
+        assert!((0..100).all(|_| solid.passes(0, 0.3, 0.3)));
+    }
+
+    #[test]
+    fn only_meshes_can_be_masked() {
+        let textures = TextureRegistry::new();
+        let sphere = GeomInfo::Sphere(SphereGeometry {
+            radius: 1.0,
+            center: Vec3::ZERO,
+        });
+        let geometry = Geometry::with_material(
+            material(AlphaMode::Mask(0.5), Texture::Solid(Rgba::WHITE)),
+            sphere,
+        );
+        assert!(AlphaMask::new(&geometry, &textures).is_err());
+    }
+
+    #[test]
+    fn procedural_textures_can_not_be_masked() {
+        let textures = TextureRegistry::new();
+        let noise = Texture::procedural(
+            crate::geometry::TextureNode::Noise { octaves: 1 },
+            crate::geometry::TextureSpace::Uv,
+        );
+        let geometry = triangle(material(AlphaMode::Mask(0.5), noise));
+        assert!(AlphaMask::new(&geometry, &textures).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use super::{Sky, TextureFilter, TextureRegistry};
+use crate::color::Rgba;
+use glam::Vec3;
+
+/// What rays that hit nothing see. It lights the scene too, through the bounces that end up in it
+#[derive(Debug, Clone)]
+pub enum Background {
+    /// a cube map of the scene, see `TextureRegistry::add_cube_map`
+    CubeMap { id: u32, intensity: f32 },
+    /// its sun is a light of its own, see `Scene::set_sky`
+    Sky(Sky),
+}
+
+impl Background {
+    /// what a ray going in direction `dir` sees. `spread` is the angle the ray covers
+    pub fn radiance(
+        &self,
+        dir: Vec3,
+        spread: f32,
+        textures: &TextureRegistry,
+        filter: TextureFilter,
+    ) -> Rgba {
+        match self {
+            Background::CubeMap { id, intensity } => {
+                textures.cube_map(*id).sample(dir, spread, filter) * *intensity
+            }
+            // smooth enough that the footprint does not matter
+            Background::Sky(sky) => sky.radiance(dir),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use super::{MipMap, TextureFilter, TextureFormat, TextureImage, WrapMode};
+use crate::color::Rgba;
+use anyhow::{Result, bail};
+use glam::{Vec2, Vec3};
+use image::{GenericImageView, Rgba32FImage};
+use std::sync::Arc;
+
+// column and row of each face in `CubeMap::from_cross`, in the order of the faces
+const CROSS_CELLS: [(u32, u32); 6] = [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)];
+
+/// Six square images around the origin, sampled by direction. For backgrounds and reflection probes.
+/// Faces are in the order +x, -x, +y, -y, +z, -z and oriented like OpenGL's cube maps
+pub struct CubeMap {
+    faces: [Arc<TextureImage>; 6],
+}
+
+impl CubeMap {
+    pub fn new(faces: [Arc<TextureImage>; 6]) -> Self {
+        Self { faces }
+    }
+
+    /// a single image with the faces laid out as a horizontal cross, 4 faces wide and 3 high:
+    ///       +y
+    ///   -x  +z  +x  -z
+    ///       -y
+    pub fn from_cross(image: &Rgba32FImage, format: TextureFormat) -> Result<Self> {
+        let (w, h) = image.dimensions();
+        let size = w / 4;
+        if size == 0 || w != size * 4 || h != size * 3 {
+            bail!("A cube map cross must be 4 faces wide and 3 high, got {w}x{h}");
+        }
+
+        let faces = CROSS_CELLS.map(|(x, y)| {
+            let face = image.view(x * size, y * size, size, size).to_image();
+            Arc::new(TextureImage::new(MipMap::with_format(face, format)))
+        });
+
+        Ok(Self::new(faces))
+    }
+
+    /// `spread` is the angle the ray covers, in radians. It picks the mip level
+    pub fn sample(&self, dir: Vec3, spread: f32, filter: TextureFilter) -> Rgba {
+        let (face, uv) = face_uv(dir);
+        // at the center of a face one radian is half of the face
+        let width = spread * 0.5;
+        let footprint = (filter != TextureFilter::Bilinear && width > 0.0)
+            .then(|| (Vec2::new(width, 0.0), Vec2::new(0.0, width)));
+
+        // repeating would blend in the opposite edge of the face along the seams
+        let wrap = (WrapMode::ClampToEdge, WrapMode::ClampToEdge);
+        self.faces[face].get().sample(uv, footprint, filter, wrap)
+    }
+}
+
+// face of the largest axis, then the other two divided by it.
+// the sign flips are what OpenGL does so that the faces line up at the edges
+fn face_uv(dir: Vec3) -> (usize, Vec2) {
+    let abs = dir.abs();
+    let (face, major, sc, tc) = if abs.x >= abs.y && abs.x >= abs.z {
+        if dir.x > 0.0 {
+            (0, abs.x, -dir.z, -dir.y)
+        } else {
+            (1, abs.x, dir.z, -dir.y)
+        }
+    } else if abs.y >= abs.z {
+        if dir.y > 0.0 {
+            (2, abs.y, dir.x, dir.z)
+        } else {
+            (3, abs.y, dir.x, -dir.z)
+        }
+    } else if dir.z > 0.0 {
+        (4, abs.z, dir.x, -dir.y)
+    } else {
+        (5, abs.z, -dir.x, -dir.y)
+    };
+
+    let uv = (Vec2::new(sc, tc) / major.max(f32::MIN_POSITIVE) + 1.0) * 0.5;
+    (face, uv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AXES: [Vec3; 6] = [
+        Vec3::X,
+        Vec3::NEG_X,
+        Vec3::Y,
+        Vec3::NEG_Y,
+        Vec3::Z,
+        Vec3::NEG_Z,
+    ];
+
+    // where a direction lands in a cross, in faces
+    fn cross_position(dir: Vec3) -> Vec2 {
+        let (face, uv) = face_uv(dir);
+        let (x, y) = CROSS_CELLS[face];
+        Vec2::new(x as f32, y as f32) + uv
+    }
+
+    #[test]
+    fn axes_hit_the_face_centers() {
+        for (i, axis) in AXES.into_iter().enumerate() {
+            assert_eq!(face_uv(axis), (i, Vec2::splat(0.5)), "{axis}");
+            assert_eq!(face_uv(axis * 3.0), (i, Vec2::splat(0.5)), "{axis}");
+        }
+    }
+
+    #[test]
+    fn faces_agree_across_edges() {
+        // pairs of directions just either side of an edge that the cross keeps together
+        let e = 1e-4;
+        let edges = [
+            (Vec3::new(1.0, 0.3, 1.0 - e), Vec3::new(1.0 - e, 0.3, 1.0)),
+            (
+                Vec3::new(-1.0, -0.6, 1.0 - e),
+                Vec3::new(-1.0 + e, -0.6, 1.0),
+            ),
+            (Vec3::new(1.0, 0.2, -1.0 + e), Vec3::new(1.0 - e, 0.2, -1.0)),
+            (Vec3::new(0.2, 1.0, 1.0 - e), Vec3::new(0.2, 1.0 - e, 1.0)),
+            (
+                Vec3::new(-0.7, -1.0, 1.0 - e),
+                Vec3::new(-0.7, -1.0 + e, 1.0),
+            ),
+        ];
+        for (a, b) in edges {
+            assert_ne!(face_uv(a).0, face_uv(b).0);
+            let (a, b) = (cross_position(a), cross_position(b));
+            assert!(a.distance(b) < 1e-3, "{a} {b}");
+        }
+    }
+
+    #[test]
+    fn cross_faces_are_cut_in_order() {
+        // every cell of the cross has the value of the face it belongs to, empty cells are -1
+        let size = 4;
+        let cross = Rgba32FImage::from_fn(size * 4, size * 3, |x, y| {
+            let cell = (x / size, y / size);
+            let face = CROSS_CELLS.iter().position(|c| *c == cell);
+            let value = face.map_or(-1.0, |face| face as f32);
+            image::Rgba([value, value, value, 1.0])
+        });
+        let cube = CubeMap::from_cross(&cross, TextureFormat::F32).unwrap();
+
+        for (i, axis) in AXES.into_iter().enumerate() {
+            let color: image::Rgba<f32> = cube.sample(axis, 0.0, TextureFilter::Bilinear).into();
+            assert_eq!(color.0[0], i as f32, "{axis}");
+        }
+    }
+
+    #[test]
+    fn cross_must_be_four_by_three_faces() {
+        for (w, h) in [(0, 0), (3, 3), (5, 3), (4, 4), (8, 3), (12, 12)] {
+            let image = Rgba32FImage::new(w, h);
+            let Err(err) = CubeMap::from_cross(&image, TextureFormat::F32) else {
+                panic!("{w}x{h} was accepted");
+            };
+            assert!(err.to_string().contains(&format!("{w}x{h}")), "{err}");
+        }
+        assert!(CubeMap::from_cross(&Rgba32FImage::new(8, 6), TextureFormat::F32).is_ok());
+    }
+}
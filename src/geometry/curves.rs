@@ -0,0 +1,152 @@
+use anyhow::{Result, bail};
+use glam::{Mat4, Vec3, Vec4, Vec4Swizzles};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveBasis {
+    /// straight segments between the points
+    Linear,
+    /// smooth, does not go through the control points (except at ends where they are repeated)
+    BSpline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveShape {
+    /// ribbon that always faces the ray, cheap and good for thin fibers far away
+    Flat,
+    /// actual tube
+    Round,
+}
+
+/// Strands of hair, fur, grass or wire. Intersected by embree's curve geometry
+#[derive(Debug, Clone)]
+pub struct CurveGeometry {
+    /// xyz and radius of every control point
+    pub points: Vec<Vec4>,
+    /// first control point of every segment, linear segments use 2 points and b-spline ones 4
+    pub segments: Vec<u32>,
+    pub basis: CurveBasis,
+    pub shape: CurveShape,
+}
+
+impl CurveGeometry {
+    /// one strand per list of control points, all of them joined into the same geometry
+    pub fn from_strands(
+        strands: &[Vec<Vec4>],
+        basis: CurveBasis,
+        shape: CurveShape,
+    ) -> Result<Self> {
+        let points_per_segment = match basis {
+            CurveBasis::Linear => 2,
+            CurveBasis::BSpline => 4,
+        };
+
+        let mut points = Vec::new();
+        let mut segments = Vec::new();
+        for (i, strand) in strands.iter().enumerate() {
+            if strand.len() < points_per_segment {
+                bail!(
+                    "Strand {i} has {} control points, needs at least {points_per_segment}",
+                    strand.len()
+                );
+            }
+
+            let first = points.len() as u32;
+            let count = (strand.len() - points_per_segment + 1) as u32;
+            segments.extend(first..first + count);
+            points.extend_from_slice(strand);
+        }
+
+        Ok(Self {
+            points,
+            segments,
+            basis,
+            shape,
+        })
+    }
+
+    pub fn transform(&mut self, matrix: Mat4) {
+        // radii are scaled by the average scale, curves can not be squashed
+        let scale = Vec3::new(
+            matrix.x_axis.truncate().length(),
+            matrix.y_axis.truncate().length(),
+            matrix.z_axis.truncate().length(),
+        )
+        .element_sum()
+            / 3.0;
+
+        for point in &mut self.points {
+            *point = matrix.transform_point3(point.xyz()).extend(point.w * scale);
+        }
+    }
+
+    /// direction of the curve at `u` (0 to 1) along a segment, not normalized
+    pub fn tangent(&self, segment: u32, u: f32) -> Vec3 {
+        let first = self.segments[segment as usize] as usize;
+        let p = |i: usize| self.points[first + i].xyz();
+
+        match self.basis {
+            CurveBasis::Linear => p(1) - p(0),
+            CurveBasis::BSpline => {
+                // derivatives of the uniform cubic b-spline basis
+                let w0 = -(1.0 - u) * (1.0 - u) / 2.0;
+                let w1 = (3.0 * u * u - 4.0 * u) / 2.0;
+                let w2 = (-3.0 * u * u + 2.0 * u + 1.0) / 2.0;
+                let w3 = u * u / 2.0;
+                p(0) * w0 + p(1) * w1 + p(2) * w2 + p(3) * w3
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(points: usize) -> Vec<Vec4> {
+        (0..points)
+            .map(|i| Vec4::new(i as f32, 0.0, 0.0, 0.5))
+            .collect()
+    }
+
+    #[test]
+    fn segments_of_the_strands() {
+        let linear =
+            CurveGeometry::from_strands(&[line(3), line(2)], CurveBasis::Linear, CurveShape::Flat)
+                .unwrap();
+        assert_eq!(linear.points.len(), 5);
+        assert_eq!(linear.segments, [0, 1, 3]);
+
+        let bspline = CurveGeometry::from_strands(
+            &[line(4), line(6)],
+            CurveBasis::BSpline,
+            CurveShape::Round,
+        )
+        .unwrap();
+        assert_eq!(bspline.segments, [0, 4, 5, 6]);
+    }
+
+    #[test]
+    fn strands_need_a_whole_segment() {
+        assert!(
+            CurveGeometry::from_strands(&[line(3)], CurveBasis::BSpline, CurveShape::Round)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tangent_along_a_line() {
+        let curve = CurveGeometry::from_strands(&[line(4)], CurveBasis::BSpline, CurveShape::Round)
+            .unwrap();
+        for u in [0.0, 0.5, 1.0] {
+            assert!((curve.tangent(0, u) - Vec3::X).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn transform_scales_the_radius() {
+        let mut curve =
+            CurveGeometry::from_strands(&[line(2)], CurveBasis::Linear, CurveShape::Flat).unwrap();
+        curve.transform(Mat4::from_scale(Vec3::splat(2.0)));
+        assert_eq!(curve.points[1], Vec4::new(2.0, 0.0, 0.0, 1.0));
+    }
+}
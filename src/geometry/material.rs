@@ -1,5 +1,8 @@
 use crate::color::Rgba;
 use crate::geometry::Texture;
+use crate::hair::Hair;
+use crate::medium::Medium;
+use crate::spectrum::Dispersion;
 
 #[derive(Debug, Clone)]
 pub struct Material {
@@ -9,10 +12,171 @@ pub struct Material {
     pub specular: Rgba,     // reflections
     pub transmission: Rgba, // refractions
     pub refraction: f32,
+    pub dispersion: Dispersion, // wavelength dependent refraction, spectral mode only
+    pub priority: u32, // for overlapping transparent objects, the one with the highest priority is the medium the ray is in
     pub reflectivity: f32,
     pub transparency: f32,
-    pub texture: Texture,  // diffuse
+    pub roughness: f32, // blurs reflections and refractions, 0 is a perfect mirror
+    pub metallic: f32,  // metals reflect with their own color and do not scatter
+    pub medium: Option<Medium>, // what fills the inside of the (closed) geometry
+    pub texture: Texture, // diffuse
     pub emissive: Texture, // emissive
+    pub maps: MaterialMaps, // textures for everything else
+    pub alpha_mode: AlphaMode, // what the alpha of `texture` does
+    pub hair: Option<Hair>, // replaces everything above (except emissive) with the hair model
+}
+
+/// How the alpha of the diffuse texture cuts holes in the surface, same as glTF's alphaMode.
+/// Only meshes (and instances) can have holes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    /// alpha is ignored
+    Opaque,
+    /// the surface is not there where alpha is below the cutoff, for leaves and fences
+    Mask(f32),
+    /// rays go through with a probability of 1 - alpha, so it looks semi transparent on average
+    Blend,
+}
+
+impl AlphaMode {
+    pub fn from_gltf(material: &gltf::Material) -> Self {
+        match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => {
+                AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5))
+            }
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        }
+    }
+}
+
+/// Which part of a texture drives a scalar parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    R,
+    G,
+    B,
+    A,
+    /// average of r, g and b, for greyscale images
+    Grey,
+}
+
+/// A scalar parameter read from a texture, see `MaterialMaps`
+#[derive(Debug, Clone)]
+pub struct ParamMap {
+    pub texture: Texture,
+    pub channel: Channel,
+}
+
+impl Channel {
+    pub fn of(self, color: Rgba) -> f32 {
+        let [r, g, b, a] = color.to_array();
+        match self {
+            Channel::R => r,
+            Channel::G => g,
+            Channel::B => b,
+            Channel::A => a,
+            Channel::Grey => (r + g + b) / 3.0,
+        }
+    }
+}
+
+impl ParamMap {
+    pub fn new(texture: Texture, channel: Channel) -> Self {
+        Self { texture, channel }
+    }
+}
+
+/// Textures for the parameters of the material, they multiply the constant of the material like in glTF.
+/// None keeps the constant
+#[derive(Debug, Clone)]
+pub struct MaterialMaps {
+    pub specular: Option<Texture>,
+    pub transmission: Option<Texture>,
+    pub roughness: Option<ParamMap>,
+    pub metallic: Option<ParamMap>,
+    pub reflectivity: Option<ParamMap>,
+    /// multiplies the opacity (1 - transparency), black is fully transparent
+    pub opacity: Option<ParamMap>,
+    /// scales how far the ior is from 1, so that an image can hold it
+    pub ior: Option<ParamMap>,
+    /// a cube map of the scene, reflections read it instead of tracing rays.
+    /// much faster, but only right for objects close to where it was captured
+    pub reflection_probe: Option<u32>,
+}
+
+impl MaterialMaps {
+    pub const NONE: Self = Self {
+        specular: None,
+        transmission: None,
+        roughness: None,
+        metallic: None,
+        reflectivity: None,
+        opacity: None,
+        ior: None,
+        reflection_probe: None,
+    };
+
+    /// no texture that needs the uv, the probe is read by direction
+    pub fn is_empty(&self) -> bool {
+        self.specular.is_none()
+            && self.transmission.is_none()
+            && self.roughness.is_none()
+            && self.metallic.is_none()
+            && self.reflectivity.is_none()
+            && self.opacity.is_none()
+            && self.ior.is_none()
+    }
+}
+
+/// Every parameter of the material at a hit, with the textures already applied, see `BuiltScene::sample_surface`
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceParams {
+    /// metals have none
+    pub diffuse: Rgba,
+    pub emissive: Rgba,
+    /// only lit by ambient lights
+    pub ambient: Rgba,
+    /// the diffuse color for metals
+    pub specular: Rgba,
+    pub transmission: Rgba,
+    pub roughness: f32,
+    pub reflectivity: f32,
+    pub transparency: f32,
+    /// see `MaterialMaps::ior`, 1.0 is the ior of the material
+    pub ior_scale: f32,
+    pub dispersive: bool,
+    /// see `MaterialMaps::reflection_probe`
+    pub reflection_probe: Option<u32>,
+}
+
+impl SurfaceParams {
+    /// the constants of the material, without any texture
+    pub fn constant(material: &Material, diffuse: Rgba, emissive: Rgba) -> Self {
+        Self {
+            diffuse,
+            emissive,
+            ambient: material.color,
+            specular: material.specular,
+            transmission: material.transmission,
+            roughness: material.roughness,
+            reflectivity: material.reflectivity,
+            transparency: material.transparency,
+            ior_scale: 1.0,
+            dispersive: material.dispersion.is_dispersive(),
+            reflection_probe: material.maps.reflection_probe,
+        }
+    }
+
+    /// metals reflect with the diffuse color instead of scattering it
+    pub fn with_metallic(mut self, metallic: f32) -> Self {
+        if metallic > 0.0 {
+            self.specular = self.specular * (1.0 - metallic) + self.diffuse * metallic;
+            self.reflectivity += (1.0 - self.reflectivity) * metallic;
+            self.diffuse *= 1.0 - metallic;
+        }
+        self
+    }
 }
 
 impl Default for Material {
@@ -23,24 +187,47 @@ impl Default for Material {
             specular: Rgba::RED,
             transmission: Rgba::RED,
             refraction: 1.0,
+            dispersion: Dispersion::None,
+            priority: 0,
             reflectivity: 0.0,
             transparency: 0.0,
+            roughness: 0.0,
+            metallic: 0.0,
+            medium: None,
             texture: Texture::Solid(Rgba::RED),
             emissive: Texture::Solid(Rgba::NONE),
+            maps: MaterialMaps::NONE,
+            alpha_mode: AlphaMode::Opaque,
+            hair: None,
         }
     }
 }
 
 impl Material {
+    /// index of refraction at the given wavelength, or the rgb one if not rendering spectrally
+    pub fn ior(&self, lambda: Option<f32>) -> f32 {
+        lambda
+            .and_then(|l| self.dispersion.ior(l))
+            .unwrap_or(self.refraction)
+    }
+
     pub const WHITE_MATERIAL: Self = Self {
         color: Rgba::rgb(0.9, 0.9, 0.9),
         texture: Texture::Solid(Rgba::rgb(0.4, 0.4, 0.4)),
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
 
     pub const RED_MATERIAL: Self = Self {
@@ -49,9 +236,17 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const GREEN_MATERIAL: Self = Self {
         color: Rgba::rgb(0.0, 0.9, 0.0),
@@ -59,9 +254,17 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const BLUE_MATERIAL: Self = Self {
         color: Rgba::rgb(0.0, 0.0, 0.9),
@@ -69,9 +272,17 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const ORANGE_MATERIAL: Self = Self {
         color: Rgba::rgb(0.99, 0.65, 0.0),
@@ -79,9 +290,17 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const MIRROR_MATERIAL: Self = Self {
         color: Rgba::BLACK,
@@ -89,9 +308,17 @@ impl Material {
         specular: Rgba::rgb(0.9, 0.9, 0.9),
         transmission: Rgba::BLACK,
         refraction: 1.5,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 1.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const GLASS_MATERIAL: Self = Self {
         color: Rgba::WHITE,
@@ -99,9 +326,18 @@ impl Material {
         specular: Rgba::rgb(1.0, 1.0, 1.0),
         transmission: Rgba::rgb(0.9, 0.9, 0.9),
         refraction: 1.125,
+        // exaggerated compared to real glass so the rainbows are visible, n(589nm) ~= refraction
+        dispersion: Dispersion::Cauchy { a: 1.1, b: 0.0087 },
+        priority: 0,
         reflectivity: 0.1, // try 0.01
         transparency: 1.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
     pub const UV_MATERIAL: Self = Self {
         color: Rgba::rgb(1.0, 1.0, 1.0),
@@ -109,9 +345,17 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
 
     pub const EMISSIVE_MATERIAL: Self = Self {
@@ -120,19 +364,67 @@ impl Material {
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
         refraction: 1.0,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
         emissive: Texture::Solid(Rgba::RED),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        hair: None,
     };
 
-    pub const CUBEMAP_MATERIAL: Self = Self {
-        color: Rgba::BLACK,
-        texture: Texture::Solid(Rgba::BLACK),
+    /// brown hair, for curves
+    pub const HAIR_MATERIAL: Self = Self {
+        color: Rgba::rgb(0.3, 0.2, 0.1),
+        texture: Texture::Solid(Rgba::rgb(0.3, 0.2, 0.1)),
         specular: Rgba::BLACK,
         transmission: Rgba::BLACK,
-        refraction: 1.0,
+        refraction: 1.55,
+        dispersion: Dispersion::None,
+        priority: 0,
         reflectivity: 0.0,
         transparency: 0.0,
-        emissive: Texture::Image(1),
+        roughness: 0.0,
+        metallic: 0.0,
+        medium: None,
+        emissive: Texture::Solid(Rgba::NONE),
+        maps: MaterialMaps::NONE,
+        alpha_mode: AlphaMode::Opaque,
+        // eumelanin 1.3, see Hair::from_melanin
+        hair: Some(Hair::new(Rgba::rgb(0.545, 0.906, 1.781))),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_of_a_color() {
+        let color = Rgba::new(0.1, 0.2, 0.6, 0.5);
+        assert_eq!(Channel::R.of(color), 0.1);
+        assert_eq!(Channel::G.of(color), 0.2);
+        assert_eq!(Channel::B.of(color), 0.6);
+        assert_eq!(Channel::A.of(color), 0.5);
+        assert!((Channel::Grey.of(color) - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn metals_reflect_their_color() {
+        let material = Material::WHITE_MATERIAL;
+        let red = Rgba::rgb(1.0, 0.0, 0.0);
+
+        let metal = SurfaceParams::constant(&material, red, Rgba::NONE).with_metallic(1.0);
+        assert_eq!(metal.specular.to_array()[..3], [1.0, 0.0, 0.0]);
+        assert_eq!(metal.reflectivity, 1.0);
+        assert_eq!(metal.diffuse.to_array()[..3], [0.0; 3]);
+
+        let dielectric = SurfaceParams::constant(&material, red, Rgba::NONE).with_metallic(0.0);
+        assert_eq!(dielectric.diffuse.to_array(), red.to_array());
+        assert_eq!(dielectric.reflectivity, material.reflectivity);
+    }
+}
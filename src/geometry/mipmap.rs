@@ -0,0 +1,406 @@
+use super::{Sampler, TextureImage, WrapMode};
+use crate::color::Rgba;
+use glam::Vec2;
+use half::f16;
+use image::{ColorType, DynamicImage, Rgba32FImage};
+use std::sync::Arc;
+
+/// How image textures are filtered, see `MipMap::sample`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureFilter {
+    /// full resolution only, aliases on far away and grazing surfaces
+    Bilinear,
+    /// blends the two levels closest to the size of the footprint, blurry at grazing angles
+    Trilinear,
+    /// elliptical weighted average, keeps the detail along the short axis of stretched footprints
+    Ewa,
+}
+
+/// How the texels of a `MipMap` are stored, smaller formats save memory on big scenes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureFormat {
+    /// 4 bytes a texel, enough for 8 bit files
+    U8,
+    /// 8 bytes a texel, for 16 bit and hdr files. about 3 decimal digits, up to 65504
+    F16,
+    /// 16 bytes a texel
+    F32,
+}
+
+impl TextureFormat {
+    /// the smallest format that keeps the precision of the image, 16 bit integers lose a few bits
+    pub fn of(image: &DynamicImage) -> Self {
+        match image.color() {
+            ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => {
+                TextureFormat::U8
+            }
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+                TextureFormat::F16
+            }
+            _ => TextureFormat::F32,
+        }
+    }
+}
+
+// EWA footprints longer than this compared to their width get widened, or the ellipse gets huge
+const MAX_ANISOTROPY: f32 = 8.0;
+
+/// An image in the scene and how it is sampled, several of them can share the same pyramid
+#[derive(Clone)]
+pub struct ImageTexture {
+    pub image: Arc<TextureImage>,
+    pub sampler: Sampler,
+}
+
+impl ImageTexture {
+    pub fn new(image: Arc<TextureImage>, sampler: Sampler) -> Self {
+        Self { image, sampler }
+    }
+
+    /// see `MipMap::sample`, the uvs and the footprint go through the uv transform first
+    pub fn sample(&self, uv: Vec2, footprint: Option<(Vec2, Vec2)>, filter: TextureFilter) -> Rgba {
+        let transform = &self.sampler.transform;
+        let footprint = footprint
+            .map(|(axis0, axis1)| (transform.apply_vector(axis0), transform.apply_vector(axis1)));
+        let wrap = (self.sampler.wrap_u, self.sampler.wrap_v);
+
+        self.image
+            .get()
+            .sample(transform.apply(uv), footprint, filter, wrap)
+    }
+}
+
+/// A texture and its mip pyramid, each level half the size of the previous one down to 1x1
+pub struct MipMap {
+    levels: Vec<Level>,
+}
+
+struct Level {
+    width: u32,
+    height: u32,
+    texels: Texels,
+}
+
+enum Texels {
+    U8(Vec<[u8; 4]>),
+    F16(Vec<[f16; 4]>),
+    F32(Vec<[f32; 4]>),
+}
+
+impl Level {
+    fn new(image: &Rgba32FImage, format: TextureFormat) -> Self {
+        let pixels = image.pixels().map(|px| px.0);
+        let texels = match format {
+            TextureFormat::U8 => Texels::U8(
+                pixels
+                    .map(|px| px.map(|x| (x.clamp(0.0, 1.0) * 255.0).round() as u8))
+                    .collect(),
+            ),
+            TextureFormat::F16 => Texels::F16(pixels.map(|px| px.map(f16::from_f32)).collect()),
+            TextureFormat::F32 => Texels::F32(pixels.collect()),
+        };
+
+        Self {
+            width: image.width(),
+            height: image.height(),
+            texels,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> Rgba {
+        let i = (y * self.width + x) as usize;
+        Rgba::from_array(match &self.texels {
+            Texels::U8(texels) => texels[i].map(|x| x as f32 / 255.0),
+            Texels::F16(texels) => texels[i].map(f16::to_f32),
+            Texels::F32(texels) => texels[i],
+        })
+    }
+}
+
+impl MipMap {
+    pub fn new(image: Rgba32FImage) -> Self {
+        Self::with_format(image, TextureFormat::F32)
+    }
+
+    // the pyramid is built in f32 and every level is converted on its own, so rounding does not add up
+    pub fn with_format(image: Rgba32FImage, format: TextureFormat) -> Self {
+        let mut levels = Vec::new();
+        let mut image = image;
+        loop {
+            levels.push(Level::new(&image, format));
+            if image.width() == 1 && image.height() == 1 {
+                break;
+            }
+            image = downsample(&image);
+        }
+
+        Self { levels }
+    }
+
+    /// `footprint` is the area the ray covers on the texture, as the two axes of an ellipse in uv space.
+    /// Without one the full resolution image is used. `wrap` is for u and v
+    pub fn sample(
+        &self,
+        uv: Vec2,
+        footprint: Option<(Vec2, Vec2)>,
+        filter: TextureFilter,
+        wrap: (WrapMode, WrapMode),
+    ) -> Rgba {
+        match (filter, footprint) {
+            (TextureFilter::Bilinear, _) | (_, None) => self.bilinear(0, uv, wrap),
+            (TextureFilter::Trilinear, Some((axis0, axis1))) => {
+                self.trilinear(uv, axis0.length().max(axis1.length()), wrap)
+            }
+            (TextureFilter::Ewa, Some((axis0, axis1))) => self.ewa(uv, axis0, axis1, wrap),
+        }
+    }
+
+    // fractional level where a texel is about `width` wide, in uv units
+    fn level_for(&self, width: f32) -> f32 {
+        let base = &self.levels[0];
+        let size = base.width.max(base.height) as f32;
+        (width * size).max(1e-8).log2()
+    }
+
+    // texels are looked up one by one so that the wrap mode also applies between the edges
+    fn bilinear(&self, level: usize, uv: Vec2, wrap: (WrapMode, WrapMode)) -> Rgba {
+        let level = level.min(self.levels.len() - 1);
+        let image = &self.levels[level];
+        let pos = uv * Vec2::new(image.width as f32, image.height as f32) - 0.5;
+        let base = pos.floor();
+        let t = pos - base;
+        let (x, y) = (base.x as i32, base.y as i32);
+
+        let top =
+            self.texel(level, x, y, wrap) * (1.0 - t.x) + self.texel(level, x + 1, y, wrap) * t.x;
+        let bottom = self.texel(level, x, y + 1, wrap) * (1.0 - t.x)
+            + self.texel(level, x + 1, y + 1, wrap) * t.x;
+        top * (1.0 - t.y) + bottom * t.y
+    }
+
+    fn trilinear(&self, uv: Vec2, width: f32, wrap: (WrapMode, WrapMode)) -> Rgba {
+        let level = self
+            .level_for(width)
+            .clamp(0.0, (self.levels.len() - 1) as f32);
+        let lower = level.floor();
+        let t = level - lower;
+        if t == 0.0 {
+            return self.bilinear(lower as usize, uv, wrap);
+        }
+
+        self.bilinear(lower as usize, uv, wrap) * (1.0 - t)
+            + self.bilinear(lower as usize + 1, uv, wrap) * t
+    }
+
+    // same as pbrt, the level is picked by the short axis and the ellipse is filtered with a gaussian
+    fn ewa(&self, uv: Vec2, axis0: Vec2, axis1: Vec2, wrap: (WrapMode, WrapMode)) -> Rgba {
+        let (major, mut minor) = if axis0.length_squared() >= axis1.length_squared() {
+            (axis0, axis1)
+        } else {
+            (axis1, axis0)
+        };
+
+        let major_length = major.length();
+        let minor_length = minor.length();
+        if minor_length * MAX_ANISOTROPY < major_length && minor_length > 0.0 {
+            minor *= major_length / (minor_length * MAX_ANISOTROPY);
+        }
+        if minor.length() == 0.0 {
+            return self.bilinear(0, uv, wrap);
+        }
+
+        let level = self.level_for(minor.length()).max(0.0);
+        let lower = level.floor();
+        let t = level - lower;
+        let low = self.ewa_level(lower as usize, uv, major, minor, wrap);
+        if t == 0.0 {
+            return low;
+        }
+
+        low * (1.0 - t) + self.ewa_level(lower as usize + 1, uv, major, minor, wrap) * t
+    }
+
+    fn ewa_level(
+        &self,
+        level: usize,
+        uv: Vec2,
+        axis0: Vec2,
+        axis1: Vec2,
+        wrap: (WrapMode, WrapMode),
+    ) -> Rgba {
+        let Some(image) = self.levels.get(level) else {
+            // past the end everything is a single texel
+            return self.texel(self.levels.len() - 1, 0, 0, wrap);
+        };
+
+        // to texel space, with texel centers on the integers
+        let size = Vec2::new(image.width as f32, image.height as f32);
+        let center = uv * size - 0.5;
+        let axis0 = axis0 * size;
+        let axis1 = axis1 * size;
+
+        // implicit ellipse a x^2 + b x y + c y^2 = 1, the +1 keeps at least a texel inside it
+        let mut a = axis0.y * axis0.y + axis1.y * axis1.y + 1.0;
+        let mut b = -2.0 * (axis0.x * axis0.y + axis1.x * axis1.y);
+        let mut c = axis0.x * axis0.x + axis1.x * axis1.x + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        // bounding box of the ellipse
+        let det = -b * b + 4.0 * a * c;
+        let inv_det = 1.0 / det;
+        let u_sqrt = (det * c).sqrt();
+        let v_sqrt = (a * det).sqrt();
+        let s0 = (center.x - 2.0 * inv_det * u_sqrt).ceil() as i32;
+        let s1 = (center.x + 2.0 * inv_det * u_sqrt).floor() as i32;
+        let t0 = (center.y - 2.0 * inv_det * v_sqrt).ceil() as i32;
+        let t1 = (center.y + 2.0 * inv_det * v_sqrt).floor() as i32;
+
+        const ALPHA: f32 = 2.0;
+        let mut sum = Rgba::NONE;
+        let mut weights = 0.0;
+        for t in t0..=t1 {
+            let tt = t as f32 - center.y;
+            for s in s0..=s1 {
+                let ss = s as f32 - center.x;
+                let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r2 < 1.0 {
+                    let weight = (-ALPHA * r2).exp() - (-ALPHA).exp();
+                    sum += self.texel(level, s, t, wrap) * weight;
+                    weights += weight;
+                }
+            }
+        }
+
+        if weights <= 0.0 {
+            return self.bilinear(level, uv, wrap);
+        }
+        sum / weights
+    }
+
+    fn texel(&self, level: usize, x: i32, y: i32, (wrap_u, wrap_v): (WrapMode, WrapMode)) -> Rgba {
+        let image = &self.levels[level];
+        let x = wrap_u.wrap_texel(x, image.width);
+        let y = wrap_v.wrap_texel(y, image.height);
+        image.get(x, y)
+    }
+}
+
+// 2x2 box filter, the last row or column of odd sizes is folded into the one before it
+fn downsample(image: &Rgba32FImage) -> Rgba32FImage {
+    let (w, h) = image.dimensions();
+    let (new_w, new_h) = ((w / 2).max(1), (h / 2).max(1));
+
+    Rgba32FImage::from_fn(new_w, new_h, |x, y| {
+        let xs = 2 * x..(2 * x + if x == new_w - 1 { w - 2 * x } else { 2 }).min(w);
+        let ys = 2 * y..(2 * y + if y == new_h - 1 { h - 2 * y } else { 2 }).min(h);
+
+        let mut sum = [0.0; 4];
+        let mut count = 0.0;
+        for py in ys {
+            for px in xs.clone() {
+                for (total, value) in sum.iter_mut().zip(image.get_pixel(px, py).0) {
+                    *total += value;
+                }
+                count += 1.0;
+            }
+        }
+
+        image::Rgba(sum.map(|total| total / count))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPEAT: (WrapMode, WrapMode) = (WrapMode::Repeat, WrapMode::Repeat);
+
+    fn gray(value: f32) -> image::Rgba<f32> {
+        image::Rgba([value, value, value, 1.0])
+    }
+
+    // texels numbered in reading order
+    fn counting(w: u32, h: u32) -> Rgba32FImage {
+        Rgba32FImage::from_fn(w, h, |x, y| gray((y * w + x) as f32))
+    }
+
+    #[test]
+    fn odd_sizes_fold_the_last_row_and_column() {
+        let half = downsample(&counting(3, 3));
+        assert_eq!(half.dimensions(), (1, 1));
+        // the average of all 9
+        assert_eq!(half.get_pixel(0, 0).0[0], 4.0);
+
+        let half = downsample(&counting(5, 1));
+        assert_eq!(half.dimensions(), (2, 1));
+        assert_eq!(half.get_pixel(0, 0).0[0], 0.5);
+        assert_eq!(half.get_pixel(1, 0).0[0], 3.0);
+
+        let levels = MipMap::new(counting(5, 3)).levels;
+        let sizes: Vec<_> = levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, [(5, 3), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn level_for_the_width_of_a_texel() {
+        let mipmap = MipMap::new(counting(8, 4));
+        assert_eq!(mipmap.level_for(1.0 / 8.0), 0.0);
+        assert_eq!(mipmap.level_for(1.0 / 4.0), 1.0);
+        assert_eq!(mipmap.level_for(1.0), 3.0);
+        assert!((mipmap.level_for(1.0 / 8.0 * 2f32.sqrt()) - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn trilinear_blends_two_levels() {
+        // a checkerboard averages out to 0.5 one level down
+        let checkers = Rgba32FImage::from_fn(4, 4, |x, y| gray(((x + y) % 2) as f32));
+        let mipmap = MipMap::new(checkers);
+        let uv = Vec2::splat(0.125);
+        let footprint = |width: f32| Some((Vec2::new(width, 0.0), Vec2::new(0.0, width)));
+
+        let sharp = mipmap.sample(uv, footprint(0.25), TextureFilter::Trilinear, REPEAT);
+        assert_eq!(sharp.to_array()[0], 0.0);
+        let halfway = mipmap.sample(
+            uv,
+            footprint(0.25 * 2f32.sqrt()),
+            TextureFilter::Trilinear,
+            REPEAT,
+        );
+        assert!((halfway.to_array()[0] - 0.25).abs() < 1e-4, "{halfway:?}");
+        let blurred = mipmap.sample(uv, footprint(0.5), TextureFilter::Trilinear, REPEAT);
+        assert!((blurred.to_array()[0] - 0.5).abs() < 1e-5);
+        // huge footprints stop at the last level
+        let last = mipmap.sample(uv, footprint(100.0), TextureFilter::Trilinear, REPEAT);
+        assert!((last.to_array()[0] - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn ewa_weights_follow_the_ellipse() {
+        // a constant image stays constant, the weights add up to one
+        let mipmap = MipMap::new(Rgba32FImage::from_pixel(16, 16, gray(0.3)));
+        let color = mipmap.sample(
+            Vec2::splat(0.4),
+            Some((Vec2::new(0.2, 0.05), Vec2::new(-0.01, 0.04))),
+            TextureFilter::Ewa,
+            REPEAT,
+        );
+        assert!((color.to_array()[0] - 0.3).abs() < 1e-5);
+
+        // stripes along v: a footprint stretched along them keeps them, across them averages them out
+        let stripes = Rgba32FImage::from_fn(16, 16, |x, _| gray((x / 2 % 2) as f32));
+        let mipmap = MipMap::new(stripes);
+        let uv = Vec2::new(1.0 / 16.0, 0.5);
+        let along = (Vec2::new(0.0, 0.25), Vec2::new(0.01, 0.0));
+        let across = (Vec2::new(0.25, 0.0), Vec2::new(0.0, 0.01));
+
+        let along = mipmap.ewa_level(0, uv, along.0, along.1, REPEAT).to_array()[0];
+        let across = mipmap
+            .ewa_level(0, uv, across.0, across.1, REPEAT)
+            .to_array()[0];
+        assert!(along < 0.05, "{along}");
+        assert!((across - 0.5).abs() < 0.1, "{across}");
+    }
+}
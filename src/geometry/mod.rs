@@ -1,4 +1,6 @@
+use anyhow::{Result, bail};
 use glam::{Mat4, Vec2, Vec3, Vec4Swizzles};
+use std::sync::Arc;
 
 mod storage;
 pub use storage::*;
@@ -7,20 +9,119 @@ mod material;
 use crate::color::Rgba;
 pub use material::*;
 
+mod volume;
+pub use volume::*;
+
+mod quadric;
+pub use quadric::*;
+
+mod subdivision;
+
+mod curves;
+pub use curves::*;
+
+mod points;
+pub use points::*;
+
+mod mipmap;
+pub use mipmap::*;
+
+mod sampler;
+pub use sampler::*;
+
+mod registry;
+pub use registry::*;
+
+mod cubemap;
+pub use cubemap::*;
+
+mod background;
+pub use background::*;
+
+mod sky;
+pub use sky::*;
+
+mod procedural;
+pub use procedural::*;
+
+mod alpha;
+pub use alpha::*;
+
 #[derive(Clone)]
 pub struct SphereGeometry {
     pub radius: f32,
     pub center: Vec3,
 }
 
-#[derive(Clone, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct MeshGeometry {
     pub verts: Vec<(f32, f32, f32)>,
     pub indices: Vec<(u32, u32, u32)>,
-    pub tex_coords: Vec<Vec2>, // not sent to embree
+    pub tex_coords: Vec<Vec2>,            // not sent to embree
+    pub extra_tex_coords: Vec<Vec<Vec2>>, // other uv sets (glTF TEXCOORD_1 and up)
+    pub tex_coord_set: usize,             // the set textures are sampled with, 0 is tex_coords
 }
 
 impl MeshGeometry {
+    /// textures are sampled with the first uv set unless told otherwise, 1 is the first extra one
+    pub fn use_tex_coord_set(&mut self, set: usize) -> Result<()> {
+        if set > self.extra_tex_coords.len() {
+            bail!(
+                "Mesh has no uv set {set}, only {}",
+                self.extra_tex_coords.len() + 1
+            );
+        }
+        self.tex_coord_set = set;
+        Ok(())
+    }
+
+    /// the uv set textures are sampled with
+    pub fn active_tex_coords(&self) -> &[Vec2] {
+        match self.tex_coord_set {
+            0 => &self.tex_coords,
+            set => &self.extra_tex_coords[set - 1],
+        }
+    }
+
+    // u v are actually the uv passed in by embree
+    pub fn compute_uv(&self, u: f32, v: f32, prim_id: u32) -> Vec2 {
+        let w = 1.0 - u - v;
+
+        // get the indices for this triangle
+        let (i0, i1, i2) = self.indices[prim_id as usize];
+
+        // uv of the vertices
+        let tex_coords = self.active_tex_coords();
+        let vertex_uv_0 = tex_coords[i0 as usize];
+        let vertex_uv_1 = tex_coords[i1 as usize];
+        let vertex_uv_2 = tex_coords[i2 as usize];
+
+        // not clamped, outside of [0, 1] is up to the wrap mode of the texture
+        vertex_uv_0 * w + vertex_uv_1 * u + vertex_uv_2 * v
+    }
+
+    /// how far the texture coordinates move along `offset`, a vector on the surface of the triangle
+    pub fn uv_offset(&self, prim_id: u32, offset: Vec3) -> Vec2 {
+        let (i0, i1, i2) = self.indices[prim_id as usize];
+        let p0 = Vec3::from(self.verts[i0 as usize]);
+        let edge1 = Vec3::from(self.verts[i1 as usize]) - p0;
+        let edge2 = Vec3::from(self.verts[i2 as usize]) - p0;
+
+        // offset = a * edge1 + b * edge2, least squares in case it is not exactly on the plane
+        let (e11, e12, e22) = (edge1.dot(edge1), edge1.dot(edge2), edge2.dot(edge2));
+        let det = e11 * e22 - e12 * e12;
+        if det.abs() < 1e-12 {
+            return Vec2::ZERO;
+        }
+        let (d1, d2) = (offset.dot(edge1), offset.dot(edge2));
+        let a = (d1 * e22 - d2 * e12) / det;
+        let b = (d2 * e11 - d1 * e12) / det;
+
+        let tex_coords = self.active_tex_coords();
+        let uv0 = tex_coords[i0 as usize];
+        (tex_coords[i1 as usize] - uv0) * a + (tex_coords[i2 as usize] - uv0) * b
+    }
+
     pub fn transform(&mut self, matrix: Mat4) {
         for vert in &mut self.verts {
             let pos = Vec3::from(*vert).extend(1.0);
@@ -30,52 +131,141 @@ impl MeshGeometry {
     }
 }
 
+/// A mesh shared between several places in the scene, the vertices are only stored once.
+/// embree builds the BVH of the mesh once and every instance only adds its transform on top
+#[derive(Clone)]
+pub struct MeshInstance {
+    pub mesh: Arc<MeshGeometry>,
+    pub transform: Mat4,
+}
+
+impl MeshInstance {
+    pub fn new(mesh: Arc<MeshGeometry>, transform: Mat4) -> Self {
+        Self { mesh, transform }
+    }
+}
+
 #[derive(Clone)]
 pub enum GeomInfo {
     Mesh(MeshGeometry),
     Sphere(SphereGeometry),
+    /// the material is per instance, so the same mesh can be used with different ones
+    Instance(MeshInstance),
+    Quadric(QuadricGeometry),
+    Curves(CurveGeometry),
+    Points(PointCloud),
+}
+
+/// Transforms applied on top of the geometry during the frame, for motion blur.
+/// Keyframes are evenly spaced in time from 0.0 to 1.0 and linearly interpolated in between
+#[derive(Clone)]
+pub struct Motion {
+    pub keyframes: Vec<Mat4>,
+}
+
+impl Motion {
+    pub fn new(keyframes: Vec<Mat4>) -> Result<Self> {
+        if keyframes.len() < 2 {
+            bail!("Motion needs at least 2 keyframes, got {}", keyframes.len());
+        }
+        Ok(Self { keyframes })
+    }
+
+    pub fn linear(start: Mat4, end: Mat4) -> Self {
+        Self {
+            keyframes: vec![start, end],
+        }
+    }
+
+    pub fn translation(offset: Vec3) -> Self {
+        Self::linear(Mat4::IDENTITY, Mat4::from_translation(offset))
+    }
 }
 
 #[derive(Clone)]
 pub struct Geometry {
     pub info: GeomInfo,
     pub material: Material,
+    pub motion: Option<Motion>,
 }
 
 impl Geometry {
     pub fn with_material(material: Material, info: GeomInfo) -> Self {
-        Self { material, info }
+        Self {
+            material,
+            info,
+            motion: None,
+        }
+    }
+
+    pub fn with_motion(mut self, motion: Motion) -> Self {
+        self.motion = Some(motion);
+        self
     }
 
     // u v are actually the uv passed in by embree
-    pub fn compute_uv(&self, u: f32, v: f32, prim_id: u32) -> Vec2 {
+    // normal is only used by spheres, embree does not give them a uv
+    pub fn compute_uv(&self, u: f32, v: f32, prim_id: u32, normal: Vec3) -> Vec2 {
         match self.info {
-            GeomInfo::Mesh(ref mesh) => {
-                let w = 1.0 - u - v;
-
-                // get the indices for this triangle
-                let (i0, i1, i2) = mesh.indices[prim_id as usize];
-
-                // // position of the vertices
-                // let p0 = mesh.verts[i0];
-                // let p1 = mesh.verts[i1];
-                // let p2 = mesh.verts[i2];
-
-                // uv of the vertices
-                let vertex_uv_0 = mesh.tex_coords[i0 as usize];
-                let vertex_uv_1 = mesh.tex_coords[i1 as usize];
-                let vertex_uv_2 = mesh.tex_coords[i2 as usize];
-
-                let actual_u =
-                    (vertex_uv_0.x * w + vertex_uv_1.x * u + vertex_uv_2.x * v).clamp(0.0, 1.0);
-                // FIX: textures are flipped vertically. is it this math or the image loader?
-                // for now I just added 1.0 - ... here
-                let actual_v = 1.0
-                    - (vertex_uv_0.y * w + vertex_uv_1.y * u + vertex_uv_2.y * v).clamp(0.0, 1.0);
-
-                Vec2::new(actual_u, actual_v)
-            }
-            _ => Vec2::ZERO, // TODO: how do I implement this
+            GeomInfo::Mesh(ref mesh) => mesh.compute_uv(u, v, prim_id),
+            GeomInfo::Instance(ref instance) => instance.mesh.compute_uv(u, v, prim_id),
+            GeomInfo::Sphere(_) => sphere_uv(normal.normalize()),
+            // the intersection callback already computed it
+            // for curves u goes along the segment
+            GeomInfo::Quadric(_) | GeomInfo::Curves(_) => Vec2::new(u, v),
+            // points use their own color instead of textures
+            GeomInfo::Points(_) => Vec2::ZERO,
+        }
+    }
+
+    /// Area of the texture covered by a ray cone `width` wide when it hits the surface, as the two axes of an ellipse in uv space.
+    /// The cone is stretched along the surface when it hits at a grazing angle.
+    /// Only meshes have one, everything else is sampled at full resolution
+    pub fn uv_footprint(
+        &self,
+        prim_id: u32,
+        normal: Vec3,
+        ray_dir: Vec3,
+        width: f32,
+    ) -> Option<(Vec2, Vec2)> {
+        let (mesh, to_mesh) = match self.info {
+            GeomInfo::Mesh(ref mesh) => (mesh, Mat4::IDENTITY),
+            GeomInfo::Instance(ref instance) => (&*instance.mesh, instance.transform.inverse()),
+            _ => return None,
+        };
+        if width <= 0.0 {
+            return None;
+        }
+
+        let normal = normal.normalize();
+        let dir = ray_dir.normalize();
+        let cos = dir.dot(normal).abs().max(0.01);
+        let along = (dir - normal * dir.dot(normal)).normalize_or(normal.any_orthonormal_vector());
+        let across = normal.cross(along);
+
+        let axis0 = to_mesh.transform_vector3(along * width / cos);
+        let axis1 = to_mesh.transform_vector3(across * width);
+        Some((
+            mesh.uv_offset(prim_id, axis0),
+            mesh.uv_offset(prim_id, axis1),
+        ))
+    }
+
+    /// `pos` without the transform of the shape, for object space textures.
+    /// meshes and most shapes are moved when transformed, so it is the same point for them
+    pub fn object_point(&self, pos: Vec3) -> Vec3 {
+        match self.info {
+            GeomInfo::Instance(ref instance) => instance.transform.inverse().transform_point3(pos),
+            GeomInfo::Quadric(ref quadric) => quadric.transform.inverse().transform_point3(pos),
+            _ => pos,
+        }
+    }
+
+    /// direction along the surface that anisotropic materials (hair) are aligned to, only curves have one
+    pub fn tangent(&self, prim_id: u32, u: f32) -> Option<Vec3> {
+        match self.info {
+            GeomInfo::Curves(ref curves) => Some(curves.tangent(prim_id, u)),
+            _ => None,
         }
     }
 
@@ -84,8 +274,33 @@ impl Geometry {
             GeomInfo::Mesh(ref mut mesh) => {
                 mesh.transform(matrix);
             }
-            _ => {
-                panic!("transform for spheres not implemented yet");
+            GeomInfo::Instance(ref mut instance) => {
+                instance.transform = matrix * instance.transform;
+            }
+            GeomInfo::Quadric(ref mut quadric) => {
+                quadric.transform = matrix * quadric.transform;
+            }
+            GeomInfo::Curves(ref mut curves) => {
+                curves.transform(matrix);
+            }
+            GeomInfo::Points(ref mut cloud) => {
+                cloud.transform(matrix);
+            }
+            GeomInfo::Sphere(ref mut sphere) => {
+                // embree spheres can only be moved and scaled uniformly, anything else makes an ellipsoid
+                let linear = glam::Mat3::from_mat4(matrix);
+                let squared = linear.transpose() * linear;
+                let scale = squared.x_axis.x;
+                if squared.abs_diff_eq(glam::Mat3::from_diagonal(Vec3::splat(scale)), 1e-4 * scale)
+                {
+                    sphere.center = matrix.transform_point3(sphere.center);
+                    sphere.radius *= scale.sqrt();
+                } else {
+                    let mut ellipsoid =
+                        QuadricGeometry::ellipsoid(sphere.center, Vec3::splat(sphere.radius));
+                    ellipsoid.transform = matrix * ellipsoid.transform;
+                    self.info = GeomInfo::Quadric(ellipsoid);
+                }
             }
         }
     }
@@ -95,6 +310,13 @@ impl Geometry {
 pub enum Texture {
     Solid(Rgba),
     Image(u32), // an id
+    Procedural(Arc<ProceduralTexture>),
+}
+
+impl Texture {
+    pub fn procedural(root: TextureNode, space: TextureSpace) -> Self {
+        Self::Procedural(Arc::new(ProceduralTexture { root, space }))
+    }
 }
 
 pub struct Light {
@@ -106,6 +328,17 @@ pub enum LightType {
     Ambient,
     Point(Vec3), // stores position
     AreaQuad(LightQuad),
+    /// point light that only shines inside a cone around `direction`.
+    /// angles are from the axis of the cone, in radians. fades out between inner_cone and outer_cone
+    Spot {
+        pos: Vec3,
+        direction: Vec3,
+        inner_cone: f32,
+        outer_cone: f32,
+    },
+    /// infinitely far away light (the sun), stores the direction the light travels in.
+    /// the color is the irradiance it gives a surface facing it, there is no falloff
+    Directional(Vec3),
 }
 
 pub struct LightQuad {
@@ -135,3 +368,41 @@ impl LightQuad {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switching_uv_sets_keeps_every_set() {
+        let mut mesh = MeshGeometry {
+            verts: vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+            indices: vec![(0, 1, 2)],
+            tex_coords: vec![Vec2::ZERO, Vec2::X, Vec2::Y],
+            extra_tex_coords: vec![vec![Vec2::ONE; 3], vec![Vec2::splat(0.5); 3]],
+            tex_coord_set: 0,
+        };
+        assert_eq!(mesh.compute_uv(1.0, 0.0, 0), Vec2::X);
+
+        // twice in a row, then back, each set still has its own values
+        mesh.use_tex_coord_set(2).unwrap();
+        mesh.use_tex_coord_set(2).unwrap();
+        assert_eq!(mesh.compute_uv(1.0, 0.0, 0), Vec2::splat(0.5));
+        mesh.use_tex_coord_set(1).unwrap();
+        assert_eq!(mesh.compute_uv(1.0, 0.0, 0), Vec2::ONE);
+        mesh.use_tex_coord_set(0).unwrap();
+        assert_eq!(mesh.compute_uv(1.0, 0.0, 0), Vec2::X);
+        assert_eq!(mesh.extra_tex_coords[0], [Vec2::ONE; 3]);
+
+        assert!(mesh.use_tex_coord_set(3).is_err());
+        assert_eq!(mesh.tex_coord_set, 0);
+    }
+
+    #[test]
+    fn motion_needs_two_keyframes() {
+        assert!(Motion::new(vec![Mat4::IDENTITY]).is_err());
+        let motion = Motion::translation(Vec3::X);
+        assert_eq!(motion.keyframes.len(), 2);
+        assert_eq!(motion.keyframes[1].transform_point3(Vec3::ZERO), Vec3::X);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::color::Rgba;
+use anyhow::{Result, bail};
+use glam::{Mat4, Vec3, Vec4, Vec4Swizzles};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointShape {
+    Sphere,
+    /// flat disc, facing the ray unless the cloud has normals
+    Disc,
+}
+
+/// Point cloud with a color per point (photogrammetry, lidar), each point is drawn as a small sphere or disc
+#[derive(Debug, Clone)]
+pub struct PointCloud {
+    /// xyz and radius of every point
+    pub points: Vec<Vec4>,
+    /// used instead of the texture of the material
+    pub colors: Vec<Rgba>,
+    /// orients the discs, ignored by spheres
+    pub normals: Option<Vec<Vec3>>,
+    pub shape: PointShape,
+}
+
+impl PointCloud {
+    pub fn new(
+        points: Vec<Vec4>,
+        colors: Vec<Rgba>,
+        normals: Option<Vec<Vec3>>,
+        shape: PointShape,
+    ) -> Result<Self> {
+        if colors.len() != points.len() {
+            bail!(
+                "Point cloud has {} points but {} colors",
+                points.len(),
+                colors.len()
+            );
+        }
+        if let Some(normals) = &normals
+            && normals.len() != points.len()
+        {
+            bail!(
+                "Point cloud has {} points but {} normals",
+                points.len(),
+                normals.len()
+            );
+        }
+
+        Ok(Self {
+            points,
+            colors,
+            normals,
+            shape,
+        })
+    }
+
+    pub fn with_shape(mut self, shape: PointShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    /// every point gets the same radius
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        for point in &mut self.points {
+            point.w = radius;
+        }
+        self
+    }
+
+    pub fn transform(&mut self, matrix: Mat4) {
+        // same as curves, radii are scaled by the average scale
+        let scale = Vec3::new(
+            matrix.x_axis.truncate().length(),
+            matrix.y_axis.truncate().length(),
+            matrix.z_axis.truncate().length(),
+        )
+        .element_sum()
+            / 3.0;
+
+        for point in &mut self.points {
+            *point = matrix.transform_point3(point.xyz()).extend(point.w * scale);
+        }
+
+        if let Some(normals) = &mut self.normals {
+            let normal_matrix = glam::Mat3::from_mat4(matrix).inverse().transpose();
+            for normal in normals {
+                *normal = (normal_matrix * *normal).normalize_or_zero();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<Vec4> {
+        vec![Vec4::new(0.0, 0.0, 0.0, 1.0), Vec4::new(1.0, 2.0, 3.0, 2.0)]
+    }
+
+    #[test]
+    fn every_point_needs_a_color_and_a_normal() {
+        let colors = vec![Rgba::WHITE; 2];
+        assert!(PointCloud::new(points(), vec![Rgba::WHITE], None, PointShape::Disc).is_err());
+        assert!(
+            PointCloud::new(
+                points(),
+                colors.clone(),
+                Some(vec![Vec3::Y]),
+                PointShape::Disc
+            )
+            .is_err()
+        );
+        assert!(
+            PointCloud::new(points(), colors, Some(vec![Vec3::Y; 2]), PointShape::Disc).is_ok()
+        );
+    }
+
+    #[test]
+    fn same_radius_and_shape() {
+        let cloud = PointCloud::new(points(), vec![Rgba::WHITE; 2], None, PointShape::Disc)
+            .unwrap()
+            .with_shape(PointShape::Sphere)
+            .with_radius(0.5);
+        assert_eq!(cloud.shape, PointShape::Sphere);
+        assert!(cloud.points.iter().all(|p| p.w == 0.5));
+    }
+
+    #[test]
+    fn transform_moves_scales_and_turns() {
+        let mut cloud = PointCloud::new(
+            points(),
+            vec![Rgba::WHITE; 2],
+            Some(vec![Vec3::Y; 2]),
+            PointShape::Disc,
+        )
+        .unwrap();
+        cloud.transform(
+            Mat4::from_translation(Vec3::X) * Mat4::from_rotation_z(std::f32::consts::FRAC_PI_2),
+        );
+        assert!((cloud.points[0] - Vec4::new(1.0, 0.0, 0.0, 1.0)).length() < 1e-5);
+        assert!((cloud.normals.unwrap()[0] - Vec3::NEG_X).length() < 1e-5);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::color::Rgba;
+use glam::{IVec3, Mat4, Vec2, Vec3};
+
+/// Where a procedural texture is evaluated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureSpace {
+    /// (u, v, 0), follows the surface like an image would
+    Uv,
+    /// the space of the shape before it was placed, so the pattern moves with it.
+    /// only instances and quadrics keep their transform, everything else is already in world space
+    Object,
+    World,
+}
+
+/// A node of a procedural texture, patterns are greyscale and can be colored with `Mix`.
+/// Patterns repeat every unit of their space, use `Transform` to scale them
+#[derive(Debug, Clone)]
+pub enum TextureNode {
+    Constant(Rgba),
+    /// an image texture of the scene, always read with the uv of the surface
+    Image(u32),
+    /// 3d checkerboard, cubes of size 1
+    Checker {
+        even: Box<TextureNode>,
+        odd: Box<TextureNode>,
+    },
+    /// fractal sum of Perlin noise, in [0, 1]
+    Noise {
+        octaves: u32,
+    },
+    /// sum of the absolute value of the octaves, sharp creases like marble veins
+    Turbulence {
+        octaves: u32,
+    },
+    /// 0 at the origin to 1 at `axis`, clamped
+    Gradient(Vec3),
+    /// one random point per unit cell, jitter is how far from the center of the cell it can be (0 to 1)
+    Voronoi {
+        jitter: f32,
+        output: VoronoiOutput,
+    },
+    /// a to b by factor (its first channel)
+    Mix {
+        a: Box<TextureNode>,
+        b: Box<TextureNode>,
+        factor: Box<TextureNode>,
+    },
+    Multiply(Box<TextureNode>, Box<TextureNode>),
+    /// low..high to 0..1 on every channel, clamped
+    Remap {
+        input: Box<TextureNode>,
+        low: f32,
+        high: f32,
+    },
+    /// evaluates input at the point moved by the matrix
+    Transform {
+        input: Box<TextureNode>,
+        matrix: Mat4,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoronoiOutput {
+    /// to the closest point, 0 at the points
+    Distance,
+    /// a random grey per cell
+    Cell,
+    /// distance to the second closest minus the closest, 0 on the edges between cells
+    Edge,
+}
+
+impl TextureNode {
+    pub fn checker(even: TextureNode, odd: TextureNode) -> Self {
+        Self::Checker {
+            even: Box::new(even),
+            odd: Box::new(odd),
+        }
+    }
+
+    pub fn mix(a: TextureNode, b: TextureNode, factor: TextureNode) -> Self {
+        Self::Mix {
+            a: Box::new(a),
+            b: Box::new(b),
+            factor: Box::new(factor),
+        }
+    }
+
+    /// pattern colored from a (at 0) to b (at 1)
+    pub fn colored(self, a: Rgba, b: Rgba) -> Self {
+        Self::mix(Self::Constant(a), Self::Constant(b), self)
+    }
+
+    pub fn multiply(self, other: TextureNode) -> Self {
+        Self::Multiply(Box::new(self), Box::new(other))
+    }
+
+    pub fn remap(self, low: f32, high: f32) -> Self {
+        Self::Remap {
+            input: Box::new(self),
+            low,
+            high,
+        }
+    }
+
+    /// bigger scale, bigger pattern
+    pub fn scaled(self, scale: f32) -> Self {
+        self.transformed(Mat4::from_scale(Vec3::splat(1.0 / scale)))
+    }
+
+    pub fn transformed(self, matrix: Mat4) -> Self {
+        Self::Transform {
+            input: Box::new(self),
+            matrix,
+        }
+    }
+
+    /// `image` samples image textures of the scene
+    pub fn eval(&self, p: Vec3, uv: Vec2, image: &impl Fn(u32, Vec2) -> Rgba) -> Rgba {
+        match self {
+            TextureNode::Constant(color) => *color,
+            TextureNode::Image(id) => image(*id, uv),
+            TextureNode::Checker { even, odd } => {
+                let cell = p.floor().as_ivec3();
+                if (cell.x + cell.y + cell.z).rem_euclid(2) == 0 {
+                    even.eval(p, uv, image)
+                } else {
+                    odd.eval(p, uv, image)
+                }
+            }
+            TextureNode::Noise { octaves } => grey(fbm(p, *octaves) * 0.5 + 0.5),
+            TextureNode::Turbulence { octaves } => grey(turbulence(p, *octaves)),
+            TextureNode::Gradient(axis) => {
+                grey((p.dot(*axis) / axis.length_squared()).clamp(0.0, 1.0))
+            }
+            TextureNode::Voronoi { jitter, output } => grey(voronoi(p, *jitter, *output)),
+            TextureNode::Mix { a, b, factor } => {
+                let t = factor.eval(p, uv, image).to_array()[0].clamp(0.0, 1.0);
+                a.eval(p, uv, image) * (1.0 - t) + b.eval(p, uv, image) * t
+            }
+            TextureNode::Multiply(a, b) => a.eval(p, uv, image) * b.eval(p, uv, image),
+            TextureNode::Remap { input, low, high } => {
+                let scale = 1.0 / (high - low).max(1e-6);
+                input
+                    .eval(p, uv, image)
+                    .map(|x| ((x - low) * scale).clamp(0.0, 1.0))
+            }
+            TextureNode::Transform { input, matrix } => {
+                input.eval(matrix.transform_point3(p), uv, image)
+            }
+        }
+    }
+}
+
+/// A texture computed from the position of the hit instead of read from an image
+#[derive(Debug, Clone)]
+pub struct ProceduralTexture {
+    pub root: TextureNode,
+    pub space: TextureSpace,
+}
+
+fn grey(x: f32) -> Rgba {
+    Rgba::rgb(x, x, x)
+}
+
+// integer hash of a lattice point, same idea as the permutation table of Perlin's noise
+fn hash(cell: IVec3) -> u32 {
+    let mut h = (cell.x as u32).wrapping_mul(0x8da6_b343)
+        ^ (cell.y as u32).wrapping_mul(0xd816_3841)
+        ^ (cell.z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+// in [0, 1)
+fn hash_float(cell: IVec3, seed: u32) -> f32 {
+    (hash(cell + IVec3::splat(seed as i32 * 1013)) >> 8) as f32 / (1 << 24) as f32
+}
+
+// one of the 12 edge directions of a cube, from Perlin's improved noise
+fn gradient(hash: u32, d: Vec3) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { d.x } else { d.y };
+    let v = if h < 4 {
+        d.y
+    } else if h == 12 || h == 14 {
+        d.x
+    } else {
+        d.z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+fn fade(t: Vec3) -> Vec3 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Perlin noise, about -1 to 1 and 0 on the lattice points
+fn perlin(p: Vec3) -> f32 {
+    let cell = p.floor();
+    let d = p - cell;
+    let cell = cell.as_ivec3();
+    let t = fade(d);
+
+    let corner = |x: i32, y: i32, z: i32| {
+        let offset = IVec3::new(x, y, z);
+        gradient(hash(cell + offset), d - offset.as_vec3())
+    };
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), t.x);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), t.x);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), t.x);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), t.x);
+    lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+}
+
+// every octave doubles the frequency and halves the amplitude, normalized by the sum of amplitudes
+fn fbm(p: Vec3, octaves: u32) -> f32 {
+    octave_sum(p, octaves, perlin)
+}
+
+fn turbulence(p: Vec3, octaves: u32) -> f32 {
+    octave_sum(p, octaves, |p| perlin(p).abs())
+}
+
+fn octave_sum(p: Vec3, octaves: u32, noise: impl Fn(Vec3) -> f32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut p = p;
+    for _ in 0..octaves.max(1) {
+        sum += noise(p) * amplitude;
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    sum / total
+}
+
+fn voronoi(p: Vec3, jitter: f32, output: VoronoiOutput) -> f32 {
+    let cell = p.floor().as_ivec3();
+
+    // the closest point is always in one of the 27 cells around
+    let mut closest = (f32::INFINITY, cell);
+    let mut second = f32::INFINITY;
+    for z in -1..=1 {
+        for y in -1..=1 {
+            for x in -1..=1 {
+                let neighbour = cell + IVec3::new(x, y, z);
+                let offset = Vec3::new(
+                    hash_float(neighbour, 0),
+                    hash_float(neighbour, 1),
+                    hash_float(neighbour, 2),
+                );
+                let point = neighbour.as_vec3() + 0.5 + (offset - 0.5) * jitter;
+                let distance = p.distance(point);
+                if distance < closest.0 {
+                    second = closest.0;
+                    closest = (distance, neighbour);
+                } else if distance < second {
+                    second = distance;
+                }
+            }
+        }
+    }
+
+    match output {
+        VoronoiOutput::Distance => closest.0.min(1.0),
+        VoronoiOutput::Cell => hash_float(closest.1, 3),
+        VoronoiOutput::Edge => (second - closest.0).min(1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(node: &TextureNode, p: Vec3) -> f32 {
+        node.eval(p, Vec2::ZERO, &|_, _| Rgba::BLACK).to_array()[0]
+    }
+
+    #[test]
+    fn checker_alternates_every_unit() {
+        let checker = TextureNode::checker(
+            TextureNode::Constant(Rgba::WHITE),
+            TextureNode::Constant(Rgba::BLACK),
+        );
+        assert_eq!(eval(&checker, Vec3::splat(0.5)), 1.0);
+        assert_eq!(eval(&checker, Vec3::new(1.5, 0.5, 0.5)), 0.0);
+        assert_eq!(eval(&checker, Vec3::new(-0.5, 0.5, 0.5)), 0.0);
+        // twice as big
+        assert_eq!(eval(&checker.scaled(2.0), Vec3::new(1.5, 0.5, 0.5)), 1.0);
+    }
+
+    #[test]
+    fn gradient_remap_and_mix() {
+        let gradient = TextureNode::Gradient(Vec3::X * 4.0);
+        assert_eq!(eval(&gradient, Vec3::new(1.0, 7.0, 0.0)), 0.25);
+        assert_eq!(eval(&gradient, Vec3::new(-1.0, 0.0, 0.0)), 0.0);
+        assert_eq!(eval(&gradient, Vec3::new(9.0, 0.0, 0.0)), 1.0);
+
+        let remapped = gradient.clone().remap(0.25, 0.75);
+        assert_eq!(eval(&remapped, Vec3::new(2.0, 0.0, 0.0)), 0.5);
+        assert_eq!(eval(&remapped, Vec3::new(0.5, 0.0, 0.0)), 0.0);
+
+        let colored = gradient.colored(Rgba::rgb(0.2, 0.0, 0.0), Rgba::rgb(0.6, 0.0, 0.0));
+        assert!((eval(&colored, Vec3::new(2.0, 0.0, 0.0)) - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn images_are_read_with_the_uv() {
+        let node = TextureNode::Image(3).multiply(TextureNode::Constant(Rgba::rgb(0.5, 0.5, 0.5)));
+        let color = node.eval(Vec3::ZERO, Vec2::new(0.25, 0.75), &|id, uv| {
+            assert_eq!(id, 3);
+            Rgba::rgb(uv.x, uv.y, 1.0)
+        });
+        assert_eq!(color.to_array()[..3], [0.125, 0.375, 0.5]);
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let noise = TextureNode::Noise { octaves: 4 };
+        let turbulence = TextureNode::Turbulence { octaves: 4 };
+        for i in 0..200 {
+            let p = Vec3::new(i as f32 * 0.37, i as f32 * 0.11, -(i as f32) * 0.23);
+            assert!((0.0..=1.0).contains(&eval(&noise, p)));
+            assert!((0.0..=1.0).contains(&eval(&turbulence, p)));
+        }
+        // perlin noise is 0 on the lattice
+        assert_eq!(eval(&noise, Vec3::new(3.0, -2.0, 5.0)), 0.5);
+    }
+
+    #[test]
+    fn voronoi_outputs() {
+        let voronoi = |output| TextureNode::Voronoi {
+            jitter: 0.0,
+            output,
+        };
+        // no jitter puts the points in the middle of the cells
+        let center = Vec3::splat(0.5);
+        assert_eq!(eval(&voronoi(VoronoiOutput::Distance), center), 0.0);
+        assert_eq!(eval(&voronoi(VoronoiOutput::Edge), center), 1.0);
+        assert_eq!(
+            eval(&voronoi(VoronoiOutput::Edge), Vec3::new(1.0, 0.5, 0.5)),
+            0.0
+        );
+
+        let cell = voronoi(VoronoiOutput::Cell);
+        assert_eq!(eval(&cell, center), eval(&cell, Vec3::new(0.7, 0.3, 0.6)));
+    }
+}
@@ -0,0 +1,308 @@
+use glam::{Mat3, Mat4, Vec2, Vec3};
+use std::f32::consts::PI;
+
+// "infinite" planes still need finite bounds for the BVH
+const PLANE_EXTENT: f32 = 1.0e5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuadricShape {
+    /// radius 1 around the origin, non-uniform scale makes it an ellipsoid
+    Sphere,
+    /// radius 1 around the Y axis, from y = 0 to y = 1, open at both ends
+    Cylinder,
+    /// radius 1 at y = 0, facing +Y
+    Disk,
+    /// y = 0, facing +Y
+    Plane,
+}
+
+/// An analytic shape intersected by embree through user geometry callbacks.
+/// The shape is defined in its own space and placed in the scene by `transform`
+#[derive(Debug, Clone)]
+pub struct QuadricGeometry {
+    pub shape: QuadricShape,
+    pub transform: Mat4,
+}
+
+/// Intersection in the space of the shape
+pub struct QuadricHit {
+    pub t: f32,
+    pub normal: Vec3,
+    pub uv: Vec2,
+}
+
+impl QuadricGeometry {
+    pub fn new(shape: QuadricShape, transform: Mat4) -> Self {
+        Self { shape, transform }
+    }
+
+    pub fn ellipsoid(center: Vec3, radii: Vec3) -> Self {
+        Self::new(
+            QuadricShape::Sphere,
+            Mat4::from_scale_rotation_translation(radii, Default::default(), center),
+        )
+    }
+
+    /// axis goes from the center of the bottom to the center of the top
+    pub fn cylinder(base: Vec3, axis: Vec3, radius: f32) -> Self {
+        let rotation = glam::Quat::from_rotation_arc(Vec3::Y, axis.normalize());
+        let scale = Vec3::new(radius, axis.length(), radius);
+        Self::new(
+            QuadricShape::Cylinder,
+            Mat4::from_scale_rotation_translation(scale, rotation, base),
+        )
+    }
+
+    pub fn disk(center: Vec3, normal: Vec3, radius: f32) -> Self {
+        let rotation = glam::Quat::from_rotation_arc(Vec3::Y, normal.normalize());
+        Self::new(
+            QuadricShape::Disk,
+            Mat4::from_scale_rotation_translation(Vec3::splat(radius), rotation, center),
+        )
+    }
+
+    pub fn plane(point: Vec3, normal: Vec3) -> Self {
+        let rotation = glam::Quat::from_rotation_arc(Vec3::Y, normal.normalize());
+        Self::new(
+            QuadricShape::Plane,
+            Mat4::from_rotation_translation(rotation, point),
+        )
+    }
+
+    /// corners of the box around the shape, in its own space
+    pub fn local_bounds(&self) -> (Vec3, Vec3) {
+        match self.shape {
+            QuadricShape::Sphere => (Vec3::splat(-1.0), Vec3::splat(1.0)),
+            QuadricShape::Cylinder => (Vec3::new(-1.0, 0.0, -1.0), Vec3::ONE),
+            QuadricShape::Disk => (Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0)),
+            QuadricShape::Plane => (
+                Vec3::new(-PLANE_EXTENT, 0.0, -PLANE_EXTENT),
+                Vec3::new(PLANE_EXTENT, 0.0, PLANE_EXTENT),
+            ),
+        }
+    }
+
+    /// box around the shape after `matrix`
+    pub fn bounds(&self, matrix: Mat4) -> (Vec3, Vec3) {
+        let (lower, upper) = self.local_bounds();
+        (0..8)
+            .map(|corner| {
+                let select = |bit: u32, l: f32, u: f32| if corner & bit == 0 { l } else { u };
+                matrix.transform_point3(Vec3::new(
+                    select(1, lower.x, upper.x),
+                    select(2, lower.y, upper.y),
+                    select(4, lower.z, upper.z),
+                ))
+            })
+            .fold(
+                (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+                |(lower, upper), p| (lower.min(p), upper.max(p)),
+            )
+    }
+
+    /// origin and dir are in the space of the shape. dir is not normalized, so t is the same as in world space
+    pub fn intersect_local(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<QuadricHit> {
+        let in_range = |t: f32| t > t_min && t < t_max;
+
+        match self.shape {
+            QuadricShape::Sphere => {
+                let a = dir.dot(dir);
+                let b = 2.0 * origin.dot(dir);
+                let c = origin.dot(origin) - 1.0;
+                let t = solve_quadratic(a, b, c)
+                    .into_iter()
+                    .flatten()
+                    .find(|t| in_range(*t))?;
+
+                let p = origin + dir * t;
+                Some(QuadricHit {
+                    t,
+                    normal: p,
+                    uv: sphere_uv(p),
+                })
+            }
+            QuadricShape::Cylinder => {
+                let a = dir.x * dir.x + dir.z * dir.z;
+                let b = 2.0 * (origin.x * dir.x + origin.z * dir.z);
+                let c = origin.x * origin.x + origin.z * origin.z - 1.0;
+                // the closest root can be outside of the height, the other one is then the inside wall
+                let (t, p) = solve_quadratic(a, b, c)
+                    .into_iter()
+                    .flatten()
+                    .map(|t| (t, origin + dir * t))
+                    .find(|(t, p)| in_range(*t) && (0.0..=1.0).contains(&p.y))?;
+
+                Some(QuadricHit {
+                    t,
+                    normal: Vec3::new(p.x, 0.0, p.z),
+                    uv: Vec2::new(azimuth_u(p), 1.0 - p.y),
+                })
+            }
+            QuadricShape::Disk | QuadricShape::Plane => {
+                if dir.y == 0.0 {
+                    return None;
+                }
+                let t = -origin.y / dir.y;
+                if !in_range(t) {
+                    return None;
+                }
+
+                let p = origin + dir * t;
+                let uv = if self.shape == QuadricShape::Disk {
+                    if p.x * p.x + p.z * p.z > 1.0 {
+                        return None;
+                    }
+                    // the disk fills the texture
+                    Vec2::new(p.x, p.z) * 0.5 + 0.5
+                } else {
+                    if p.x.abs() > PLANE_EXTENT || p.z.abs() > PLANE_EXTENT {
+                        return None;
+                    }
+                    // one unit is the whole texture, past that is up to its wrap mode
+                    Vec2::new(p.x, p.z)
+                };
+
+                Some(QuadricHit {
+                    t,
+                    normal: Vec3::Y,
+                    uv,
+                })
+            }
+        }
+    }
+
+    /// turns normals from the space of the shape to world space
+    pub fn normal_matrix(matrix: Mat4) -> Mat3 {
+        Mat3::from_mat4(matrix).inverse().transpose()
+    }
+}
+
+// both roots in increasing order, when there are any
+fn solve_quadratic(a: f32, b: f32, c: f32) -> [Option<f32>; 2] {
+    if a == 0.0 {
+        return [None, None];
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return [None, None];
+    }
+
+    // avoids the cancellation of -b + sqrt when they are close
+    let sqrt = discriminant.sqrt();
+    let q = -0.5 * (b + b.signum() * sqrt);
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    [Some(t0.min(t1)), Some(t0.max(t1))]
+}
+
+// angle around the Y axis in [0, 1], the seam is at -X
+fn azimuth_u(p: Vec3) -> f32 {
+    (p.z.atan2(-p.x) + PI) / (2.0 * PI)
+}
+
+/// latitude-longitude mapping of a point on the unit sphere, v = 0 at the top
+pub fn sphere_uv(p: Vec3) -> Vec2 {
+    Vec2::new(azimuth_u(p), p.y.clamp(-1.0, 1.0).acos() / PI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(shape: QuadricShape) -> QuadricGeometry {
+        QuadricGeometry::new(shape, Mat4::IDENTITY)
+    }
+
+    #[test]
+    fn sphere_front_and_back() {
+        let sphere = shape(QuadricShape::Sphere);
+        let hit = sphere
+            .intersect_local(Vec3::new(0.0, 0.0, -5.0), Vec3::Z, 0.0, f32::MAX)
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::NEG_Z).length() < 1e-5);
+        assert!((hit.uv.y - 0.5).abs() < 1e-5);
+
+        // from inside only the far side is left
+        let hit = sphere
+            .intersect_local(Vec3::ZERO, Vec3::Z * 2.0, 0.0, f32::MAX)
+            .unwrap();
+        assert!((hit.t - 0.5).abs() < 1e-5);
+
+        assert!(
+            sphere
+                .intersect_local(Vec3::new(0.0, 0.0, -5.0), Vec3::Z, 0.0, 3.0)
+                .is_none()
+        );
+        assert!(
+            sphere
+                .intersect_local(Vec3::new(0.0, 2.0, -5.0), Vec3::Z, 0.0, f32::MAX)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn cylinder_is_open() {
+        let cylinder = shape(QuadricShape::Cylinder);
+        let hit = cylinder
+            .intersect_local(Vec3::new(-3.0, 0.5, 0.0), Vec3::X, 0.0, f32::MAX)
+            .unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::NEG_X).length() < 1e-5);
+        assert!((hit.uv.y - 0.5).abs() < 1e-5);
+
+        // comes in through the open top and hits the inside of the wall
+        let dir = Vec3::new(1.0, -1.0, 0.0);
+        let hit = cylinder
+            .intersect_local(Vec3::new(-0.5, 1.5, 0.0), dir, 0.0, f32::MAX)
+            .unwrap();
+        assert!((hit.t - 1.5).abs() < 1e-5);
+
+        // straight down the axis never touches the wall
+        assert!(
+            cylinder
+                .intersect_local(Vec3::new(0.0, 2.0, 0.0), Vec3::NEG_Y, 0.0, f32::MAX)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn disk_and_plane() {
+        let disk = shape(QuadricShape::Disk);
+        let hit = disk
+            .intersect_local(Vec3::new(0.5, 1.0, 0.0), Vec3::NEG_Y, 0.0, f32::MAX)
+            .unwrap();
+        assert_eq!(hit.t, 1.0);
+        assert_eq!(hit.uv, Vec2::new(0.75, 0.5));
+        assert!(
+            disk.intersect_local(Vec3::new(1.5, 1.0, 0.0), Vec3::NEG_Y, 0.0, f32::MAX)
+                .is_none()
+        );
+
+        let plane = shape(QuadricShape::Plane);
+        let hit = plane
+            .intersect_local(Vec3::new(2.5, 1.0, -3.0), Vec3::NEG_Y, 0.0, f32::MAX)
+            .unwrap();
+        // not wrapped, that is up to the sampler
+        assert_eq!(hit.uv, Vec2::new(2.5, -3.0));
+        // parallel
+        assert!(
+            plane
+                .intersect_local(Vec3::new(0.0, 1.0, 0.0), Vec3::X, 0.0, f32::MAX)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn bounds_follow_the_transform() {
+        let cylinder = QuadricGeometry::cylinder(Vec3::new(1.0, 2.0, 3.0), Vec3::Y * 4.0, 0.5);
+        let (lower, upper) = cylinder.bounds(cylinder.transform);
+        assert!((lower - Vec3::new(0.5, 2.0, 2.5)).length() < 1e-5);
+        assert!((upper - Vec3::new(1.5, 6.0, 3.5)).length() < 1e-5);
+    }
+}
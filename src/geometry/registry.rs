@@ -0,0 +1,424 @@
+use super::{CubeMap, ImageTexture, MipMap, Sampler, Texture, TextureFormat};
+use anyhow::{Context, Result, bail};
+use fxhash::FxHashMap;
+use image::{DynamicImage, ImageReader, Rgba32FImage};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+/// The texture `Texture::Image(0)` points to, shown on materials that use a texture without setting one
+pub const DEFAULT_TEXTURE: u32 = 0;
+const DEFAULT_TEXTURE_PATH: &str = "assets/textures/uv.png";
+
+/// How a file is loaded by `TextureRegistry`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureOptions {
+    pub sampler: Sampler,
+    /// None picks it from the file, see `TextureFormat::of`
+    pub format: Option<TextureFormat>,
+    /// decode the file the first time a ray needs it instead of right away.
+    /// files that are never seen are never loaded, but errors only show up while rendering
+    pub lazy: bool,
+}
+
+impl TextureOptions {
+    pub const DEFAULT: Self = Self {
+        sampler: Sampler::REPEAT,
+        format: None,
+        lazy: false,
+    };
+
+    pub const fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    #[allow(dead_code)] // overrides what the file asks for, every file of the presets is stored as it asks
+    pub const fn with_format(mut self, format: TextureFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub const fn lazy(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+}
+
+/// The pixels of a texture, either already there or loaded from a file on first use
+pub struct TextureImage {
+    path: Option<PathBuf>,
+    format: Option<TextureFormat>,
+    mipmap: OnceLock<MipMap>,
+    // why the file could not be loaded on first use, reported after the render
+    error: OnceLock<anyhow::Error>,
+}
+
+impl TextureImage {
+    pub fn new(mipmap: MipMap) -> Self {
+        Self {
+            path: None,
+            format: None,
+            mipmap: OnceLock::from(mipmap),
+            error: OnceLock::new(),
+        }
+    }
+
+    fn lazy(path: PathBuf, format: Option<TextureFormat>) -> Self {
+        Self {
+            path: Some(path),
+            format,
+            mipmap: OnceLock::new(),
+            error: OnceLock::new(),
+        }
+    }
+
+    /// loads the file if it is not loaded yet, other threads wait for it.
+    /// files that can not be loaded are replaced with a placeholder so the render goes on,
+    /// see `TextureRegistry::check_lazy_loads`
+    pub fn get(&self) -> &MipMap {
+        self.mipmap.get_or_init(|| {
+            let path = self
+                .path
+                .as_ref()
+                .expect("Images without a file are loaded when created");
+            load_mipmap(path, self.format).unwrap_or_else(|err| {
+                let _ = self.error.set(err);
+                placeholder()
+            })
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.mipmap.get().is_some()
+    }
+}
+
+/// Every image texture of the scene. Ids are what `Texture::Image` holds, names are optional aliases for them.
+/// Files are only decoded once, loading the same path with the same format again shares the pixels.
+/// Cube maps have ids of their own
+pub struct TextureRegistry {
+    textures: Vec<ImageTexture>,
+    cube_maps: Vec<CubeMap>,
+    names: FxHashMap<String, u32>,
+    // by canonical path, so that different spellings of the same file match.
+    // a file stored in another format is another image
+    files: FxHashMap<(PathBuf, Option<TextureFormat>), Arc<TextureImage>>,
+}
+
+impl TextureRegistry {
+    /// with the default texture, loaded on first use so a missing file only matters if something shows it
+    pub fn new() -> Self {
+        let mut res = Self {
+            textures: Vec::new(),
+            cube_maps: Vec::new(),
+            names: FxHashMap::default(),
+            files: FxHashMap::default(),
+        };
+
+        let id = res
+            .add_file(DEFAULT_TEXTURE_PATH, TextureOptions::DEFAULT.lazy())
+            .expect("Lazy textures are not loaded here");
+        debug_assert_eq!(id, DEFAULT_TEXTURE);
+        res.set_name(id, "default");
+
+        res
+    }
+
+    pub fn get(&self, id: u32) -> &ImageTexture {
+        &self.textures[id as usize]
+    }
+
+    /// an image made in code instead of loaded from a file
+    pub fn add_image(&mut self, image: Rgba32FImage, sampler: Sampler) -> u32 {
+        let image = Arc::new(TextureImage::new(MipMap::new(image)));
+        self.push(ImageTexture::new(image, sampler))
+    }
+
+    pub fn add_file(
+        &mut self,
+        path: impl AsRef<Path> + Sync,
+        options: TextureOptions,
+    ) -> Result<u32> {
+        let ids = self.add_files(&[path], options)?;
+        Ok(ids[0])
+    }
+
+    // decoding and into_rgba32f is actually pretty slow so use this if loading many images
+    // returns the ids of the textures, in the same order as the paths
+    pub fn add_files(
+        &mut self,
+        paths: &[impl AsRef<Path> + Sync],
+        options: TextureOptions,
+    ) -> Result<Vec<u32>> {
+        let images = self.load_files(paths, options)?;
+        let ids = images
+            .into_iter()
+            .map(|image| self.view(image, options.sampler))
+            .collect();
+        Ok(ids)
+    }
+
+    /// the faces in the order of `CubeMap`, the sampler of the options is ignored
+    pub fn add_cube_map(
+        &mut self,
+        paths: &[impl AsRef<Path> + Sync; 6],
+        options: TextureOptions,
+    ) -> Result<u32> {
+        let faces = self.load_files(paths, options)?;
+        let Ok(faces) = <[Arc<TextureImage>; 6]>::try_from(faces) else {
+            unreachable!("There are 6 paths");
+        };
+        self.cube_maps.push(CubeMap::new(faces));
+        Ok(self.cube_maps.len() as u32 - 1)
+    }
+
+    /// a cube map laid out as a cross in a single file, see `CubeMap::from_cross`.
+    /// it has to be cut up into faces, so it is never lazy
+    #[allow(dead_code)] // the skybox of the presets comes as six files
+    pub fn add_cube_map_cross(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: TextureOptions,
+    ) -> Result<u32> {
+        let path = path.as_ref();
+        let image = decode(path)?;
+        let format = options.format.unwrap_or_else(|| TextureFormat::of(&image));
+        let cube_map = CubeMap::from_cross(&image.into_rgba32f(), format)
+            .with_context(|| format!("Error loading cube map {}", path.display()))?;
+        self.cube_maps.push(cube_map);
+        Ok(self.cube_maps.len() as u32 - 1)
+    }
+
+    pub fn cube_map(&self, id: u32) -> &CubeMap {
+        &self.cube_maps[id as usize]
+    }
+
+    /// an error naming every lazy file that failed to load while rendering, they were drawn as a placeholder
+    pub fn check_lazy_loads(&self) -> Result<()> {
+        let mut errors: Vec<String> = self
+            .files
+            .values()
+            .filter_map(|image| image.error.get())
+            .map(|err| format!("{err:#}"))
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        errors.sort();
+        bail!("Textures drawn as a placeholder:\n{}", errors.join("\n"))
+    }
+
+    // the images of the files in the same order, loading the ones that are new.
+    // files that are already there lazily are loaded now if these options are not lazy
+    fn load_files(
+        &mut self,
+        paths: &[impl AsRef<Path> + Sync],
+        options: TextureOptions,
+    ) -> Result<Vec<Arc<TextureImage>>> {
+        let keys: Vec<(PathBuf, Option<TextureFormat>)> = paths
+            .iter()
+            .map(|path| (file_key(path.as_ref()), options.format))
+            .collect();
+
+        // only the files that are not loaded yet, each once
+        let mut new_files: Vec<&(PathBuf, Option<TextureFormat>)> = keys
+            .iter()
+            .filter(|key| match self.files.get(*key) {
+                Some(image) => !options.lazy && !image.is_loaded(),
+                None => true,
+            })
+            .collect();
+        new_files.sort_by(|a, b| a.0.cmp(&b.0));
+        new_files.dedup();
+
+        if options.lazy {
+            for (path, format) in new_files {
+                let image = TextureImage::lazy(path.clone(), *format);
+                self.files.insert((path.clone(), *format), Arc::new(image));
+            }
+        } else {
+            let mipmaps: Vec<MipMap> = new_files
+                .par_iter()
+                .map(|(path, format)| load_mipmap(path, *format))
+                .collect::<Result<_>>()
+                .context("Error loading textures")?;
+            for (key, mipmap) in new_files.into_iter().zip(mipmaps) {
+                match self.files.get(key) {
+                    // a lazy one, textures that already use it get the pixels too
+                    Some(image) => {
+                        let _ = image.mipmap.set(mipmap);
+                    }
+                    None => {
+                        let image = Arc::new(TextureImage::new(mipmap));
+                        self.files.insert(key.clone(), image);
+                    }
+                }
+            }
+        }
+
+        Ok(keys.iter().map(|key| self.files[key].clone()).collect())
+    }
+
+    /// textures repeat by default
+    #[allow(dead_code)] // the presets pick the sampler when adding the texture
+    pub fn set_sampler(&mut self, id: u32, sampler: Sampler) {
+        self.textures[id as usize].sampler = sampler;
+    }
+
+    /// the same image as texture `id` with another sampler, returns the id of the new texture
+    pub fn add_view(&mut self, id: u32, sampler: Sampler) -> u32 {
+        let image = self.textures[id as usize].image.clone();
+        self.view(image, sampler)
+    }
+
+    /// a name for texture `id`, replaces whatever had the name before
+    pub fn set_name(&mut self, id: u32, name: impl Into<String>) {
+        self.names.insert(name.into(), id);
+    }
+
+    /// the texture called `name`
+    #[allow(dead_code)] // lookups by name are for scene code, the presets keep the ids they get
+    pub fn named(&self, name: &str) -> Result<Texture> {
+        match self.names.get(name) {
+            Some(id) => Ok(Texture::Image(*id)),
+            None => bail!("No texture called {name}"),
+        }
+    }
+
+    // a texture with the same image and sampler is reused
+    fn view(&mut self, image: Arc<TextureImage>, sampler: Sampler) -> u32 {
+        let existing = self
+            .textures
+            .iter()
+            .position(|texture| Arc::ptr_eq(&texture.image, &image) && texture.sampler == sampler);
+        match existing {
+            Some(id) => id as u32,
+            None => self.push(ImageTexture::new(image, sampler)),
+        }
+    }
+
+    fn push(&mut self, texture: ImageTexture) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+}
+
+impl Default for TextureRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// files that do not exist can not be canonicalized, they keep their path and fail when loaded
+fn file_key(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+fn decode(path: &Path) -> Result<DynamicImage> {
+    ImageReader::open(path)
+        .with_context(|| format!("Error opening texture {}", path.display()))?
+        .decode()
+        .with_context(|| format!("Error decoding texture {}", path.display()))
+}
+
+fn load_mipmap(path: &Path, format: Option<TextureFormat>) -> Result<MipMap> {
+    let image = decode(path)?;
+    let format = format.unwrap_or_else(|| TextureFormat::of(&image));
+    // the pyramid is built here too, it is just as slow
+    Ok(MipMap::with_format(image.into_rgba32f(), format))
+}
+
+// magenta and black checkers, hard to miss
+fn placeholder() -> MipMap {
+    let image = Rgba32FImage::from_fn(8, 8, |x, y| {
+        if (x + y) % 2 == 0 {
+            image::Rgba([1.0, 0.0, 1.0, 1.0])
+        } else {
+            image::Rgba([0.0, 0.0, 0.0, 1.0])
+        }
+    });
+    MipMap::with_format(image, TextureFormat::U8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lazy, so the files do not have to exist
+    #[test]
+    fn files_are_shared_by_path_and_format() {
+        let mut registry = TextureRegistry::new();
+        let options = TextureOptions::DEFAULT.lazy();
+
+        let a = registry.add_file("missing/a.png", options).unwrap();
+        let ids = registry
+            .add_files(
+                &["missing/a.png", "missing/b.png", "missing/a.png"],
+                options,
+            )
+            .unwrap();
+        assert_eq!(ids[0], a);
+        assert_eq!(ids[2], a);
+        assert_ne!(ids[1], a);
+
+        let half = registry
+            .add_file("missing/a.png", options.with_format(TextureFormat::F16))
+            .unwrap();
+        assert_ne!(half, a);
+        assert!(!Arc::ptr_eq(
+            &registry.get(a).image,
+            &registry.get(half).image
+        ));
+    }
+
+    #[test]
+    fn lazy_failures_are_reported_once() {
+        let mut registry = TextureRegistry::new();
+        let id = registry
+            .add_file("missing/a.png", TextureOptions::DEFAULT.lazy())
+            .unwrap();
+        assert!(registry.check_lazy_loads().is_ok());
+
+        // the render goes on with the placeholder, however many rays ask for it
+        for _ in 0..3 {
+            registry.get(id).image.get();
+        }
+        let err = registry.check_lazy_loads().unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.starts_with("Textures drawn"), "{message}");
+        assert_eq!(message.matches("missing/a.png").count(), 1, "{message}");
+    }
+
+    #[test]
+    fn names_and_samplers() {
+        let mut registry = TextureRegistry::new();
+        assert!(matches!(
+            registry.named("default").unwrap(),
+            Texture::Image(DEFAULT_TEXTURE)
+        ));
+        assert!(registry.named("rock").is_err());
+
+        let image = Rgba32FImage::from_pixel(1, 1, image::Rgba([1.0; 4]));
+        let id = registry.add_image(image, Sampler::REPEAT);
+        registry.set_name(id, "rock");
+        assert!(matches!(registry.named("rock").unwrap(), Texture::Image(i) if i == id));
+
+        registry.set_sampler(id, Sampler::CLAMP);
+        assert_eq!(registry.get(id).sampler, Sampler::CLAMP);
+    }
+
+    #[test]
+    fn eager_loads_of_lazy_files_fail_right_away() {
+        let mut registry = TextureRegistry::new();
+        registry
+            .add_file("missing/a.png", TextureOptions::DEFAULT.lazy())
+            .unwrap();
+        assert!(
+            registry
+                .add_file("missing/a.png", TextureOptions::DEFAULT)
+                .is_err()
+        );
+    }
+}
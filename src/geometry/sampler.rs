@@ -0,0 +1,178 @@
+use glam::Vec2;
+
+/// What happens to uvs outside of [0, 1], same as the glTF wrap modes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    /// tiles the texture
+    Repeat,
+    /// tiles it, flipping every other copy so that the edges match
+    MirroredRepeat,
+    /// stretches the texels on the edge
+    ClampToEdge,
+}
+
+impl WrapMode {
+    /// texel coordinate `x` of a row or column `size` texels long, moved inside of it
+    pub fn wrap_texel(self, x: i32, size: u32) -> u32 {
+        let size = size as i32;
+        let x = match self {
+            WrapMode::Repeat => x.rem_euclid(size),
+            WrapMode::MirroredRepeat => {
+                let x = x.rem_euclid(2 * size);
+                if x >= size { 2 * size - 1 - x } else { x }
+            }
+            WrapMode::ClampToEdge => x.clamp(0, size - 1),
+        };
+        x as u32
+    }
+}
+
+/// Moves the uvs before sampling, same as glTF's KHR_texture_transform.
+/// Scaled first, then rotated around the origin and then offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvTransform {
+    pub offset: Vec2,
+    /// radians, counter-clockwise in uv space
+    pub rotation: f32,
+    pub scale: Vec2,
+}
+
+impl UvTransform {
+    pub const IDENTITY: Self = Self {
+        offset: Vec2::ZERO,
+        rotation: 0.0,
+        scale: Vec2::ONE,
+    };
+
+    pub const fn tiling(scale: Vec2) -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            rotation: 0.0,
+            scale,
+        }
+    }
+
+    pub fn apply(&self, uv: Vec2) -> Vec2 {
+        self.offset + self.apply_vector(uv)
+    }
+
+    /// without the offset, for the footprint of the ray
+    pub fn apply_vector(&self, uv: Vec2) -> Vec2 {
+        let (sin, cos) = self.rotation.sin_cos();
+        let uv = uv * self.scale;
+        // the rotation matrix of the extension, v points down so this is counter-clockwise on the image
+        Vec2::new(cos * uv.x + sin * uv.y, -sin * uv.x + cos * uv.y)
+    }
+}
+
+/// How a texture is read, see `ImageTexture`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampler {
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub transform: UvTransform,
+}
+
+impl Sampler {
+    /// glTF's default
+    pub const REPEAT: Self = Self {
+        wrap_u: WrapMode::Repeat,
+        wrap_v: WrapMode::Repeat,
+        transform: UvTransform::IDENTITY,
+    };
+
+    /// for textures that should not bleed into the opposite edge, like decals
+    pub const CLAMP: Self = Self {
+        wrap_u: WrapMode::ClampToEdge,
+        wrap_v: WrapMode::ClampToEdge,
+        transform: UvTransform::IDENTITY,
+    };
+
+    pub const fn with_transform(mut self, transform: UvTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// the sampler of a glTF texture, with its KHR_texture_transform if there is one
+    pub fn from_gltf(info: &gltf::texture::Info) -> Self {
+        let sampler = info.texture().sampler();
+        let transform = info
+            .texture_transform()
+            .map_or(UvTransform::IDENTITY, |transform| UvTransform {
+                offset: transform.offset().into(),
+                rotation: transform.rotation(),
+                scale: transform.scale().into(),
+            });
+
+        Self {
+            wrap_u: sampler.wrap_s().into(),
+            wrap_v: sampler.wrap_t().into(),
+            transform,
+        }
+    }
+}
+
+impl From<gltf::texture::WrappingMode> for WrapMode {
+    fn from(mode: gltf::texture::WrappingMode) -> Self {
+        match mode {
+            gltf::texture::WrappingMode::Repeat => WrapMode::Repeat,
+            gltf::texture::WrappingMode::MirroredRepeat => WrapMode::MirroredRepeat,
+            gltf::texture::WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn wrap_texels() {
+        let wrap = |mode: WrapMode| [-5, -1, 0, 3, 4, 7, 9].map(|x| mode.wrap_texel(x, 4));
+        assert_eq!(wrap(WrapMode::Repeat), [3, 3, 0, 3, 0, 3, 1]);
+        assert_eq!(wrap(WrapMode::MirroredRepeat), [3, 0, 0, 3, 3, 0, 1]);
+        assert_eq!(wrap(WrapMode::ClampToEdge), [0, 0, 0, 3, 3, 3, 3]);
+        // a single texel is all there is
+        for mode in [
+            WrapMode::Repeat,
+            WrapMode::MirroredRepeat,
+            WrapMode::ClampToEdge,
+        ] {
+            assert_eq!(mode.wrap_texel(-3, 1), 0);
+            assert_eq!(mode.wrap_texel(2, 1), 0);
+        }
+    }
+
+    #[test]
+    fn uv_transform_order_and_rotation() {
+        // a quarter turn takes u to -v, the same matrix as KHR_texture_transform
+        let quarter = UvTransform {
+            rotation: FRAC_PI_2,
+            ..UvTransform::IDENTITY
+        };
+        assert!(quarter.apply(Vec2::X).abs_diff_eq(Vec2::NEG_Y, 1e-6));
+        assert!(quarter.apply(Vec2::Y).abs_diff_eq(Vec2::X, 1e-6));
+
+        // scaled, then rotated, then offset. vectors skip the offset
+        let transform = UvTransform {
+            offset: Vec2::new(0.5, 0.0),
+            rotation: FRAC_PI_2,
+            scale: Vec2::new(2.0, 1.0),
+        };
+        assert!(
+            transform
+                .apply(Vec2::X)
+                .abs_diff_eq(Vec2::new(0.5, -2.0), 1e-6)
+        );
+        assert!(
+            transform
+                .apply_vector(Vec2::X)
+                .abs_diff_eq(Vec2::new(0.0, -2.0), 1e-6)
+        );
+        assert_eq!(
+            UvTransform::tiling(Vec2::splat(3.0)).apply(Vec2::ONE),
+            Vec2::splat(3.0)
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use super::{Light, LightType};
+use crate::color::Rgba;
+use glam::Vec3;
+
+// light of the sun before the atmosphere, in klux. the sky comes out of the model in kcd/m², so they match
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+
+/// Analytic daylight sky (Preetham, Shirley and Smits, 1999), for outdoor scenes without an environment map.
+/// Y is up. The sun itself is not drawn, it is the directional light from `sun_light`
+#[derive(Debug, Clone)]
+pub struct Sky {
+    sun: Vec3,
+    turbidity: f32,
+    /// multiplies the sky and the sun, the model is in thousands of cd/m² which is way too bright
+    pub intensity: f32,
+    /// the lower half of the sphere is the horizon times this
+    pub ground: Rgba,
+    // everything that only depends on the sun and the turbidity, for Y, x and y
+    zenith: [f32; 3],
+    perez: [[f32; 5]; 3],
+}
+
+impl Sky {
+    /// elevation is from the horizon and azimuth from +z towards +x, both in radians.
+    /// turbidity is how hazy the air is, from 2 (very clear) to 10 (hazy), 3 is a clear day
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Self {
+        let sun = Vec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            elevation.cos() * azimuth.cos(),
+        );
+        let t = turbidity.clamp(2.0, 10.0);
+        // the model only goes down to the horizon
+        let theta =
+            (std::f32::consts::FRAC_PI_2 - elevation).clamp(0.0, std::f32::consts::FRAC_PI_2);
+
+        // zenith luminance and chromaticity, from the appendix of the paper
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta_powers = Vec3::new(theta * theta * theta, theta * theta, theta);
+        let chromaticity = |rows: [[f32; 4]; 3]| {
+            let row = |[a, b, c, d]: [f32; 4]| theta_powers.dot(Vec3::new(a, b, c)) + d;
+            t * t * row(rows[0]) + t * row(rows[1]) + row(rows[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        // the zenith values are divided by the distribution at the zenith, so that F is 1 there
+        let zenith = [zenith_luminance, zenith_x, zenith_y];
+        let zenith = std::array::from_fn(|i| zenith[i] / perez_f(perez[i], 0.0, theta));
+
+        Self {
+            sun,
+            turbidity: t,
+            intensity: 0.01,
+            ground: Rgba::rgb(0.3, 0.3, 0.3),
+            zenith,
+            perez,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_ground(mut self, ground: Rgba) -> Self {
+        self.ground = ground;
+        self
+    }
+
+    /// towards the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun
+    }
+
+    /// what a ray going in direction `dir` sees
+    pub fn radiance(&self, dir: Vec3) -> Rgba {
+        let dir = dir.normalize();
+        let below = dir.y < 0.0;
+        // the horizon is as low as the model goes
+        let up = Vec3::new(dir.x, dir.y.max(1e-3), dir.z).normalize();
+
+        let theta = up.y.acos();
+        let gamma = up.dot(self.sun).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] =
+            std::array::from_fn(|i| self.zenith[i] * perez_f(self.perez[i], theta, gamma));
+
+        let color = xyy_to_rgb(x, y, luminance) * self.intensity;
+        if below { color * self.ground } else { color }
+    }
+
+    /// the sun as a directional light, dimmed and reddened by how much air it goes through
+    pub fn sun_light(&self) -> Light {
+        Light {
+            light_type: LightType::Directional(-self.sun_direction()),
+            color: self.sun_color() * SOLAR_ILLUMINANCE * self.intensity,
+        }
+    }
+
+    // transmittance of the atmosphere at a red, green and blue wavelength, from the appendix of the paper.
+    // rayleigh and aerosols only, ozone and water vapour barely change the color
+    fn sun_color(&self) -> Rgba {
+        if self.sun.y <= 0.0 {
+            return Rgba::BLACK;
+        }
+
+        let theta = self.sun.y.acos();
+        // relative optical mass, how much more air there is than straight up
+        let mass = 1.0 / (self.sun.y + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+
+        // micrometers
+        let transmittance = |lambda: f32| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        };
+
+        Rgba::rgb(
+            transmittance(0.65),
+            transmittance(0.57),
+            transmittance(0.475),
+        )
+    }
+}
+
+// Perez et al. distribution of sky luminance, theta is from the zenith and gamma from the sun
+fn perez_f([a, b, c, d, e]: [f32; 5], theta: f32, gamma: f32) -> f32 {
+    (1.0 + a * (b / theta.cos().max(1e-3)).exp())
+        * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Rgba {
+    let y = y.max(1e-6);
+    let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    let rgb = Vec3::new(
+        Vec3::new(3.2406, -1.5372, -0.4986).dot(xyz),
+        Vec3::new(-0.9689, 1.8758, 0.0415).dot(xyz),
+        Vec3::new(0.0557, -0.2040, 1.0570).dot(xyz),
+    )
+    .max(Vec3::ZERO);
+
+    Rgba::rgb(rgb.x, rgb.y, rgb.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sun_color(sky: &Sky) -> [f32; 4] {
+        sky.sun_light().color.to_array()
+    }
+
+    #[test]
+    fn the_sun_shines_down_from_it() {
+        let sky = Sky::new(0.5, 1.0, 3.0);
+        let LightType::Directional(dir) = sky.sun_light().light_type else {
+            panic!("the sun should be a directional light");
+        };
+        assert!((dir + sky.sun_direction()).length() < 1e-6);
+        assert!(dir.y < 0.0);
+    }
+
+    #[test]
+    fn low_sun_is_dimmer_and_redder() {
+        let [r_high, g_high, b_high, _] = sun_color(&Sky::new(1.5, 0.0, 3.0));
+        let [r_low, g_low, b_low, _] = sun_color(&Sky::new(0.05, 0.0, 3.0));
+        assert!(g_low < g_high);
+        assert!(b_low / r_low < b_high / r_high);
+        assert!(b_high > 0.0 && g_high > 0.0 && r_high > 0.0);
+    }
+
+    #[test]
+    fn no_sun_below_the_horizon() {
+        assert_eq!(sun_color(&Sky::new(-0.2, 0.0, 3.0))[..3], [0.0; 3]);
+    }
+
+    #[test]
+    fn ground_dims_the_lower_half() {
+        let sky = Sky::new(0.6, 0.0, 3.0).with_ground(Rgba::rgb(0.5, 0.5, 0.5));
+        let above = sky.radiance(Vec3::new(1.0, 0.0001, 0.0)).to_array();
+        let below = sky.radiance(Vec3::new(1.0, -0.5, 0.0)).to_array();
+        for i in 0..3 {
+            assert!(above[i] > 0.0);
+            assert!((below[i] - above[i] * 0.5).abs() < 1e-3 * above[i]);
+        }
+    }
+
+    #[test]
+    fn brighter_towards_the_sun() {
+        let sky = Sky::new(0.6, 0.0, 3.0);
+        let sun = sky.radiance(sky.sun_direction()).to_array();
+        let away = sky
+            .radiance(Vec3::new(0.0, 0.6f32.sin(), -0.6f32.cos()))
+            .to_array();
+        assert!(sun[1] > away[1]);
+    }
+}
@@ -1,60 +1,58 @@
 use crate::color::Rgba;
-use crate::geometry::{Geometry, Light, Texture};
-use crate::raytracer::{GeometryId, RayTracer, RayTracerBuilder};
-use anyhow::{Context, Result};
+use crate::geometry::{
+    AlphaMask, Background, GeomInfo, Geometry, Light, ParamMap, Sky, SurfaceParams, Texture,
+    TextureFilter, TextureRegistry, TextureSpace, Volume,
+};
+use crate::medium::Medium;
+use crate::raytracer::{GeometryId, RayHitResult, RayTracer, RayTracerBuilder};
+use anyhow::Result;
 use fxhash::FxHashMap;
-use glam::Vec2;
-use image::ImageReader;
-use image::Rgba32FImage;
-use rayon::prelude::*;
+use glam::Vec3;
 
 pub struct Scene {
     pub lights: Vec<Light>,
     pub geometry: Vec<Geometry>,
-    pub textures: Vec<Rgba32FImage>,
+    pub textures: TextureRegistry,
+    /// medium filling everything that is not inside an object
+    pub fog: Option<Medium>,
+    pub volumes: Vec<Volume>,
+    /// black if there is none
+    pub background: Option<Background>,
+    // where the sun of the sky is in lights, so that another sky replaces it
+    sun: Option<usize>,
 }
 
 impl Scene {
-    // use this instead of default so that the default texture is loaded
-    pub fn new() -> Result<Self> {
-        let mut res = Self {
+    pub fn new() -> Self {
+        Self {
             lights: Vec::new(),
             geometry: Vec::new(),
-            textures: Vec::new(),
-        };
-
-        let default_texture: Rgba32FImage = ImageReader::open("assets/textures/uv.png")
-            .context("Default texture (assets/textures/uv.png) does not exist")?
-            .decode()
-            .context("Error decoding texture assets/textures/uv.png")?
-            .into_rgba32f();
-
-        res.textures.push(default_texture);
-
-        Ok(res)
+            textures: TextureRegistry::new(),
+            fog: None,
+            volumes: Vec::new(),
+            background: None,
+            sun: None,
+        }
     }
 
     pub fn add_geometry(&mut self, geom: Geometry) {
         self.geometry.push(geom);
     }
 
-    // decoding and into_rgba32f is actually pretty slow so use this if loading many images
-    pub fn add_textures_batch_from_files(&mut self, paths: &[&str]) -> Result<()> {
-        let new_textures: Result<Vec<Rgba32FImage>> = paths
-            .par_iter() // Parallel iterator
-            .map(|&path| {
-                // Load and decode each texture in parallel
-                Ok(ImageReader::open(path)?.decode()?.into_rgba32f())
-            })
-            .collect(); // Collect results into a Vec
-
-        let new_textures = new_textures.context("Error loading textures")?;
-
-        for texture in new_textures {
-            self.textures.push(texture);
+    /// the sky as the background and its sun as a light, replacing the sun of the previous sky
+    pub fn set_sky(&mut self, sky: Sky) {
+        match self.sun {
+            Some(i) => self.lights[i] = sky.sun_light(),
+            None => {
+                self.lights.push(sky.sun_light());
+                self.sun = Some(self.lights.len() - 1);
+            }
         }
+        self.background = Some(Background::Sky(sky));
+    }
 
-        Ok(())
+    pub fn add_volume(&mut self, volume: Volume) {
+        self.volumes.push(volume);
     }
 
     pub fn build_scene(
@@ -64,7 +62,8 @@ impl Scene {
         let mut geometry_map = FxHashMap::default();
 
         for geometry in self.geometry {
-            let id = raytracer_builder.add_geometry(&geometry)?;
+            let alpha = AlphaMask::new(&geometry, &self.textures)?;
+            let id = raytracer_builder.add_geometry(&geometry, alpha)?;
             geometry_map.insert(id, geometry);
         }
 
@@ -74,54 +73,169 @@ impl Scene {
             lights: self.lights,
             geometry: geometry_map,
             textures: self.textures,
+            fog: self.fog,
+            volumes: self.volumes,
+            background: self.background,
+            sun: self.sun,
             raytracer,
         })
     }
 }
 
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct BuiltScene<T: RayTracer> {
     pub lights: Vec<Light>,
     pub geometry: FxHashMap<GeometryId, Geometry>,
-    pub textures: Vec<Rgba32FImage>,
+    pub textures: TextureRegistry,
+    pub fog: Option<Medium>,
+    pub volumes: Vec<Volume>,
+    pub background: Option<Background>,
+    sun: Option<usize>,
     pub raytracer: T,
 }
 
 impl<T: RayTracer> BuiltScene<T> {
+    /// throws away the acceleration structure, so that the scene can be changed and built again
+    pub fn into_scene(self) -> Scene {
+        let mut geometry: Vec<_> = self.geometry.into_iter().collect();
+        // back in the order it was added
+        geometry.sort_unstable_by_key(|(id, _)| *id);
+
+        Scene {
+            lights: self.lights,
+            geometry: geometry.into_iter().map(|(_, geometry)| geometry).collect(),
+            textures: self.textures,
+            fog: self.fog,
+            volumes: self.volumes,
+            background: self.background,
+            sun: self.sun,
+        }
+    }
+
     pub fn get_geometry(&self, id: GeometryId) -> Option<&Geometry> {
         self.geometry.get(&id)
     }
 
-    // to avoid repetitions, this is more efficient
-    // returns (diff, emissive)
-    // FIX: this code is bad
-    pub fn sample_color(&self, geom: &Geometry, prim_id: u32, u: f32, v: f32) -> (Rgba, Rgba) {
-        if let Texture::Solid(diff) = geom.material.texture {
-            if let Texture::Solid(emissive) = geom.material.emissive {
-                return (diff, emissive);
-            }
+    /// what a ray that hit nothing sees, see `Background::radiance`
+    pub fn background(&self, dir: Vec3, spread: f32, filter: TextureFilter) -> Rgba {
+        self.background.as_ref().map_or(Rgba::BLACK, |background| {
+            background.radiance(dir, spread, &self.textures, filter)
+        })
+    }
+
+    /// Every parameter of the material at the hit, textures included.
+    /// cone_width is how wide the ray is when it gets to the hit, it picks the mip level
+    pub fn sample_surface(
+        &self,
+        geom: &Geometry,
+        hit: &RayHitResult,
+        ray_dir: Vec3,
+        cone_width: f32,
+        filter: TextureFilter,
+    ) -> SurfaceParams {
+        let material = &geom.material;
+        let prim_id = hit.triangle_id;
+
+        // every point has its own color, and no uvs for textures
+        if let GeomInfo::Points(ref cloud) = geom.info {
+            let emissive = match material.emissive {
+                Texture::Solid(emissive) => emissive,
+                Texture::Image(_) | Texture::Procedural(_) => Rgba::NONE,
+            };
+            return SurfaceParams::constant(material, cloud.colors[prim_id as usize], emissive)
+                .with_metallic(material.metallic);
         }
 
-        // one of the textures is not a solid color
-        // my goal was to only calculate this when needed
-        // but the sampling should also have some unneeded repetitions
-        let uv = geom.compute_uv(u, v, prim_id);
+        // only calculate the uv when something needs it
+        if let (Texture::Solid(diff), Texture::Solid(emissive)) =
+            (&material.texture, &material.emissive)
+            && material.maps.is_empty()
+        {
+            return SurfaceParams::constant(material, *diff, *emissive)
+                .with_metallic(material.metallic);
+        }
 
-        let diff: Rgba = match geom.material.texture {
-            Texture::Solid(diff) => diff,
-            Texture::Image(id) => Self::sample_texture(uv, &self.textures[id as usize]),
+        let uv = geom.compute_uv(hit.u, hit.v, prim_id, hit.normal);
+        // only meshes have a footprint, spheres, quadrics and curves are sampled at full resolution whatever the filter
+        let footprint = match filter {
+            TextureFilter::Bilinear => None,
+            _ => geom.uv_footprint(prim_id, hit.normal, ray_dir, cone_width),
         };
 
-        let emissive: Rgba = match geom.material.emissive {
-            Texture::Solid(emissive) => emissive,
-            Texture::Image(id) => Self::sample_texture(uv, &self.textures[id as usize]),
+        let image = |id: u32, uv| self.textures.get(id).sample(uv, footprint, filter);
+        let sample = |texture: &Texture| match texture {
+            Texture::Solid(color) => *color,
+            Texture::Image(id) => image(*id, uv),
+            Texture::Procedural(procedural) => {
+                let p = match procedural.space {
+                    TextureSpace::Uv => uv.extend(0.0),
+                    TextureSpace::Object => geom.object_point(hit.hit_point),
+                    TextureSpace::World => hit.hit_point,
+                };
+                // no filtering, small patterns far away alias
+                procedural.root.eval(p, uv, &image)
+            }
         };
+        let value = |map: &ParamMap| map.channel.of(sample(&map.texture));
 
-        (diff, emissive)
+        let mut surface = SurfaceParams::constant(
+            material,
+            sample(&material.texture),
+            sample(&material.emissive),
+        );
+
+        let maps = &material.maps;
+        if let Some(texture) = &maps.specular {
+            surface.specular = surface.specular * sample(texture);
+        }
+        if let Some(texture) = &maps.transmission {
+            surface.transmission = surface.transmission * sample(texture);
+        }
+        if let Some(map) = &maps.roughness {
+            surface.roughness *= value(map);
+        }
+        if let Some(map) = &maps.reflectivity {
+            surface.reflectivity *= value(map);
+        }
+        if let Some(map) = &maps.opacity {
+            surface.transparency = 1.0 - (1.0 - surface.transparency) * value(map);
+        }
+        if let Some(map) = &maps.ior {
+            surface.ior_scale = value(map);
+        }
+        let metallic = maps
+            .metallic
+            .as_ref()
+            .map_or(material.metallic, |map| material.metallic * value(map));
+
+        surface.with_metallic(metallic)
     }
+}
 
-    fn sample_texture(uv: Vec2, texture: &Rgba32FImage) -> Rgba {
-        image::imageops::sample_bilinear(texture, uv.x, uv.y)
-            .expect("UV is in bounds")
-            .into()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::LightType;
+
+    #[test]
+    fn another_sky_replaces_the_sun() {
+        let mut scene = Scene::new();
+        scene.lights.push(Light {
+            light_type: LightType::Point(Vec3::ZERO),
+            color: Rgba::WHITE,
+        });
+        scene.set_sky(Sky::new(0.3, 0.0, 3.0));
+        scene.set_sky(Sky::new(1.0, 0.0, 3.0));
+
+        assert_eq!(scene.lights.len(), 2);
+        let LightType::Directional(dir) = scene.lights[1].light_type else {
+            panic!("The sun is a directional light");
+        };
+        assert!((-dir.y - 1.0_f32.sin()).abs() < 1e-5);
     }
 }
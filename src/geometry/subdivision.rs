@@ -0,0 +1,310 @@
+use super::{ImageTexture, MeshGeometry, TextureFilter};
+use fxhash::FxHashMap;
+use glam::{Vec2, Vec3};
+
+// vertices are split wherever the uvs are (seams, glTF), smoothing has to see through that
+// or the mesh tears apart along the seams. every vertex is mapped to the first one with its position
+fn weld(mesh: &MeshGeometry) -> (Vec<u32>, Vec<Vec3>) {
+    let mut first: FxHashMap<[u32; 3], u32> = FxHashMap::default();
+    let mut positions = Vec::new();
+    let canonical = mesh
+        .verts
+        .iter()
+        .map(|&(x, y, z)| {
+            *first
+                .entry([x.to_bits(), y.to_bits(), z.to_bits()])
+                .or_insert_with(|| {
+                    positions.push(Vec3::new(x, y, z));
+                    positions.len() as u32 - 1
+                })
+        })
+        .collect();
+
+    (canonical, positions)
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+impl MeshGeometry {
+    /// Loop subdivision, every level splits each triangle in 4 and smooths the surface.
+    /// Boundary edges stay sharp, uvs are interpolated linearly
+    pub fn subdivide(&self, levels: u32) -> MeshGeometry {
+        let mut mesh = self.clone();
+        for _ in 0..levels {
+            mesh = mesh.subdivide_once();
+        }
+        mesh
+    }
+
+    fn subdivide_once(&self) -> MeshGeometry {
+        let (canonical, positions) = weld(self);
+        let c = |i: u32| canonical[i as usize];
+
+        // vertices opposite to every edge, one per triangle using it
+        let mut opposite: FxHashMap<(u32, u32), Vec<u32>> = FxHashMap::default();
+        for &(a, b, d) in &self.indices {
+            let (a, b, d) = (c(a), c(b), c(d));
+            for (e0, e1, o) in [(a, b, d), (b, d, a), (d, a, b)] {
+                opposite.entry(edge_key(e0, e1)).or_default().push(o);
+            }
+        }
+
+        // neighbours of every vertex, and the ones across boundary edges
+        let mut neighbours: Vec<Vec<u32>> = vec![Vec::new(); positions.len()];
+        let mut boundary: Vec<Vec<u32>> = vec![Vec::new(); positions.len()];
+        for (&(a, b), faces) in &opposite {
+            neighbours[a as usize].push(b);
+            neighbours[b as usize].push(a);
+            if faces.len() != 2 {
+                boundary[a as usize].push(b);
+                boundary[b as usize].push(a);
+            }
+        }
+
+        let moved: Vec<Vec3> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| {
+                if !boundary[i].is_empty() {
+                    // only a simple boundary curve can be smoothed, corners stay where they are
+                    return match boundary[i].as_slice() {
+                        [a, b] => {
+                            p * 0.75 + (positions[*a as usize] + positions[*b as usize]) * 0.125
+                        }
+                        _ => p,
+                    };
+                }
+
+                let n = neighbours[i].len();
+                if n == 0 {
+                    return p;
+                }
+                let beta = if n == 3 {
+                    3.0 / 16.0
+                } else {
+                    3.0 / (8.0 * n as f32)
+                };
+                let sum: Vec3 = neighbours[i].iter().map(|j| positions[*j as usize]).sum();
+                p * (1.0 - n as f32 * beta) + sum * beta
+            })
+            .collect();
+
+        let mut out = MeshGeometry {
+            verts: canonical
+                .iter()
+                .map(|i| moved[*i as usize].into())
+                .collect(),
+            indices: Vec::with_capacity(self.indices.len() * 4),
+            tex_coords: self.tex_coords.clone(),
+            extra_tex_coords: self.extra_tex_coords.clone(),
+            tex_coord_set: self.tex_coord_set,
+        };
+
+        // new vertices are shared by the triangles on both sides, unless the edge is on a uv seam
+        let mut edge_points: FxHashMap<(u32, u32), u32> = FxHashMap::default();
+        let mut edge_point = |a: u32, b: u32, out: &mut MeshGeometry| -> u32 {
+            *edge_points.entry(edge_key(a, b)).or_insert_with(|| {
+                let (ca, cb) = (c(a), c(b));
+                let (pa, pb) = (positions[ca as usize], positions[cb as usize]);
+                let pos = match opposite[&edge_key(ca, cb)].as_slice() {
+                    [o0, o1] => {
+                        (pa + pb) * 0.375
+                            + (positions[*o0 as usize] + positions[*o1 as usize]) * 0.125
+                    }
+                    _ => (pa + pb) * 0.5,
+                };
+
+                out.verts.push(pos.into());
+                let midpoint = |uvs: &[Vec2]| (uvs[a as usize] + uvs[b as usize]) * 0.5;
+                out.tex_coords.push(midpoint(&self.tex_coords));
+                for (set, uvs) in out.extra_tex_coords.iter_mut().zip(&self.extra_tex_coords) {
+                    set.push(midpoint(uvs));
+                }
+                out.verts.len() as u32 - 1
+            })
+        };
+
+        for &(a, b, d) in &self.indices {
+            let ab = edge_point(a, b, &mut out);
+            let bd = edge_point(b, d, &mut out);
+            let da = edge_point(d, a, &mut out);
+            out.indices
+                .extend([(a, ab, da), (ab, b, bd), (da, bd, d), (ab, bd, da)]);
+        }
+
+        out
+    }
+
+    /// Smooth normal of every vertex, the same for all the copies of a vertex on a uv seam
+    pub fn vertex_normals(&self) -> Vec<Vec3> {
+        let (canonical, positions) = weld(self);
+
+        // the cross product is weighted by the area of the triangle
+        let mut normals = vec![Vec3::ZERO; positions.len()];
+        for &(a, b, d) in &self.indices {
+            let [a, b, d] = [a, b, d].map(|i| canonical[i as usize] as usize);
+            let normal = (positions[b] - positions[a]).cross(positions[d] - positions[a]);
+            for i in [a, b, d] {
+                normals[i] += normal;
+            }
+        }
+
+        canonical
+            .iter()
+            .map(|i| normals[*i as usize].normalize_or_zero())
+            .collect()
+    }
+
+    /// Moves every vertex along its normal by the brightness of the heightmap times scale.
+    /// The heightmap is read like any texture on the mesh, with its wrap modes and uv transform.
+    /// The mesh needs enough vertices to show the detail, subdivide it first
+    pub fn displace(&mut self, heightmap: &ImageTexture, scale: f32) {
+        let normals = self.vertex_normals();
+        let (canonical, positions) = weld(self);
+
+        // copies of a vertex on a seam can read different heights, they are averaged so that no cracks open
+        let mut heights = vec![(0.0, 0); positions.len()];
+        for (i, uv) in self.active_tex_coords().iter().enumerate() {
+            let [r, g, b, _] = heightmap
+                .sample(*uv, None, TextureFilter::Bilinear)
+                .to_array();
+            let height = (r + g + b) / 3.0;
+
+            let entry = &mut heights[canonical[i] as usize];
+            entry.0 += height;
+            entry.1 += 1;
+        }
+
+        for (i, vert) in self.verts.iter_mut().enumerate() {
+            let (sum, count) = heights[canonical[i] as usize];
+            let offset = normals[i] * scale * sum / count as f32;
+            *vert = (Vec3::from(*vert) + offset).into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{MipMap, Sampler, TextureImage};
+    use image::Rgba32FImage;
+    use std::sync::Arc;
+
+    fn mesh(verts: &[[f32; 3]], indices: &[(u32, u32, u32)]) -> MeshGeometry {
+        MeshGeometry {
+            verts: verts.iter().map(|&[x, y, z]| (x, y, z)).collect(),
+            indices: indices.to_vec(),
+            tex_coords: vec![Vec2::ZERO; verts.len()],
+            extra_tex_coords: Vec::new(),
+            tex_coord_set: 0,
+        }
+    }
+
+    fn tetrahedron() -> MeshGeometry {
+        mesh(
+            &[
+                [1.0, 1.0, 1.0],
+                [1.0, -1.0, -1.0],
+                [-1.0, 1.0, -1.0],
+                [-1.0, -1.0, 1.0],
+            ],
+            &[(0, 1, 2), (0, 3, 1), (0, 2, 3), (1, 3, 2)],
+        )
+    }
+
+    #[test]
+    fn every_level_splits_triangles_in_4() {
+        let triangle = mesh(&[[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], &[(0, 1, 2)]);
+        let once = triangle.subdivide(1);
+        assert_eq!((once.verts.len(), once.indices.len()), (6, 4));
+        let twice = triangle.subdivide(2);
+        assert_eq!((twice.verts.len(), twice.indices.len()), (15, 16));
+        assert_eq!(twice.tex_coords.len(), 15);
+
+        // closed: V - E + F = 2 stays true
+        let tetrahedron = tetrahedron().subdivide(1);
+        assert_eq!(
+            (tetrahedron.verts.len(), tetrahedron.indices.len()),
+            (10, 16)
+        );
+    }
+
+    #[test]
+    fn closed_meshes_shrink() {
+        // every vertex has 3 neighbours whose sum is -p, so it moves to p / 4
+        let smooth = tetrahedron().subdivide(1);
+        assert_eq!(smooth.verts[0], (0.25, 0.25, 0.25));
+    }
+
+    #[test]
+    fn uv_seams_do_not_tear() {
+        // a square with the diagonal split in two, every triangle has its own vertices
+        let mut square = mesh(
+            &[
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 0.0, 1.0],
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0],
+            ],
+            &[(0, 2, 1), (3, 5, 4)],
+        );
+        square.tex_coords[3] = Vec2::ONE;
+        square.tex_coords[4] = Vec2::ONE;
+
+        let smooth = square.subdivide(1);
+        assert_eq!(smooth.verts.len(), 12);
+        // the diagonal is shared, so its new vertex is smoothed from both sides
+        let diagonal: Vec<_> = smooth.verts[6..]
+            .iter()
+            .filter(|(x, _, z)| (x - z).abs() < 1e-6)
+            .collect();
+        assert_eq!(diagonal.len(), 2);
+        assert_eq!(diagonal[0], diagonal[1]);
+    }
+
+    #[test]
+    fn displace_along_the_normals() {
+        let mut quad = mesh(
+            &[
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0],
+            ],
+            &[(0, 2, 1), (0, 3, 2)],
+        );
+        assert!(quad.vertex_normals().iter().all(|n| *n == Vec3::Y));
+
+        let white = Rgba32FImage::from_pixel(2, 2, image::Rgba([1.0; 4]));
+        let white = ImageTexture::new(
+            Arc::new(TextureImage::new(MipMap::new(white))),
+            Sampler::REPEAT,
+        );
+        quad.displace(&white, 2.0);
+        assert!(quad.verts.iter().all(|(_, y, _)| *y == 2.0));
+    }
+
+    #[test]
+    fn displace_with_the_sampler() {
+        // black on the left, white on the right
+        let image = Rgba32FImage::from_fn(2, 1, |x, _| image::Rgba([x as f32; 4]));
+        let image = Arc::new(TextureImage::new(MipMap::new(image)));
+        let point = |sampler: Sampler| {
+            let mut quad = mesh(
+                &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 1.0]],
+                &[(0, 2, 1)],
+            );
+            // the middle of the black texel one tile over
+            quad.tex_coords = vec![Vec2::new(1.25, 0.5); 3];
+            quad.displace(&ImageTexture::new(image.clone(), sampler), 1.0);
+            quad.verts[0].1
+        };
+
+        assert_eq!(point(Sampler::REPEAT), 0.0);
+        assert_eq!(point(Sampler::CLAMP), 1.0);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::color::Rgba;
+use crate::medium::Medium;
+use crate::renderer::{channel_average, channel_max};
+use anyhow::{Context, Result, bail};
+use glam::{UVec3, Vec3};
+use std::path::Path;
+
+/// Densities sampled at the centers of a regular grid of voxels, x changes fastest, then y, then z
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    pub size: UVec3,
+    pub density: Vec<f32>,
+    max_density: f32,
+}
+
+impl VoxelGrid {
+    pub fn new(size: UVec3, density: Vec<f32>) -> Result<Self> {
+        if size.min_element() == 0 {
+            bail!("Voxel grid of size {size} has no voxels");
+        }
+        let expected = voxel_count(size)?;
+        if density.len() != expected {
+            bail!(
+                "Voxel grid of size {size} needs {expected} values, got {}",
+                density.len()
+            );
+        }
+        let max_density = density.iter().copied().fold(0.0, f32::max);
+
+        Ok(Self {
+            size,
+            density,
+            max_density,
+        })
+    }
+
+    /// f receives the center of each voxel, in [0, 1]^3
+    pub fn from_fn(size: UVec3, f: impl Fn(Vec3) -> f32) -> Result<Self> {
+        let mut density = Vec::with_capacity(voxel_count(size)?);
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let p = (Vec3::new(x as f32, y as f32, z as f32) + 0.5) / size.as_vec3();
+                    density.push(f(p).max(0.0));
+                }
+            }
+        }
+
+        Self::new(size, density)
+    }
+
+    /// Raw little endian grid: nx, ny, nz as u32 followed by nx * ny * nz f32 densities
+    pub fn load_raw(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Error reading voxel grid {}", path.display()))?;
+        Self::from_raw(&bytes).with_context(|| format!("Invalid voxel grid {}", path.display()))
+    }
+
+    fn from_raw(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 12 {
+            bail!("The header is missing");
+        }
+        let read_u32 = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let size = UVec3::new(read_u32(0), read_u32(4), read_u32(8));
+
+        let density: Vec<f32> = bytes[12..]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Self::new(size, density)
+    }
+
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    #[inline]
+    fn voxel(&self, x: u32, y: u32, z: u32) -> f32 {
+        let (size_x, size_y) = (self.size.x as usize, self.size.y as usize);
+        self.density[x as usize + size_x * (y as usize + size_y * z as usize)]
+    }
+
+    /// trilinear interpolation, p in [0, 1]^3
+    pub fn sample(&self, p: Vec3) -> f32 {
+        let max = (self.size - 1).as_vec3();
+        let p = (p * self.size.as_vec3() - 0.5).clamp(Vec3::ZERO, max);
+        let p0 = p.floor().as_uvec3();
+        let p1 = (p0 + 1).min(self.size - 1);
+        let t = p - p0.as_vec3();
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let c00 = lerp(
+            self.voxel(p0.x, p0.y, p0.z),
+            self.voxel(p1.x, p0.y, p0.z),
+            t.x,
+        );
+        let c10 = lerp(
+            self.voxel(p0.x, p1.y, p0.z),
+            self.voxel(p1.x, p1.y, p0.z),
+            t.x,
+        );
+        let c01 = lerp(
+            self.voxel(p0.x, p0.y, p1.z),
+            self.voxel(p1.x, p0.y, p1.z),
+            t.x,
+        );
+        let c11 = lerp(
+            self.voxel(p0.x, p1.y, p1.z),
+            self.voxel(p1.x, p1.y, p1.z),
+            t.x,
+        );
+
+        lerp(lerp(c00, c10, t.y), lerp(c01, c11, t.y), t.z)
+    }
+}
+
+/// Heterogeneous medium (smoke, clouds) inside an axis aligned box.
+/// The coefficients of `medium` are the ones at density 1.0.
+/// Not traced by the raytracer backend, volumes should not overlap each other
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub grid: VoxelGrid,
+    pub min: Vec3,
+    pub max: Vec3,
+    pub medium: Medium,
+}
+
+impl Volume {
+    pub fn new(grid: VoxelGrid, min: Vec3, max: Vec3, medium: Medium) -> Self {
+        Self {
+            grid,
+            min,
+            max,
+            medium,
+        }
+    }
+
+    /// density at a point in world space, which must be inside the box
+    #[inline]
+    pub fn density(&self, pos: Vec3) -> f32 {
+        self.grid.sample((pos - self.min) / (self.max - self.min))
+    }
+
+    /// slab test, returns the part of [0, max_distance] that is inside the box
+    pub fn intersect(&self, origin: Vec3, dir: Vec3, max_distance: f32) -> Option<(f32, f32)> {
+        let inv_dir = dir.recip();
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+
+        let near = t0.min(t1).max_element().max(0.0);
+        let far = t0.max(t1).min_element().min(max_distance);
+
+        (near < far).then_some((near, far))
+    }
+
+    /// Delta tracking along the part `range` of the ray inside the box, see `intersect`.
+    /// Spectral tracking (Kutz et al. 2017), so that coloured media do not need one path per channel.
+    /// The coefficients are the ones of the medium in the channels the path carries, `weight` is
+    /// what the path carried so far and comes back multiplied by the null collisions on the way
+    pub fn delta_tracking(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        range: (f32, f32),
+        (sigma_a, sigma_s): (Rgba, Rgba),
+        num_channels: usize,
+        mut weight: Rgba,
+    ) -> VolumeEvent {
+        let sigma_t = sigma_a + sigma_s;
+        let majorant = self.grid.max_density() * channel_max(sigma_t, num_channels);
+        if majorant <= 0.0 {
+            return VolumeEvent::PassThrough(weight);
+        }
+
+        let (mut t, t1) = range;
+        loop {
+            t -= (1.0 - fastrand::f32()).ln() / majorant;
+            if t >= t1 {
+                return VolumeEvent::PassThrough(weight);
+            }
+
+            let pos = origin + dir * t;
+            let density = self.density(pos);
+
+            let absorb = sigma_a * density;
+            let scatter = sigma_s * density;
+            let null = (sigma_t * density).map(|s| majorant - s);
+
+            // probabilities are the average over the channels, they always add up to the majorant
+            let p_absorb = channel_average(absorb, num_channels);
+            let p_scatter = channel_average(scatter, num_channels);
+            let p_null = channel_average(null, num_channels);
+
+            let event = fastrand::f32() * (p_absorb + p_scatter + p_null);
+            if event < p_absorb {
+                return VolumeEvent::Absorbed;
+            } else if event < p_absorb + p_scatter {
+                return VolumeEvent::Scatter {
+                    pos,
+                    weight: weight * scatter / p_scatter,
+                    medium: self.medium,
+                };
+            } else if p_null > 0.0 {
+                weight = weight * null / p_null;
+            }
+        }
+    }
+
+    /// Ratio tracking along the part `range` of the ray inside the box, an unbiased estimate of the transmittance
+    pub fn ratio_tracking(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        range: (f32, f32),
+        sigma_t: Rgba,
+        num_channels: usize,
+    ) -> Rgba {
+        let mut tr = Rgba::WHITE;
+        let majorant = self.grid.max_density() * channel_max(sigma_t, num_channels);
+        if majorant <= 0.0 {
+            return tr;
+        }
+
+        let (mut t, t1) = range;
+        loop {
+            t -= (1.0 - fastrand::f32()).ln() / majorant;
+            if t >= t1 {
+                return tr;
+            }
+            let density = self.density(origin + dir * t);
+            tr = tr * (sigma_t * density).map(|s| 1.0 - s / majorant);
+        }
+    }
+}
+
+/// What happens to a ray going through the volumes
+pub enum VolumeEvent {
+    Absorbed,
+    Scatter {
+        pos: Vec3,
+        weight: Rgba,
+        medium: Medium,
+    },
+    PassThrough(Rgba),
+}
+
+// the number of voxels in a grid of this size, if it fits in memory at all
+fn voxel_count(size: UVec3) -> Result<usize> {
+    (size.x as usize)
+        .checked_mul(size.y as usize)
+        .and_then(|n| n.checked_mul(size.z as usize))
+        .with_context(|| format!("Voxel grid of size {size} is too big"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(size: [u32; 3], density: &[f32]) -> Vec<u8> {
+        let header = size.iter().flat_map(|n| n.to_le_bytes());
+        header
+            .chain(density.iter().flat_map(|d| d.to_le_bytes()))
+            .collect()
+    }
+
+    fn constant_volume(density: f32) -> Volume {
+        let grid = VoxelGrid::from_fn(UVec3::splat(4), |_| density).unwrap();
+        Volume::new(grid, Vec3::ZERO, Vec3::ONE, Medium::fog(1.0, 0.0))
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        assert!(VoxelGrid::new(UVec3::new(2, 0, 2), Vec::new()).is_err());
+        assert!(VoxelGrid::new(UVec3::splat(2), vec![1.0; 7]).is_err());
+        assert!(VoxelGrid::from_raw(&raw([u32::MAX; 3], &[1.0])).is_err());
+        assert!(VoxelGrid::from_raw(&[0; 8]).is_err());
+
+        let grid = VoxelGrid::from_raw(&raw([2, 1, 1], &[0.25, 0.5])).unwrap();
+        assert_eq!(grid.size, UVec3::new(2, 1, 1));
+        assert_eq!(grid.max_density(), 0.5);
+    }
+
+    #[test]
+    fn trilinear_sampling() {
+        let grid = VoxelGrid::from_fn(UVec3::new(2, 2, 2), |p| p.x * 4.0 + p.z * 2.0).unwrap();
+        // at voxel centers the stored value comes back
+        assert!((grid.sample(Vec3::splat(0.25)) - 1.5).abs() < 1e-5);
+        assert!((grid.sample(Vec3::new(0.75, 0.25, 0.75)) - 4.5).abs() < 1e-5);
+        // halfway between centers, and clamped outside of them
+        assert!((grid.sample(Vec3::new(0.5, 0.25, 0.25)) - 2.5).abs() < 1e-5);
+        assert!((grid.sample(Vec3::splat(0.5)) - 3.0).abs() < 1e-5);
+        assert!((grid.sample(Vec3::ZERO) - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn slab_intersection() {
+        let volume = constant_volume(1.0);
+        let origin = Vec3::new(-1.0, 0.5, 0.5);
+        let (near, far) = volume.intersect(origin, Vec3::X, 10.0).unwrap();
+        assert!((near - 1.0).abs() < 1e-5 && (far - 2.0).abs() < 1e-5);
+        assert_eq!(volume.intersect(origin, Vec3::X, 1.5), Some((1.0, 1.5)));
+        assert!(volume.intersect(origin, Vec3::Y, 10.0).is_none());
+        assert!(volume.intersect(origin, -Vec3::X, 10.0).is_none());
+    }
+
+    #[test]
+    fn tracking_matches_beer_lambert() {
+        fastrand::seed(7);
+        let volume = constant_volume(0.5);
+        let sigma_t = Rgba::rgb(1.0, 2.0, 0.0);
+        let (origin, range) = (Vec3::new(0.5, 0.5, 0.0), (0.0, 1.0));
+        let expected = [(-0.5f32).exp(), (-1.0f32).exp(), 1.0];
+
+        let n = 20000;
+        let mut ratio = [0.0; 3];
+        for _ in 0..n {
+            let tr = volume.ratio_tracking(origin, Vec3::Z, range, sigma_t, 3);
+            for (sum, c) in ratio.iter_mut().zip(tr.to_array()) {
+                *sum += c / n as f32;
+            }
+        }
+        for (ratio, expected) in ratio.into_iter().zip(expected) {
+            assert!((ratio - expected).abs() < 0.02, "{ratio} vs {expected}");
+        }
+
+        // purely absorbing, so paths that get through carry the transmittance
+        let mut delta = [0.0; 3];
+        for _ in 0..n {
+            let coefficients = (sigma_t, Rgba::BLACK);
+            let event = volume.delta_tracking(origin, Vec3::Z, range, coefficients, 3, Rgba::WHITE);
+            match event {
+                VolumeEvent::PassThrough(weight) => {
+                    for (sum, c) in delta.iter_mut().zip(weight.to_array()) {
+                        *sum += c / n as f32;
+                    }
+                }
+                VolumeEvent::Absorbed => {}
+                VolumeEvent::Scatter { .. } => panic!("the volume does not scatter"),
+            }
+        }
+        for (delta, expected) in delta.into_iter().zip(expected) {
+            assert!((delta - expected).abs() < 0.03, "{delta} vs {expected}");
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::color::Rgba;
+use glam::Vec3;
+use std::f32::consts::{PI, TAU};
+
+// lobes: R (reflection), TT (through the fiber), TRT (back out the same side), and the rest summed up
+const P_MAX: usize = 3;
+const SQRT_PI_OVER_8: f32 = 0.626_657_07;
+
+/// Parameters of the Chiang et al. 2016 hair scattering model, as in pbrt.
+/// Works best on round curves, flat ones are treated as a fiber seen from a random side
+#[derive(Debug, Clone, Copy)]
+pub struct Hair {
+    /// absorption inside the fiber, per channel
+    pub sigma_a: Rgba,
+    /// longitudinal roughness, how much highlights spread along the hair
+    pub beta_m: f32,
+    /// azimuthal roughness, how much light spreads around the hair
+    pub beta_n: f32,
+    /// tilt of the cuticle scales in radians, shifts the highlights (about 2 degrees for human hair)
+    pub alpha: f32,
+    pub eta: f32,
+}
+
+impl Hair {
+    pub const fn new(sigma_a: Rgba) -> Self {
+        Self {
+            sigma_a,
+            beta_m: 0.3,
+            beta_n: 0.3,
+            alpha: 0.035,
+            eta: 1.55,
+        }
+    }
+
+    /// human hair from the concentration of its pigments, 0 is blonde, 8 is black, pheomelanin makes it red
+    pub fn from_melanin(eumelanin: f32, pheomelanin: f32) -> Self {
+        let eu = Rgba::rgb(0.419, 0.697, 1.37) * eumelanin;
+        let pheo = Rgba::rgb(0.187, 0.4, 1.05) * pheomelanin;
+        Self::new(eu + pheo)
+    }
+
+    /// absorption that gives roughly this color after many bounces, for fur and non human hair
+    pub fn from_color(color: Rgba, beta_n: f32) -> Self {
+        let b = beta_n;
+        let denom = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3)
+            + 5.574 * b.powi(4)
+            + 0.245 * b.powi(5);
+        Self {
+            beta_n,
+            ..Self::new(color.map(|c| (c.max(1e-4).ln() / denom).powi(2)))
+        }
+    }
+
+    pub fn with_roughness(mut self, beta_m: f32, beta_n: f32) -> Self {
+        self.beta_m = beta_m;
+        self.beta_n = beta_n;
+        self
+    }
+
+    /// `h` is where the fiber was hit, -1 to 1 across its width.
+    /// sigma_a is passed in already converted to what the path carries
+    pub fn bsdf(&self, h: f32, sigma_a: Rgba) -> HairBsdf {
+        let beta_m = self.beta_m;
+        let beta_n = self.beta_n;
+
+        // longitudinal variance of every lobe
+        let v0 = (0.726 * beta_m + 0.812 * beta_m.powi(2) + 3.7 * beta_m.powi(20)).powi(2);
+        let v = [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0];
+
+        // azimuthal logistic scale
+        let s =
+            SQRT_PI_OVER_8 * (0.265 * beta_n + 1.194 * beta_n.powi(2) + 5.372 * beta_n.powi(22));
+
+        // sin and cos of 2^k alpha, for the tilt of every lobe
+        let mut sin_2k_alpha = [0.0; 3];
+        let mut cos_2k_alpha = [0.0; 3];
+        sin_2k_alpha[0] = self.alpha.sin();
+        cos_2k_alpha[0] = (1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]).max(0.0).sqrt();
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1] * cos_2k_alpha[i - 1]
+                - sin_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+        }
+
+        let h = h.clamp(-1.0, 1.0);
+        HairBsdf {
+            h,
+            gamma_o: h.asin(),
+            eta: self.eta,
+            sigma_a,
+            v,
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha,
+        }
+    }
+}
+
+/// The hair model at one hit point.
+/// Directions are in the frame of `HairFrame`: x along the hair, y across it, z towards the viewer
+pub struct HairBsdf {
+    h: f32,
+    gamma_o: f32,
+    eta: f32,
+    sigma_a: Rgba,
+    v: [f32; P_MAX + 1],
+    s: f32,
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3],
+}
+
+impl HairBsdf {
+    /// already multiplied by the cosine, so lights only need to multiply it by their color
+    pub fn eval(&self, wo: Vec3, wi: Vec3) -> Rgba {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = angles(wi);
+
+        let (gamma_t, transmittance) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap = self.ap(cos_theta_o, transmittance);
+        let phi = phi_i - phi_o;
+
+        let mut sum = Rgba::NONE;
+        for (p, ap) in ap.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilt(p, sin_theta_o, cos_theta_o);
+            sum +=
+                *ap * mp(
+                    cos_theta_i,
+                    cos_theta_op,
+                    sin_theta_i,
+                    sin_theta_op,
+                    self.v[p],
+                ) * np(phi, p, self.s, self.gamma_o, gamma_t);
+        }
+        // everything after TRT has no azimuthal structure left
+        sum += ap[P_MAX]
+            * mp(
+                cos_theta_i,
+                cos_theta_o,
+                sin_theta_i,
+                sin_theta_o,
+                self.v[P_MAX],
+            )
+            / TAU;
+
+        sum
+    }
+
+    /// new direction and its weight (eval / pdf).
+    /// the lobe is picked by how much light it carries, averaged over the first num_channels
+    pub fn sample(&self, wo: Vec3, num_channels: usize) -> (Vec3, Rgba) {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let (gamma_t, transmittance) = self.refracted(sin_theta_o, cos_theta_o);
+        let ap_pdf = self.ap_pdf(cos_theta_o, transmittance, num_channels);
+
+        // pick a lobe
+        let mut u0 = fastrand::f32();
+        let mut p = 0;
+        while p < P_MAX && u0 >= ap_pdf[p] {
+            u0 -= ap_pdf[p];
+            p += 1;
+        }
+
+        let (sin_theta_op, cos_theta_op) = if p < P_MAX {
+            self.tilt(p, sin_theta_o, cos_theta_o)
+        } else {
+            (sin_theta_o, cos_theta_o)
+        };
+
+        // longitudinal angle
+        let u1 = fastrand::f32().max(1e-5);
+        let v = self.v[p];
+        let cos_theta = 1.0 + v * (u1 + (1.0 - u1) * (-2.0 / v).exp()).ln();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let cos_phi = (TAU * fastrand::f32()).cos();
+        let sin_theta_i = -cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op;
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).max(0.0).sqrt();
+
+        // azimuthal angle
+        let u2 = fastrand::f32();
+        let dphi = if p < P_MAX {
+            phi_fn(p, self.gamma_o, gamma_t) + sample_trimmed_logistic(u2, self.s, -PI, PI)
+        } else {
+            TAU * u2
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vec3::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        );
+
+        let pdf = self.pdf(wo, wi, gamma_t, &ap_pdf);
+        if pdf <= 0.0 {
+            return (wi, Rgba::NONE);
+        }
+        (wi, self.eval(wo, wi) / pdf)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, gamma_t: f32, ap_pdf: &[f32; P_MAX + 1]) -> f32 {
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let (sin_theta_i, cos_theta_i, phi_i) = angles(wi);
+        let phi = phi_i - phi_o;
+
+        let mut pdf = 0.0;
+        for (p, ap) in ap_pdf.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilt(p, sin_theta_o, cos_theta_o);
+            pdf +=
+                ap * mp(
+                    cos_theta_i,
+                    cos_theta_op,
+                    sin_theta_i,
+                    sin_theta_op,
+                    self.v[p],
+                ) * np(phi, p, self.s, self.gamma_o, gamma_t);
+        }
+        pdf += ap_pdf[P_MAX]
+            * mp(
+                cos_theta_i,
+                cos_theta_o,
+                sin_theta_i,
+                sin_theta_o,
+                self.v[P_MAX],
+            )
+            / TAU;
+
+        pdf
+    }
+
+    // angle of the ray inside the fiber and how much of the light survives crossing it once
+    fn refracted(&self, sin_theta_o: f32, cos_theta_o: f32) -> (f32, Rgba) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).max(0.0).sqrt();
+
+        // modified index of refraction, for the projection perpendicular to the hair
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = (self.h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = (1.0 - sin_gamma_t * sin_gamma_t).max(0.0).sqrt();
+
+        let distance = 2.0 * cos_gamma_t / cos_theta_t;
+        (
+            sin_gamma_t.asin(),
+            self.sigma_a.map(|s| (-s * distance).exp()),
+        )
+    }
+
+    // attenuation of every lobe
+    fn ap(&self, cos_theta_o: f32, transmittance: Rgba) -> [Rgba; P_MAX + 1] {
+        let cos_gamma_o = (1.0 - self.h * self.h).max(0.0).sqrt();
+        let f = fresnel_dielectric(cos_theta_o * cos_gamma_o, self.eta);
+
+        let mut ap = [Rgba::NONE; P_MAX + 1];
+        ap[0] = Rgba::new(f, f, f, f);
+        ap[1] = transmittance * (1.0 - f) * (1.0 - f);
+        for p in 2..P_MAX {
+            ap[p] = ap[p - 1] * transmittance * f;
+        }
+        // geometric series of all the remaining bounces
+        ap[P_MAX] = ap[P_MAX - 1] * transmittance * f / transmittance.map(|t| 1.0 - t * f);
+        ap
+    }
+
+    fn ap_pdf(
+        &self,
+        cos_theta_o: f32,
+        transmittance: Rgba,
+        num_channels: usize,
+    ) -> [f32; P_MAX + 1] {
+        let ap = self
+            .ap(cos_theta_o, transmittance)
+            .map(|a| a.to_array()[..num_channels].iter().sum::<f32>() / num_channels as f32);
+        let total: f32 = ap.iter().sum();
+        if total <= 0.0 {
+            return [1.0 / (P_MAX + 1) as f32; P_MAX + 1];
+        }
+        ap.map(|a| a / total)
+    }
+
+    // the scales on the surface tilt every lobe by a multiple of alpha
+    fn tilt(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin, cos) = match p {
+            0 => (-self.sin_2k_alpha[1], self.cos_2k_alpha[1]),
+            1 => (self.sin_2k_alpha[0], self.cos_2k_alpha[0]),
+            _ => (self.sin_2k_alpha[2], self.cos_2k_alpha[2]),
+        };
+        (
+            sin_theta_o * cos + cos_theta_o * sin,
+            (cos_theta_o * cos - sin_theta_o * sin).abs(),
+        )
+    }
+}
+
+/// Local frame of a hair hit, x along the hair and z towards the viewer (perpendicular to the hair)
+pub struct HairFrame {
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl HairFrame {
+    pub fn new(tangent: Vec3, wo: Vec3) -> Self {
+        let x = tangent.normalize();
+        let mut z = wo - x * wo.dot(x);
+        if z.length_squared() < 1e-8 {
+            // looking straight down the hair
+            z = x.any_orthonormal_vector();
+        }
+        let z = z.normalize();
+        Self {
+            x,
+            y: z.cross(x),
+            z,
+        }
+    }
+
+    /// where a round fiber was hit, from its surface normal
+    pub fn h_from_normal(&self, normal: Vec3) -> f32 {
+        let across = normal - self.x * normal.dot(self.x);
+        across.normalize_or_zero().dot(self.y)
+    }
+
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(v.dot(self.x), v.dot(self.y), v.dot(self.z))
+    }
+
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+}
+
+// (sin theta, cos theta, phi): theta is the angle with the normal plane of the hair, phi goes around it
+fn angles(w: Vec3) -> (f32, f32, f32) {
+    let sin_theta = w.x.clamp(-1.0, 1.0);
+    let cos_theta = (1.0 - sin_theta * sin_theta).max(0.0).sqrt();
+    (sin_theta, cos_theta, w.z.atan2(w.y))
+}
+
+// longitudinal scattering
+fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        // the direct formula overflows for low roughness
+        (log_i0(a) - b - 1.0 / v + std::f32::consts::LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        ((-b).exp() * i0(a)) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+// azimuthal scattering
+fn np(phi: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+    let mut dphi = phi - phi_fn(p, gamma_o, gamma_t);
+    // back into [-pi, pi]
+    dphi = (dphi + PI).rem_euclid(TAU) - PI;
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+fn phi_fn(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    let p = p as f32;
+    2.0 * p * gamma_t - 2.0 * gamma_o + p * PI
+}
+
+// modified bessel function of the first kind
+fn i0(x: f32) -> f32 {
+    let mut value = 0.0;
+    let mut x2i = 1.0;
+    let mut ifact = 1.0;
+    let mut i4 = 1.0;
+    for i in 0..10 {
+        if i > 1 {
+            ifact *= i as f32;
+        }
+        value += x2i / (i4 * ifact * ifact);
+        x2i *= x * x;
+        i4 *= 4.0;
+    }
+    value
+}
+
+fn log_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-(TAU).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        i0(x).ln()
+    }
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.clamp(a, b)
+}
+
+// unpolarized fresnel reflectance, going from air into the fiber
+fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let cos_theta_i = cos_theta_i.clamp(-1.0, 1.0);
+    let sin_theta_t = (1.0 - cos_theta_i * cos_theta_i).max(0.0).sqrt() / eta;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).max(0.0).sqrt();
+
+    let parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniform_sphere() -> Vec3 {
+        let z = 1.0 - 2.0 * fastrand::f32();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = TAU * fastrand::f32();
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    #[test]
+    fn sample_weight_is_eval_over_pdf() {
+        fastrand::seed(3);
+        let hair = Hair::from_melanin(1.3, 0.2);
+        for _ in 0..200 {
+            let bsdf = hair.bsdf(fastrand::f32() * 2.0 - 1.0, hair.sigma_a);
+            let wo = uniform_sphere();
+            let (wi, weight) = bsdf.sample(wo, 3);
+
+            let (sin_theta_o, cos_theta_o, _) = angles(wo);
+            let (gamma_t, transmittance) = bsdf.refracted(sin_theta_o, cos_theta_o);
+            let ap_pdf = bsdf.ap_pdf(cos_theta_o, transmittance, 3);
+            let pdf = bsdf.pdf(wo, wi, gamma_t, &ap_pdf);
+
+            let expected = bsdf.eval(wo, wi) / pdf;
+            for (a, b) in weight.to_array().into_iter().zip(expected.to_array()) {
+                assert!((a - b).abs() <= 1e-3 * b.abs().max(1.0), "{a} vs {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn white_furnace() {
+        // without absorption every bit of light leaves the fiber somewhere, and nothing more
+        fastrand::seed(5);
+        for roughness in [0.3, 0.6, 0.9] {
+            let hair = Hair::new(Rgba::NONE).with_roughness(roughness, roughness);
+            let wo = uniform_sphere();
+            let n = 100_000;
+            let mut energy = 0.0;
+            for _ in 0..n {
+                let bsdf = hair.bsdf(fastrand::f32() * 2.0 - 1.0, hair.sigma_a);
+                let wi = uniform_sphere();
+                energy += bsdf.eval(wo, wi).to_array()[0] * 4.0 * PI / n as f32;
+            }
+            assert!(energy <= 1.02 && energy > 0.95, "{roughness}: {energy}");
+        }
+    }
+}
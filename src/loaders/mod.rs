@@ -0,0 +1,35 @@
+use crate::configs::ModelConfig;
+use crate::geometry::Scene;
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+
+pub mod obj;
+pub mod ply;
+pub mod xyz;
+
+/// Loads the file of `model` into the scene, the format is picked by extension (OBJ, PLY or XYZ)
+pub fn add_model(store: &mut Scene, model: &ModelConfig) -> Result<()> {
+    let path = Path::new(model.path);
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match (extension.as_str(), model.point_radius) {
+        ("ply" | "xyz", Some(radius)) => xyz::add_point_cloud(
+            store,
+            path,
+            radius,
+            model.point_shape,
+            model.transform,
+            &model.material,
+        ),
+        ("obj", None) => obj::add_obj(store, path, model.transform),
+        ("ply", None) => ply::add_ply(store, path, model.transform, &model.material),
+        ("xyz", None) => bail!("XYZ files only have points, they need a point radius"),
+        ("obj", Some(_)) => bail!("OBJ files are only loaded as meshes"),
+        _ => bail!("Unknown model format {extension:?}"),
+    }
+    .with_context(|| format!("Error adding model {}", path.display()))
+}
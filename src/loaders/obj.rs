@@ -0,0 +1,503 @@
+use crate::color::Rgba;
+use crate::geometry::{
+    Channel, GeomInfo, Geometry, Material, MaterialMaps, MeshGeometry, ParamMap, Scene, Texture,
+    TextureOptions,
+};
+use anyhow::{Context, Result, bail};
+use fxhash::FxHashMap;
+use glam::{Mat4, Vec2};
+use std::path::{Path, PathBuf};
+
+// vertices are shared between faces only if they have the same position and uv
+#[derive(Default)]
+struct MeshBuilder {
+    mesh: MeshGeometry,
+    vertex_map: FxHashMap<(u32, Option<u32>), u32>,
+}
+
+impl MeshBuilder {
+    fn vertex(
+        &mut self,
+        pos: u32,
+        uv: Option<u32>,
+        positions: &[(f32, f32, f32)],
+        uvs: &[Vec2],
+    ) -> u32 {
+        *self.vertex_map.entry((pos, uv)).or_insert_with(|| {
+            self.mesh.verts.push(positions[pos as usize]);
+            self.mesh
+                .tex_coords
+                .push(uv.map_or(Vec2::ZERO, |uv| uvs[uv as usize]));
+            self.mesh.verts.len() as u32 - 1
+        })
+    }
+}
+
+/// Loads a Wavefront OBJ file and the MTL files it references.
+/// Returns one mesh per material, textures are added to the scene.
+/// Faces with more than 3 vertices are triangulated as a fan, so they should be convex. Normals are ignored
+pub fn load_obj(
+    store: &mut Scene,
+    path: impl AsRef<Path>,
+) -> Result<Vec<(MeshGeometry, Material)>> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading OBJ file {}", path.display()))?;
+    parse_obj(store, &source, path)
+}
+
+// the path is for error messages and for finding the MTL files
+fn parse_obj(
+    store: &mut Scene,
+    source: &str,
+    path: &Path,
+) -> Result<Vec<(MeshGeometry, Material)>> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut positions: Vec<(f32, f32, f32)> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut materials: FxHashMap<String, Material> = FxHashMap::default();
+
+    // one mesh per material, in the order they are first used
+    let mut meshes: Vec<(Option<String>, MeshBuilder)> = Vec::new();
+    let mut current_mesh: Option<usize> = None;
+    let mut current_material: Option<String> = None;
+
+    for (line_number, line) in source.lines().enumerate() {
+        let line_number = line_number + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        let error = || format!("Error in {} at line {line_number}", path.display());
+
+        match keyword {
+            "v" => {
+                let [x, y, z] = parse_floats::<3>(rest, 3).with_context(error)?;
+                positions.push((x, y, z));
+            }
+            "vt" => {
+                let [u, v] = parse_floats::<2>(rest, 1).with_context(error)?;
+                // OBJ puts v = 0 at the bottom of the image, we put it at the top like glTF
+                uvs.push(Vec2::new(u, 1.0 - v));
+            }
+            "f" => {
+                let mesh_index = *current_mesh.get_or_insert_with(|| {
+                    meshes
+                        .iter()
+                        .position(|(name, _)| *name == current_material)
+                        .unwrap_or_else(|| {
+                            meshes.push((current_material.clone(), MeshBuilder::default()));
+                            meshes.len() - 1
+                        })
+                });
+                let builder = &mut meshes[mesh_index].1;
+
+                let mut face: Vec<u32> = Vec::new();
+                for token in rest.split_whitespace() {
+                    let mut parts = token.split('/');
+                    let pos = parts.next().unwrap_or("");
+                    let pos = resolve_index(pos, positions.len()).with_context(error)?;
+                    let uv = match parts.next() {
+                        Some(uv) if !uv.is_empty() => {
+                            Some(resolve_index(uv, uvs.len()).with_context(error)?)
+                        }
+                        _ => None,
+                    };
+                    face.push(builder.vertex(pos, uv, &positions, &uvs));
+                }
+
+                if face.len() < 3 {
+                    bail!("{}: face with less than 3 vertices", error());
+                }
+                for i in 1..face.len() - 1 {
+                    builder.mesh.indices.push((face[0], face[i], face[i + 1]));
+                }
+            }
+            "usemtl" => {
+                current_material = Some(rest.to_string());
+                current_mesh = None;
+            }
+            // one or more files
+            "mtllib" => {
+                for name in rest.split_whitespace() {
+                    let mtl_path = dir.join(name);
+                    materials.extend(load_mtl(store, &mtl_path).with_context(error)?);
+                }
+            }
+            // vertex normals, objects, groups, smoothing groups, lines...
+            _ => {}
+        }
+    }
+
+    meshes
+        .into_iter()
+        .map(|(name, builder)| {
+            let material = match name {
+                Some(name) => materials.get(&name).cloned().with_context(|| {
+                    format!("Material {name} used by {} is not defined", path.display())
+                })?,
+                None => Material::WHITE_MATERIAL,
+            };
+            Ok((builder.mesh, material))
+        })
+        .collect()
+}
+
+/// Loads an OBJ file into the scene, transformed by matrix
+pub fn add_obj(store: &mut Scene, path: impl AsRef<Path>, matrix: Mat4) -> Result<()> {
+    for (mut mesh, material) in load_obj(store, path)? {
+        mesh.transform(matrix);
+        store.add_geometry(Geometry::with_material(material, GeomInfo::Mesh(mesh)));
+    }
+
+    Ok(())
+}
+
+// indices start at 1, negative ones are relative to the end of the list so far
+fn resolve_index(token: &str, len: usize) -> Result<u32> {
+    let index: i64 = token
+        .parse()
+        .with_context(|| format!("Invalid index {token}"))?;
+    let resolved = match index {
+        0 => bail!("Index 0 is not valid, OBJ indices start at 1"),
+        i if i > 0 => i - 1,
+        i => len as i64 + i,
+    };
+
+    if resolved < 0 || resolved >= len as i64 {
+        bail!("Index {index} is out of bounds, only {len} defined so far");
+    }
+    Ok(resolved as u32)
+}
+
+// the ones after `required` default to 0.0
+fn parse_floats<const N: usize>(s: &str, required: usize) -> Result<[f32; N]> {
+    let mut values = [0.0; N];
+    let mut tokens = s.split_whitespace();
+    for (i, value) in values.iter_mut().enumerate() {
+        match tokens.next() {
+            Some(token) => {
+                *value = token
+                    .parse()
+                    .with_context(|| format!("Invalid number {token}"))?
+            }
+            None if i < required => bail!("Expected {required} numbers, got {i}"),
+            None => break,
+        }
+    }
+    Ok(values)
+}
+
+/// Materials of an MTL file, by name. Textures they use are loaded into the scene
+pub fn load_mtl(store: &mut Scene, path: impl AsRef<Path>) -> Result<FxHashMap<String, Material>> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading MTL file {}", path.display()))?;
+    parse_mtl(store, &source, path)
+}
+
+// the path is for error messages and for finding the textures
+fn parse_mtl(store: &mut Scene, source: &str, path: &Path) -> Result<FxHashMap<String, Material>> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    struct MtlMaterial {
+        name: String,
+        material: Material,
+        illum: u32,
+        diffuse_map: Option<PathBuf>,
+        emissive_map: Option<PathBuf>,
+        specular_map: Option<PathBuf>,
+        roughness_map: Option<PathBuf>,
+        metallic_map: Option<PathBuf>,
+        opacity_map: Option<PathBuf>,
+    }
+
+    let mut parsed: Vec<MtlMaterial> = Vec::new();
+
+    for (line_number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        let error = || format!("Error in {} at line {}", path.display(), line_number + 1);
+
+        if keyword == "newmtl" {
+            parsed.push(MtlMaterial {
+                name: rest.to_string(),
+                material: Material {
+                    color: Rgba::rgb(0.8, 0.8, 0.8),
+                    texture: Texture::Solid(Rgba::rgb(0.8, 0.8, 0.8)),
+                    ..Material::WHITE_MATERIAL
+                },
+                illum: 2,
+                diffuse_map: None,
+                emissive_map: None,
+                specular_map: None,
+                roughness_map: None,
+                metallic_map: None,
+                opacity_map: None,
+            });
+            continue;
+        }
+
+        let Some(current) = parsed.last_mut() else {
+            continue;
+        };
+        let material = &mut current.material;
+        let color = || -> Result<Rgba> {
+            let [r, g, b] = parse_floats::<3>(rest, 1).with_context(error)?;
+            // a single value is grey
+            Ok(if rest.split_whitespace().count() == 1 {
+                Rgba::rgb(r, r, r)
+            } else {
+                Rgba::rgb(r, g, b)
+            })
+        };
+        // options like -s or -bm come before the file name
+        let map_path = || dir.join(rest.split_whitespace().last().unwrap_or(""));
+
+        match keyword {
+            "Kd" => {
+                material.color = color()?;
+                material.texture = Texture::Solid(material.color);
+            }
+            "Ks" => material.specular = color()?,
+            "Ke" => material.emissive = Texture::Solid(color()?),
+            "Tf" => material.transmission = color()?,
+            "Ni" => material.refraction = parse_floats::<1>(rest, 1).with_context(error)?[0],
+            "d" => material.transparency = 1.0 - parse_floats::<1>(rest, 1).with_context(error)?[0],
+            "Tr" => material.transparency = parse_floats::<1>(rest, 1).with_context(error)?[0],
+            // PBR extension
+            "Pr" => material.roughness = parse_floats::<1>(rest, 1).with_context(error)?[0],
+            "Pm" => material.metallic = parse_floats::<1>(rest, 1).with_context(error)?[0],
+            "illum" => {
+                current.illum = rest
+                    .parse()
+                    .with_context(|| format!("{}: invalid illum", error()))?
+            }
+            "map_Kd" => current.diffuse_map = Some(map_path()),
+            "map_Ke" => current.emissive_map = Some(map_path()),
+            "map_Ks" => current.specular_map = Some(map_path()),
+            "map_Pr" => current.roughness_map = Some(map_path()),
+            "map_Pm" => current.metallic_map = Some(map_path()),
+            "map_d" => current.opacity_map = Some(map_path()),
+            _ => {}
+        }
+    }
+
+    // every texture is loaded once, all in parallel
+    let mut texture_paths: Vec<&PathBuf> = parsed
+        .iter()
+        .flat_map(|m| {
+            [
+                &m.diffuse_map,
+                &m.emissive_map,
+                &m.specular_map,
+                &m.roughness_map,
+                &m.metallic_map,
+                &m.opacity_map,
+            ]
+            .into_iter()
+            .flatten()
+        })
+        .collect();
+    texture_paths.sort();
+    texture_paths.dedup();
+    let ids = store
+        .textures
+        .add_files(&texture_paths, TextureOptions::DEFAULT)
+        .with_context(|| format!("Error loading textures of {}", path.display()))?;
+    let texture_ids: FxHashMap<&PathBuf, u32> = texture_paths.into_iter().zip(ids).collect();
+
+    let materials = parsed
+        .iter()
+        .map(|m| {
+            let mut material = m.material.clone();
+            if let Some(map) = &m.diffuse_map {
+                material.texture = Texture::Image(texture_ids[map]);
+            }
+            if let Some(map) = &m.emissive_map {
+                material.emissive = Texture::Image(texture_ids[map]);
+            }
+            let param = |map: &Option<PathBuf>| {
+                map.as_ref()
+                    .map(|map| ParamMap::new(Texture::Image(texture_ids[map]), Channel::Grey))
+            };
+            material.maps = MaterialMaps {
+                specular: m
+                    .specular_map
+                    .as_ref()
+                    .map(|map| Texture::Image(texture_ids[map])),
+                roughness: param(&m.roughness_map),
+                metallic: param(&m.metallic_map),
+                opacity: param(&m.opacity_map),
+                ..MaterialMaps::NONE
+            };
+            // illumination models 3 and up have raytraced reflections
+            if m.illum >= 3 {
+                let [r, g, b, _] = material.specular.to_array();
+                material.reflectivity = r.max(g).max(b);
+            }
+            if material.transparency > 0.0 && material.transmission.to_array()[..3] == [0.0; 3] {
+                material.transmission = Rgba::WHITE;
+            }
+            (m.name.clone(), material)
+        })
+        .collect();
+
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0.25
+";
+
+    fn parse(source: &str) -> Result<Vec<(MeshGeometry, Material)>> {
+        parse_obj(&mut Scene::new(), source, Path::new("test.obj"))
+    }
+
+    #[test]
+    fn quads_are_triangulated_as_a_fan() {
+        let meshes = parse(&format!("{QUAD}f 1/1 2/2 3 4")).unwrap();
+        assert_eq!(meshes.len(), 1);
+        let mesh = &meshes[0].0;
+        assert_eq!(mesh.verts.len(), 4);
+        assert_eq!(mesh.indices, [(0, 1, 2), (0, 2, 3)]);
+    }
+
+    #[test]
+    fn v_is_flipped() {
+        let meshes = parse(&format!("{QUAD}f 1/1 2/2 3")).unwrap();
+        let mesh = &meshes[0].0;
+        assert_eq!(mesh.tex_coords[0], Vec2::new(0.0, 1.0));
+        assert_eq!(mesh.tex_coords[1], Vec2::new(1.0, 0.75));
+        // no uv
+        assert_eq!(mesh.tex_coords[2], Vec2::ZERO);
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let meshes = parse(&format!("{QUAD}f -4/-2 -2 -1")).unwrap();
+        let mesh = &meshes[0].0;
+        assert_eq!(
+            mesh.verts,
+            [(0.0, 0.0, 0.0), (1.0, 1.0, 0.0), (0.0, 1.0, 0.0)]
+        );
+        assert_eq!(mesh.tex_coords[0], Vec2::new(0.0, 1.0));
+        assert_eq!(mesh.indices, [(0, 1, 2)]);
+    }
+
+    #[test]
+    fn vertices_are_shared_only_with_the_same_uv() {
+        let meshes = parse(&format!("{QUAD}f 1/1 2 3\nf 1/1 3 4\nf 1/2 3 4")).unwrap();
+        let mesh = &meshes[0].0;
+        assert_eq!(mesh.verts.len(), 5);
+        assert_eq!(mesh.indices, [(0, 1, 2), (0, 2, 3), (4, 2, 3)]);
+    }
+
+    #[test]
+    fn out_of_range_indices_are_errors() {
+        for face in ["f 1 2 5", "f 1 2 -5", "f 0 1 2", "f 1/3 2 3"] {
+            let err = parse(&format!("{QUAD}{face}")).unwrap_err();
+            let message = format!("{err:#}");
+            assert!(message.contains("line 8"), "{message}");
+            assert!(
+                message.contains("out of bounds") || message.contains("Index 0"),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn faces_need_3_vertices() {
+        assert!(parse(&format!("{QUAD}f 1 2")).is_err());
+    }
+
+    #[test]
+    fn materials_must_be_defined() {
+        let meshes = parse(&format!("{QUAD}f 1 2 3")).unwrap();
+        assert_eq!(meshes[0].1.color.to_array(), [0.9, 0.9, 0.9, 1.0]);
+
+        let err = parse(&format!("{QUAD}usemtl red\nf 1 2 3")).unwrap_err();
+        assert!(format!("{err:#}").contains("Material red"));
+    }
+
+    #[test]
+    fn several_mtl_files() {
+        let dir = std::env::temp_dir().join(format!("obj-mtllib-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+        std::fs::write(dir.join("b.mtl"), "newmtl blue\nKd 0 0 1\n").unwrap();
+
+        let source =
+            format!("mtllib a.mtl  b.mtl\n{QUAD}usemtl red\nf 1 2 3\nusemtl blue\nf 1 3 4");
+        let meshes = parse_obj(&mut Scene::new(), &source, &dir.join("test.obj"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let colors: Vec<_> = meshes
+            .unwrap()
+            .iter()
+            .map(|(_, m)| m.color.to_array())
+            .collect();
+        assert!(colors.contains(&[1.0, 0.0, 0.0, 1.0]));
+        assert!(colors.contains(&[0.0, 0.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn mtl_materials() {
+        let materials = parse_mtl(
+            &mut Scene::new(),
+            "
+# a comment
+newmtl red
+Kd 1 0 0
+Ks 0.5
+illum 3
+
+newmtl glass
+Kd 0.2 # grey
+d 0.25
+Ni 1.5
+Pr 0.1
+",
+            Path::new("test.mtl"),
+        )
+        .unwrap();
+
+        let red = &materials["red"];
+        assert_eq!(red.color.to_array(), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(red.specular.to_array(), [0.5, 0.5, 0.5, 1.0]);
+        assert_eq!(red.reflectivity, 0.5);
+        assert_eq!(red.transparency, 0.0);
+
+        let glass = &materials["glass"];
+        assert_eq!(glass.color.to_array(), [0.2, 0.2, 0.2, 1.0]);
+        assert_eq!(glass.transparency, 0.75);
+        assert_eq!(glass.transmission.to_array(), Rgba::WHITE.to_array());
+        assert_eq!(glass.refraction, 1.5);
+        assert_eq!(glass.roughness, 0.1);
+        // illum 2 has no raytraced reflections
+        assert_eq!(glass.reflectivity, 0.0);
+    }
+
+    #[test]
+    fn mtl_errors_have_the_line() {
+        let err = parse_mtl(
+            &mut Scene::new(),
+            "newmtl red\nKd red",
+            Path::new("test.mtl"),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("test.mtl at line 2"));
+    }
+}
@@ -0,0 +1,597 @@
+use crate::color::Rgba;
+use crate::geometry::{GeomInfo, Geometry, Material, MeshGeometry, PointCloud, PointShape, Scene};
+use anyhow::{Context, Result, bail};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => bail!("Unknown PLY type {name}"),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    // what a color channel of this type is divided by, integers use their whole range and floats are already in [0, 1]
+    fn color_range(self) -> f64 {
+        match self {
+            Self::I8 => i8::MAX as f64,
+            Self::U8 => u8::MAX as f64,
+            Self::I16 => i16::MAX as f64,
+            Self::U16 => u16::MAX as f64,
+            Self::I32 => i32::MAX as f64,
+            Self::U32 => u32::MAX as f64,
+            Self::F32 | Self::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PropertyType {
+    Scalar(ScalarType),
+    List { count: ScalarType, item: ScalarType },
+}
+
+// values of one property for every instance of an element.
+// lists are stored one after the other, offsets has count + 1 entries
+#[derive(Debug)]
+enum Column {
+    Scalar(Vec<f64>),
+    List {
+        offsets: Vec<usize>,
+        values: Vec<f64>,
+    },
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    ty: PropertyType,
+    column: Column,
+}
+
+/// An element of a PLY file (vertex, face...) with the values of every instance of it
+#[derive(Debug)]
+pub struct PlyElement {
+    pub name: String,
+    pub count: usize,
+    properties: Vec<Property>,
+}
+
+impl PlyElement {
+    pub fn scalar(&self, name: &str) -> Option<&[f64]> {
+        self.properties
+            .iter()
+            .find(|p| p.name == name)
+            .and_then(|p| match &p.column {
+                Column::Scalar(values) => Some(values.as_slice()),
+                Column::List { .. } => None,
+            })
+    }
+
+    /// first of the names that exists, files do not agree on what to call things (u, s, texture_u...)
+    pub fn scalar_any(&self, names: &[&str]) -> Option<&[f64]> {
+        names.iter().find_map(|name| self.scalar(name))
+    }
+
+    // with its type, for values whose meaning depends on it
+    fn typed_scalar_any(&self, names: &[&str]) -> Option<(&[f64], ScalarType)> {
+        names.iter().find_map(|name| {
+            self.properties
+                .iter()
+                .find(|p| p.name == *name)
+                .and_then(|p| match (&p.ty, &p.column) {
+                    (PropertyType::Scalar(ty), Column::Scalar(values)) => {
+                        Some((values.as_slice(), *ty))
+                    }
+                    _ => None,
+                })
+        })
+    }
+
+    pub fn list(&self, name: &str) -> Option<impl Iterator<Item = &[f64]>> {
+        self.properties
+            .iter()
+            .find(|p| p.name == name)
+            .and_then(|p| match &p.column {
+                Column::List { offsets, values } => {
+                    Some(offsets.windows(2).map(|w| &values[w[0]..w[1]]))
+                }
+                Column::Scalar(_) => None,
+            })
+    }
+}
+
+/// Every element of an ASCII or binary PLY file
+#[derive(Debug)]
+pub struct PlyFile {
+    pub elements: Vec<PlyElement>,
+}
+
+impl PlyFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Error reading PLY file {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("Error parsing PLY file {}", path.display()))
+    }
+
+    pub fn element(&self, name: &str) -> Option<&PlyElement> {
+        self.elements.iter().find(|e| e.name == name)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        const END_HEADER: &[u8] = b"end_header";
+        let header_end = bytes
+            .windows(END_HEADER.len())
+            .position(|w| w == END_HEADER)
+            .context("Missing end_header")?;
+        // the body starts after the end of the end_header line
+        let body_start = bytes[header_end..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(bytes.len(), |i| header_end + i + 1);
+
+        let header = std::str::from_utf8(&bytes[..header_end]).context("Header is not text")?;
+        let mut lines = header.lines().map(str::trim);
+        if lines.next() != Some("ply") {
+            bail!("Not a PLY file");
+        }
+
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = Vec::new();
+        for line in lines {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                ["format", name, _version] => {
+                    format = Some(match *name {
+                        "ascii" => Format::Ascii,
+                        "binary_little_endian" => Format::BinaryLittleEndian,
+                        "binary_big_endian" => Format::BinaryBigEndian,
+                        _ => bail!("Unknown PLY format {name}"),
+                    })
+                }
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count
+                        .parse()
+                        .with_context(|| format!("Invalid element count {count}"))?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count, item, name] => {
+                    let element = elements.last_mut().context("Property before any element")?;
+                    element.properties.push(Property {
+                        name: name.to_string(),
+                        ty: PropertyType::List {
+                            count: ScalarType::parse(count)?,
+                            item: ScalarType::parse(item)?,
+                        },
+                        column: Column::List {
+                            offsets: vec![0],
+                            values: Vec::new(),
+                        },
+                    });
+                }
+                ["property", ty, name] => {
+                    let element = elements.last_mut().context("Property before any element")?;
+                    element.properties.push(Property {
+                        name: name.to_string(),
+                        ty: PropertyType::Scalar(ScalarType::parse(ty)?),
+                        // the count comes from the header, so it is not trusted with an allocation
+                        column: Column::Scalar(Vec::new()),
+                    });
+                }
+                // comments, obj_info
+                _ => {}
+            }
+        }
+
+        let format = format.context("Missing format")?;
+        let body = &bytes[body_start..];
+        let mut reader = match format {
+            Format::Ascii => Body::Ascii(
+                std::str::from_utf8(body)
+                    .context("ASCII body is not text")?
+                    .split_ascii_whitespace(),
+            ),
+            _ => Body::Binary {
+                bytes: body,
+                pos: 0,
+                big_endian: format == Format::BinaryBigEndian,
+            },
+        };
+
+        for element in &mut elements {
+            for _ in 0..element.count {
+                for property in &mut element.properties {
+                    match (&property.ty, &mut property.column) {
+                        (PropertyType::Scalar(ty), Column::Scalar(values)) => {
+                            values.push(reader.read(*ty)?)
+                        }
+                        (PropertyType::List { count, item }, Column::List { offsets, values }) => {
+                            let n = reader.read(*count)? as usize;
+                            for _ in 0..n {
+                                values.push(reader.read(*item)?);
+                            }
+                            offsets.push(values.len());
+                        }
+                        _ => unreachable!("Columns are created from the property type"),
+                    }
+                }
+            }
+        }
+
+        Ok(Self { elements })
+    }
+}
+
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary {
+        bytes: &'a [u8],
+        pos: usize,
+        big_endian: bool,
+    },
+}
+
+impl Body<'_> {
+    fn read(&mut self, ty: ScalarType) -> Result<f64> {
+        match self {
+            Self::Ascii(tokens) => {
+                let token = tokens.next().context("Unexpected end of file")?;
+                token
+                    .parse()
+                    .with_context(|| format!("Invalid number {token}"))
+            }
+            Self::Binary {
+                bytes,
+                pos,
+                big_endian,
+            } => {
+                let size = ty.size();
+                let Some(slice) = bytes.get(*pos..*pos + size) else {
+                    bail!("Unexpected end of file");
+                };
+                *pos += size;
+
+                // everything is read as little endian
+                let mut b = [0u8; 8];
+                b[..size].copy_from_slice(slice);
+                if *big_endian {
+                    b[..size].reverse();
+                }
+
+                Ok(match ty {
+                    ScalarType::I8 => b[0] as i8 as f64,
+                    ScalarType::U8 => b[0] as f64,
+                    ScalarType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    ScalarType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    ScalarType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::F64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+}
+
+/// Triangle mesh from the vertex and face elements, polygons are triangulated as a fan
+pub fn load_ply(path: impl AsRef<Path>) -> Result<MeshGeometry> {
+    let path = path.as_ref();
+    let ply = PlyFile::load(path)?;
+    ply_mesh(&ply).with_context(|| format!("Invalid mesh in {}", path.display()))
+}
+
+fn ply_mesh(ply: &PlyFile) -> Result<MeshGeometry> {
+    let vertex = ply.element("vertex").context("No vertex element")?;
+    let (Some(x), Some(y), Some(z)) = (vertex.scalar("x"), vertex.scalar("y"), vertex.scalar("z"))
+    else {
+        bail!("Vertices need x, y and z");
+    };
+    let verts: Vec<(f32, f32, f32)> = (0..vertex.count)
+        .map(|i| (x[i] as f32, y[i] as f32, z[i] as f32))
+        .collect();
+
+    let u = vertex.scalar_any(&["u", "s", "texture_u"]);
+    let v = vertex.scalar_any(&["v", "t", "texture_v"]);
+    let tex_coords = match (u, v) {
+        (Some(u), Some(v)) => (0..vertex.count)
+            // v = 0 is the bottom of the image, same as OBJ
+            .map(|i| Vec2::new(u[i] as f32, 1.0 - v[i] as f32))
+            .collect(),
+        _ => vec![Vec2::ZERO; vertex.count],
+    };
+
+    let mut indices = Vec::new();
+    if let Some(face) = ply.element("face") {
+        let faces = face
+            .list("vertex_indices")
+            .or_else(|| face.list("vertex_index"))
+            .context("Faces need vertex_indices")?;
+
+        for polygon in faces {
+            // the values are floats by now, so negative or fractional ones would be cast into valid indices
+            if let Some(i) = polygon
+                .iter()
+                .find(|i| **i < 0.0 || i.fract() != 0.0 || **i as usize >= verts.len())
+            {
+                bail!("Vertex index {i} is out of bounds");
+            }
+            for i in 1..polygon.len().saturating_sub(1) {
+                indices.push((polygon[0] as u32, polygon[i] as u32, polygon[i + 1] as u32));
+            }
+        }
+    }
+
+    Ok(MeshGeometry {
+        verts,
+        indices,
+        tex_coords,
+        extra_tex_coords: Vec::new(),
+        tex_coord_set: 0,
+    })
+}
+
+/// Loads a PLY mesh into the scene, transformed by matrix
+pub fn add_ply(
+    store: &mut Scene,
+    path: impl AsRef<Path>,
+    matrix: Mat4,
+    material: &Material,
+) -> Result<()> {
+    let mut mesh = load_ply(path)?;
+    mesh.transform(matrix);
+    store.add_geometry(Geometry::with_material(
+        material.clone(),
+        GeomInfo::Mesh(mesh),
+    ));
+
+    Ok(())
+}
+
+/// Point cloud from the vertex element, faces are ignored.
+/// Points without a radius property get `radius`, integer colors use the whole range of their type and float ones 0-1
+pub fn load_ply_points(path: impl AsRef<Path>, radius: f32) -> Result<PointCloud> {
+    let path = path.as_ref();
+    let ply = PlyFile::load(path)?;
+    ply_points(&ply, radius).with_context(|| format!("Invalid point cloud in {}", path.display()))
+}
+
+fn ply_points(ply: &PlyFile, radius: f32) -> Result<PointCloud> {
+    let vertex = ply.element("vertex").context("No vertex element")?;
+    let (Some(x), Some(y), Some(z)) = (vertex.scalar("x"), vertex.scalar("y"), vertex.scalar("z"))
+    else {
+        bail!("Vertices need x, y and z");
+    };
+    let radii = vertex.scalar_any(&["radius", "scale"]);
+    let points = (0..vertex.count)
+        .map(|i| {
+            let r = radii.map_or(radius, |r| r[i] as f32);
+            Vec4::new(x[i] as f32, y[i] as f32, z[i] as f32, r)
+        })
+        .collect();
+
+    let red = vertex.typed_scalar_any(&["red", "r", "diffuse_red"]);
+    let green = vertex.typed_scalar_any(&["green", "g", "diffuse_green"]);
+    let blue = vertex.typed_scalar_any(&["blue", "b", "diffuse_blue"]);
+    let colors = match (red, green, blue) {
+        (Some((r, r_ty)), Some((g, g_ty)), Some((b, b_ty))) => {
+            let (r_range, g_range, b_range) =
+                (r_ty.color_range(), g_ty.color_range(), b_ty.color_range());
+            (0..vertex.count)
+                .map(|i| {
+                    Rgba::rgb(
+                        (r[i] / r_range) as f32,
+                        (g[i] / g_range) as f32,
+                        (b[i] / b_range) as f32,
+                    )
+                })
+                .collect()
+        }
+        _ => vec![Rgba::WHITE; vertex.count],
+    };
+
+    let normals = match (
+        vertex.scalar("nx"),
+        vertex.scalar("ny"),
+        vertex.scalar("nz"),
+    ) {
+        (Some(nx), Some(ny), Some(nz)) => Some(
+            (0..vertex.count)
+                .map(|i| Vec3::new(nx[i] as f32, ny[i] as f32, nz[i] as f32).normalize_or_zero())
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    PointCloud::new(points, colors, normals, PointShape::Disc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII_HEADER: &str = "ply
+format ascii 1.0
+comment a unit square
+element vertex 4
+property float x
+property float y
+property float z
+property float s
+property float t
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    const SQUARE: &str = "0 0 0 0 0
+1 0 0 1 0
+1 1 0 1 1
+0 1 0 0 1
+";
+
+    fn ascii(body: &str) -> Result<MeshGeometry> {
+        let ply = PlyFile::parse(format!("{ASCII_HEADER}{body}").as_bytes())?;
+        ply_mesh(&ply)
+    }
+
+    #[test]
+    fn ascii_mesh() {
+        let mesh = ascii(&format!("{SQUARE}4 0 1 2 3")).unwrap();
+        assert_eq!(mesh.verts.len(), 4);
+        assert_eq!(mesh.verts[2], (1.0, 1.0, 0.0));
+        // fan
+        assert_eq!(mesh.indices, [(0, 1, 2), (0, 2, 3)]);
+        // v flipped like OBJ
+        assert_eq!(mesh.tex_coords[0], Vec2::new(0.0, 1.0));
+        assert_eq!(mesh.tex_coords[2], Vec2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn invalid_indices_are_errors() {
+        for face in ["3 0 1 4", "3 0 1 -1", "3 0 1 1.5"] {
+            let err = ascii(&format!("{SQUARE}{face}")).unwrap_err();
+            assert!(format!("{err:#}").contains("out of bounds"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        assert!(ascii("0 0 0 0 0\n1 0 0").is_err());
+    }
+
+    #[test]
+    fn binary_little_endian_mesh() {
+        let mut bytes = b"ply
+format binary_little_endian 1.0
+element vertex 3
+property float x
+property float y
+property double z
+element face 1
+property list uchar uint vertex_index
+end_header
+"
+        .to_vec();
+        for (x, y, z) in [(0.0f32, 0.0f32, 0.5f64), (2.0, 0.0, 0.5), (0.0, 2.0, 0.5)] {
+            bytes.extend(x.to_le_bytes());
+            bytes.extend(y.to_le_bytes());
+            bytes.extend(z.to_le_bytes());
+        }
+        bytes.push(3);
+        for i in [0u32, 1, 2] {
+            bytes.extend(i.to_le_bytes());
+        }
+
+        let ply = PlyFile::parse(&bytes).unwrap();
+        let mesh = ply_mesh(&ply).unwrap();
+        assert_eq!(
+            mesh.verts,
+            [(0.0, 0.0, 0.5), (2.0, 0.0, 0.5), (0.0, 2.0, 0.5)]
+        );
+        assert_eq!(mesh.indices, [(0, 1, 2)]);
+        // no uvs in the file
+        assert_eq!(mesh.tex_coords, [Vec2::ZERO; 3]);
+    }
+
+    #[test]
+    fn points_with_byte_colors() {
+        let ply = PlyFile::parse(
+            b"ply
+format ascii 1.0
+element vertex 2
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+end_header
+0 0 0 255 0 0
+1 2 3 0 51 255
+",
+        )
+        .unwrap();
+        let cloud = ply_points(&ply, 0.5).unwrap();
+        assert_eq!(
+            cloud.points,
+            [Vec4::new(0.0, 0.0, 0.0, 0.5), Vec4::new(1.0, 2.0, 3.0, 0.5)]
+        );
+        assert_eq!(cloud.colors[1].to_array(), [0.0, 0.2, 1.0, 1.0]);
+        assert!(cloud.normals.is_none());
+    }
+
+    #[test]
+    fn color_scale_follows_the_type() {
+        let ply = PlyFile::parse(
+            b"ply
+format ascii 1.0
+element vertex 2
+property float x
+property float y
+property float z
+property ushort red
+property float green
+property uchar blue
+end_header
+0 0 0 65535 0.5 0
+1 2 3 0 2 51
+",
+        )
+        .unwrap();
+        let cloud = ply_points(&ply, 0.5).unwrap();
+        assert_eq!(cloud.colors[0].to_array(), [1.0, 0.5, 0.0, 1.0]);
+        // floats are not rescaled, even above 1
+        assert_eq!(cloud.colors[1].to_array(), [0.0, 2.0, 0.2, 1.0]);
+    }
+
+    #[test]
+    fn huge_counts_are_not_allocated() {
+        let ply = PlyFile::parse(
+            b"ply
+format binary_little_endian 1.0
+element vertex 4000000000000
+property float x
+end_header
+",
+        );
+        assert!(ply.is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::color::Rgba;
+use crate::geometry::{GeomInfo, Geometry, Material, PointCloud, PointShape, Scene};
+use anyhow::{Context, Result, bail};
+use glam::{Mat4, Vec3, Vec4};
+use std::path::Path;
+
+/// Point cloud from a text file with one point per line.
+/// The columns are `x y z`, then optionally `r g b` and `nx ny nz`.
+/// Colors above 1 anywhere in the file mean the whole file uses 0-255
+pub fn load_xyz(path: impl AsRef<Path>, radius: f32) -> Result<PointCloud> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading XYZ file {}", path.display()))?;
+    parse_xyz(&source, path, radius)
+}
+
+// the path is only for error messages
+fn parse_xyz(source: &str, path: &Path, radius: f32) -> Result<PointCloud> {
+    let mut points = Vec::new();
+    let mut colors = Vec::new();
+    let mut normals = Vec::new();
+
+    for (line_number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = || format!("Error in {} at line {}", path.display(), line_number + 1);
+
+        let values = line
+            .split([' ', '\t', ',', ';'])
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                token
+                    .parse::<f32>()
+                    .with_context(|| format!("Invalid number {token}"))
+            })
+            .collect::<Result<Vec<f32>>>()
+            .with_context(error)?;
+
+        match values.as_slice() {
+            [x, y, z, rest @ ..] => {
+                points.push(Vec4::new(*x, *y, *z, radius));
+                match rest {
+                    [] => {}
+                    [r, g, b] => colors.push(Rgba::rgb(*r, *g, *b)),
+                    [r, g, b, nx, ny, nz] => {
+                        colors.push(Rgba::rgb(*r, *g, *b));
+                        normals.push(Vec3::new(*nx, *ny, *nz).normalize_or_zero());
+                    }
+                    _ => bail!("{}: expected 3, 6 or 9 columns", error()),
+                }
+            }
+            _ => bail!("{}: expected 3, 6 or 9 columns", error()),
+        }
+    }
+
+    // every line has to agree on the columns
+    let colors = match colors.len() {
+        0 => vec![Rgba::WHITE; points.len()],
+        n if n == points.len() => {
+            let max = colors
+                .iter()
+                .flat_map(|c| c.to_array().into_iter().take(3))
+                .fold(0.0, f32::max);
+            if max > 1.0 {
+                colors
+                    .iter()
+                    .map(|c| {
+                        let [r, g, b, _] = c.to_array();
+                        Rgba::rgb(r / 255.0, g / 255.0, b / 255.0)
+                    })
+                    .collect()
+            } else {
+                colors
+            }
+        }
+        _ => bail!(
+            "Some points of {} have colors and some do not",
+            path.display()
+        ),
+    };
+    let normals = match normals.len() {
+        0 => None,
+        n if n == points.len() => Some(normals),
+        _ => bail!(
+            "Some points of {} have normals and some do not",
+            path.display()
+        ),
+    };
+
+    PointCloud::new(points, colors, normals, PointShape::Disc)
+        .with_context(|| format!("Invalid point cloud in {}", path.display()))
+}
+
+/// Loads a point cloud from a PLY or XYZ file (by extension) into the scene, transformed by matrix and drawn as `shape`.
+/// The material only gives the emission and how the points scatter light, the color comes from the file
+pub fn add_point_cloud(
+    store: &mut Scene,
+    path: impl AsRef<Path>,
+    radius: f32,
+    shape: PointShape,
+    matrix: Mat4,
+    material: &Material,
+) -> Result<()> {
+    let path = path.as_ref();
+    let is_ply = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ply"));
+    let cloud = if is_ply {
+        super::ply::load_ply_points(path, radius)?
+    } else {
+        load_xyz(path, radius)?
+    };
+    let mut cloud = cloud.with_shape(shape);
+
+    cloud.transform(matrix);
+    store.add_geometry(Geometry::with_material(
+        material.clone(),
+        GeomInfo::Points(cloud),
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<PointCloud> {
+        parse_xyz(source, Path::new("test.xyz"), 0.5)
+    }
+
+    #[test]
+    fn positions_only() {
+        let cloud = parse("# x y z\n1 2 3\n\n4\t5\t6\n").unwrap();
+        assert_eq!(
+            cloud.points,
+            [Vec4::new(1.0, 2.0, 3.0, 0.5), Vec4::new(4.0, 5.0, 6.0, 0.5)]
+        );
+        assert_eq!(cloud.colors[0].to_array(), Rgba::WHITE.to_array());
+        assert!(cloud.normals.is_none());
+    }
+
+    #[test]
+    fn byte_colors_and_normals() {
+        let cloud = parse("0,0,0,255,0,0,0,0,2\n1;1;1;0;51;0;0;3;0").unwrap();
+        assert_eq!(cloud.colors[0].to_array(), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(cloud.colors[1].to_array(), [0.0, 0.2, 0.0, 1.0]);
+        // normalized
+        assert_eq!(cloud.normals.unwrap(), [Vec3::Z, Vec3::Y]);
+    }
+
+    #[test]
+    fn float_colors_are_kept() {
+        let cloud = parse("0 0 0 0.5 0.25 1").unwrap();
+        assert_eq!(cloud.colors[0].to_array(), [0.5, 0.25, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn columns_must_agree() {
+        assert!(parse("0 0 0\n1 1 1 1 1 1").is_err());
+        let err = parse("0 0 0\n1 1").unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"));
+        assert!(parse("0 0 0 x y z").is_err());
+    }
+}
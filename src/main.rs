@@ -6,14 +6,21 @@ use std::io::*;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 
+mod animation;
 mod camera;
 mod color;
 mod common;
 mod configs;
 mod cornell;
 mod geometry;
+mod hair;
+mod loaders;
+mod medium;
+mod outdoor;
+mod postprocess;
 mod raytracer;
 mod renderer;
+mod spectrum;
 mod tonemap;
 
 use configs::*;
@@ -21,42 +28,121 @@ use configs::*;
 fn main() -> anyhow::Result<()> {
     let instant = std::time::Instant::now();
     let device = embree4_rs::Device::try_new(None)?;
-    let mut raytracer_builder = raytracer::embree::EmbreeRayTracerBuilder::new(&device);
 
-    let mut scene = geometry::Scene::new()?;
-    scene.add_textures_batch_from_files(&vec![
-        "assets/textures/skybox/front.jpg",
-        "assets/textures/skybox/back.jpg",
-        "assets/textures/skybox/right.jpg",
-        "assets/textures/skybox/left.jpg",
-        "assets/textures/skybox/top.jpg",
-        "assets/textures/skybox/bottom.jpg",
-    ])?;
-    cornell::cornell_box(&mut scene)?;
-
-    let (gltf_doc, gltf_buff, _) = gltf::import("assets/magujo/suzanne.glb")?;
-    let transform = glam::Mat4::from_scale_rotation_translation(
-        Vec3::splat(50.0),
-        Quat::from_rotation_y(220.0_f32.to_radians()),
-        Vec3::new(450.0, 50.0, 150.0),
-    );
-    cornell::add_gltf(
-        &mut scene,
-        &gltf_doc,
-        &gltf_buff,
-        transform,
-        &geometry::Material::MIRROR_MATERIAL,
-    )?;
-
-    cornell::add_skybox(&mut scene)?;
+    // what is rendered
+    let sceneconfig = SceneConfig::CornellBox;
+
+    let mut scene = geometry::Scene::new();
+    // the geometry the turntable spins and its pivot, and the cameras of the scene file
+    let (turntable, turntable_pivot, gltf_cameras) = match sceneconfig {
+        SceneConfig::CornellBox => {
+            cornell::cornell_box(&mut scene)?;
+
+            let (gltf_doc, gltf_buff, gltf_images) = gltf::import("assets/magujo/suzanne.glb")
+                .context("Error importing assets/magujo/suzanne.glb")?;
+            let suzanne_pos = Vec3::new(450.0, 50.0, 150.0);
+            let transform = glam::Mat4::from_scale_rotation_translation(
+                Vec3::splat(50.0),
+                Quat::from_rotation_y(220.0_f32.to_radians()),
+                suzanne_pos,
+            );
+            let suzanne_start = scene.geometry.len();
+            cornell::add_gltf(
+                &mut scene,
+                &gltf_doc,
+                &gltf_buff,
+                &gltf_images,
+                transform,
+                &geometry::Material::MIRROR_MATERIAL,
+            )
+            .context("Error adding assets/magujo/suzanne.glb")?;
+
+            let suzanne = suzanne_start..scene.geometry.len();
+            let gltf_cameras = cornell::get_gltf_cameras(&gltf_doc, transform, CamConfig::BALANCED)
+                .context("Error reading the cameras of assets/magujo/suzanne.glb")?;
+
+            cornell::add_skybox(&mut scene)?;
+
+            (suzanne, suzanne_pos, gltf_cameras)
+        }
+        SceneConfig::Outdoor => {
+            let middle = outdoor::outdoor_scene(&mut scene)?;
+            (middle, outdoor::OUTDOOR_CENTER, Vec::new())
+        }
+    };
+
+    // density of a haze filling the air, for closed scenes only (outside there would be no sky left)
+    let fog_density: Option<f32> = None;
+    scene.fog = fog_density.map(|density| medium::Medium::fog(density, 0.3));
+
+    // more models, from OBJ, PLY or XYZ files
+    let models: &[ModelConfig] = &[];
+    for model in models {
+        loaders::add_model(&mut scene, model)?;
+    }
+
+    // smoke and clouds, from raw voxel grids
+    let volumes: &[VolumeConfig] = &[];
+    for volume in volumes {
+        let grid = geometry::VoxelGrid::load_raw(volume.path)?;
+        scene.add_volume(geometry::Volume::new(
+            grid,
+            volume.min,
+            volume.max,
+            volume.medium,
+        ));
+    }
+
+    // configs
+    let renderconfig = RenderConfig::slowest();
+    // the camera from the glTF file, if it has one
+    let camconfig = gltf_cameras.first().copied().unwrap_or(CamConfig::BALANCED);
+    let postconfig = PostProcessConfig::tonemap_only();
+    // Some renders an animation to numbered images instead of a single one
+    let sequence: Option<SequenceConfig> = None;
+
+    if let Some(sequence) = sequence {
+        // the camera backs away from the model and comes back while it turns
+        let (start, end) = (sequence.frames.start as f32, sequence.frames.end as f32);
+        let away = camconfig.pos + (camconfig.pos - camconfig.lookat) * 0.3;
+        let camera_path = animation::Track::new(
+            vec![
+                (start, camconfig.pos),
+                ((start + end) * 0.5, away),
+                (end, camconfig.pos),
+            ],
+            animation::Interpolation::Smooth,
+        )?;
+
+        let animation = animation::Animation {
+            camera: Some(animation::CameraAnimation {
+                pos: camera_path,
+                lookat: animation::Track::constant(camconfig.lookat),
+                fov: None,
+            }),
+            objects: vec![animation::ObjectAnimation::turntable(
+                turntable,
+                turntable_pivot,
+                sequence.frames.len() as u32,
+            )],
+        };
+
+        return animation::render_sequence(
+            &device,
+            scene,
+            &animation,
+            &sequence,
+            camconfig,
+            renderconfig,
+            &postconfig,
+        );
+    }
 
+    let mut raytracer_builder = raytracer::embree::EmbreeRayTracerBuilder::new(&device);
     let scene = scene
         .build_scene(&mut raytracer_builder)
         .context("Error building scene")?;
 
-    // configs
-    let renderconfig = RenderConfig::slowest();
-    let camconfig = CamConfig::BALANCED;
     let number_of_pixels = (camconfig.w * camconfig.h) as usize;
 
     let renderer = renderer::Renderer::new(scene, renderconfig);
@@ -87,12 +173,15 @@ fn main() -> anyhow::Result<()> {
 
     let mut image = renderer.render_par_with_progress(&camera, rendered_pixels);
 
-    tonemap::tonemap(&mut image);
+    postprocess::apply(&mut image, &postconfig.stages);
 
     println!("Render complete in: {:?}", instant.elapsed());
 
     let image: RgbImage = image.convert();
     image.save("MyImage.png").context("Error saving image")?;
 
+    // the image is saved anyway, with checkers where the textures are missing
+    renderer.check_textures()?;
+
     Ok(())
 }
@@ -0,0 +1,285 @@
+use crate::color::Rgba;
+use crate::geometry::Material;
+use crate::raytracer::GeometryId;
+use crate::renderer::AIR_REFRACT;
+
+// glass inside water inside glass inside ... is already 3
+const MAX_NESTED_MEDIA: usize = 8;
+
+/// Homogeneous participating medium, filling the inside of a closed geometry or the whole scene (fog)
+#[derive(Debug, Clone, Copy)]
+pub struct Medium {
+    /// fraction of light absorbed per unit of distance, per channel
+    pub sigma_a: Rgba,
+    /// fraction of light scattered per unit of distance, per channel
+    pub sigma_s: Rgba,
+    /// henyey-greenstein asymmetry, -1 is full back scattering, 0 isotropic, 1 full forward scattering
+    pub g: f32,
+}
+
+impl Medium {
+    /// only absorbs, like tinted glass or clear liquids
+    pub const fn absorbing(sigma_a: Rgba) -> Self {
+        Self {
+            sigma_a,
+            sigma_s: Rgba::new(0.0, 0.0, 0.0, 0.0),
+            g: 0.0,
+        }
+    }
+
+    /// grey fog, `density` is the fraction of light scattered per unit of distance
+    pub const fn fog(density: f32, g: f32) -> Self {
+        Self {
+            sigma_a: Rgba::new(0.0, 0.0, 0.0, 0.0),
+            sigma_s: Rgba::new(density, density, density, density),
+            g,
+        }
+    }
+
+    /// absorbing medium where light that travels `distance` comes out as `color`. easier to tweak than sigma_a
+    pub fn from_color_at_distance(color: Rgba, distance: f32) -> Self {
+        Self::absorbing(color.map(|c| -c.max(1e-6).ln() / distance))
+    }
+
+    pub fn sigma_t(&self) -> Rgba {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn scatters(&self) -> bool {
+        self.sigma_s.to_array().iter().any(|s| *s > 0.0)
+    }
+
+    /// henyey-greenstein phase function
+    /// cos_theta is between the direction the ray was travelling in and the new direction
+    pub fn phase(&self, cos_theta: f32) -> f32 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * denom.sqrt())
+    }
+}
+
+/// beer-lambert law, per channel
+#[inline]
+pub fn transmittance(sigma_t: Rgba, distance: f32) -> Rgba {
+    sigma_t.map(|s| if s > 0.0 { (-s * distance).exp() } else { 1.0 })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MediumEntry {
+    geometry: GeometryId,
+    priority: u32,
+    ior: f32,
+    medium: Option<Medium>,
+}
+
+/// Every closed transmissive object the ray is currently inside of (Schmidt and Budge 2002).
+/// The medium the ray is travelling through is the one with the highest priority, ties go to the one entered last.
+/// This is what allows nested or touching dielectrics (liquid in a glass, bubbles, objects inside glass)
+#[derive(Debug, Clone, Copy)]
+pub struct MediumStack {
+    entries: [Option<MediumEntry>; MAX_NESTED_MEDIA],
+    len: usize,
+    /// medium outside of every object, used for fog
+    outside: Option<Medium>,
+}
+
+/// What happens to the ray when it crosses the surface of a geometry
+#[derive(Debug, Clone, Copy)]
+pub struct Interface {
+    /// refraction being left
+    pub n1: f32,
+    /// refraction being entered
+    pub n2: f32,
+    /// media the ray is in after going through the surface
+    pub refracted: MediumStack,
+    /// the surface separates two parts of the same (higher priority) medium, so it does not exist optically
+    /// e.g. the part of a liquid that overlaps the glass holding it
+    pub ignored: bool,
+}
+
+impl MediumStack {
+    /// only air, which might be foggy
+    pub const fn new(outside: Option<Medium>) -> Self {
+        Self {
+            entries: [None; MAX_NESTED_MEDIA],
+            len: 0,
+            outside,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &MediumEntry> {
+        self.entries[..self.len].iter().flatten()
+    }
+
+    fn top(&self) -> Option<&MediumEntry> {
+        // max_by_key returns the last of equal elements, which is the most recently entered
+        self.iter().max_by_key(|entry| entry.priority)
+    }
+
+    fn contains(&self, geometry: GeometryId) -> bool {
+        self.iter().any(|entry| entry.geometry == geometry)
+    }
+
+    pub fn current_ior(&self) -> f32 {
+        self.top().map_or(AIR_REFRACT, |entry| entry.ior)
+    }
+
+    /// participating medium the ray is travelling through, if any
+    pub fn current_medium(&self) -> Option<Medium> {
+        match self.top() {
+            Some(entry) => entry.medium,
+            None => self.outside,
+        }
+    }
+
+    // false if it is too deep to keep track of
+    fn push(&mut self, entry: MediumEntry) -> bool {
+        if self.len < MAX_NESTED_MEDIA {
+            self.entries[self.len] = Some(entry);
+            self.len += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remove(&mut self, geometry: GeometryId) {
+        if let Some(index) = self.entries[..self.len]
+            .iter()
+            .rposition(|entry| entry.is_some_and(|entry| entry.geometry == geometry))
+        {
+            self.entries.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+            self.entries[self.len] = None;
+        }
+    }
+
+    /// The ray hit the surface of `geometry`. If it was already inside of it, it is leaving, otherwise entering.
+    /// This only relies on the geometry being closed, not on the orientation of its normals
+    pub fn cross(
+        &self,
+        geometry: GeometryId,
+        material: &Material,
+        lambda: Option<f32>,
+    ) -> Interface {
+        self.cross_with_ior(geometry, material, material.ior(lambda))
+    }
+
+    /// same as `cross`, for surfaces where the ior is not the one of the material (ior maps)
+    pub fn cross_with_ior(&self, geometry: GeometryId, material: &Material, ior: f32) -> Interface {
+        let n1 = self.current_ior();
+        let mut refracted = *self;
+
+        let ignored = if self.contains(geometry) {
+            // leaving, only matters if it was the medium we were in
+            let was_current = self.top().is_some_and(|top| top.geometry == geometry);
+            refracted.remove(geometry);
+            !was_current
+        } else {
+            // entering, only matters if it has a high enough priority
+            let entry = MediumEntry {
+                geometry,
+                priority: material.priority,
+                ior,
+                medium: material.medium,
+            };
+            if !refracted.push(entry) {
+                // too deep. the surface still bends the ray, but it stays in the media it was in
+                return Interface {
+                    n1,
+                    n2: ior,
+                    refracted,
+                    ignored: false,
+                };
+            }
+            refracted.top().is_none_or(|top| top.geometry != geometry)
+        };
+
+        Interface {
+            n1,
+            n2: refracted.current_ior(),
+            refracted,
+            ignored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glass(priority: u32, refraction: f32) -> Material {
+        Material {
+            priority,
+            refraction,
+            ..Material::GLASS_MATERIAL
+        }
+    }
+
+    fn id(index: u32) -> GeometryId {
+        GeometryId::from_index(index)
+    }
+
+    #[test]
+    fn color_at_distance() {
+        let color = Rgba::rgb(0.25, 0.5, 1.0);
+        let medium = Medium::from_color_at_distance(color, 10.0);
+        let [r, g, b, _] = transmittance(medium.sigma_t(), 10.0).to_array();
+        assert!((r - 0.25).abs() < 1e-5 && (g - 0.5).abs() < 1e-5 && (b - 1.0).abs() < 1e-5);
+        assert!(!medium.scatters());
+    }
+
+    #[test]
+    fn phase_integrates_to_one() {
+        for g in [-0.5, 0.0, 0.8] {
+            let fog = Medium::fog(0.1, g);
+            // over the sphere, in rings of constant cos
+            let n = 10000;
+            let integral: f32 = (0..n)
+                .map(|i| {
+                    let cos = -1.0 + 2.0 * (i as f32 + 0.5) / n as f32;
+                    fog.phase(cos) * 2.0 * std::f32::consts::PI * 2.0 / n as f32
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 1e-3, "{g}: {integral}");
+        }
+    }
+
+    #[test]
+    fn enter_and_leave() {
+        let air = MediumStack::new(None);
+        let entering = air.cross(id(0), &glass(0, 1.5), None);
+        assert!(!entering.ignored);
+        assert_eq!((entering.n1, entering.n2), (AIR_REFRACT, 1.5));
+
+        let leaving = entering.refracted.cross(id(0), &glass(0, 1.5), None);
+        assert!(!leaving.ignored);
+        assert_eq!((leaving.n1, leaving.n2), (1.5, AIR_REFRACT));
+        assert_eq!(leaving.refracted.len, 0);
+    }
+
+    #[test]
+    fn lower_priority_inside_is_ignored() {
+        // water (priority 1) poured into a glass (priority 0) that overlaps it
+        let water = glass(1, 1.33);
+        let cup = glass(0, 1.5);
+        let in_water = MediumStack::new(None).cross(id(1), &water, None).refracted;
+
+        let cup_wall = in_water.cross(id(0), &cup, None);
+        assert!(cup_wall.ignored);
+        assert_eq!(cup_wall.refracted.current_ior(), 1.33);
+    }
+
+    #[test]
+    fn too_deep_still_refracts() {
+        let mut media = MediumStack::new(None);
+        for i in 0..MAX_NESTED_MEDIA as u32 {
+            media = media.cross(id(i), &glass(0, 1.5), None).refracted;
+        }
+
+        let deepest = media.cross(id(99), &glass(0, 2.0), None);
+        assert!(!deepest.ignored);
+        assert_eq!((deepest.n1, deepest.n2), (1.5, 2.0));
+        assert_eq!(deepest.refracted.len, MAX_NESTED_MEDIA);
+    }
+}
@@ -0,0 +1,409 @@
+use crate::color::Rgba;
+use crate::cornell;
+use crate::geometry::*;
+use crate::hair::Hair;
+use crate::medium::Medium;
+use crate::spectrum::Dispersion;
+use anyhow::*;
+use glam::{Mat4, Quat, UVec3, Vec2, Vec3, Vec4};
+use image::Rgba32FImage;
+use std::ops::Range;
+
+/// The middle of the outdoor scene, what the turntable spins around
+pub const OUTDOOR_CENTER: Vec3 = Vec3::new(280.0, 0.0, 280.0);
+
+/// A few things on a lawn, for the shapes and materials the cornell box does not have.
+/// Same size and place as the box, so the camera presets work for both.
+/// Returns the geometry in the middle, for the turntable
+pub fn outdoor_scene(store: &mut Scene) -> Result<Range<usize>> {
+    let ground = QuadricGeometry::plane(Vec3::ZERO, Vec3::Y);
+    store.add_geometry(Geometry::with_material(
+        ground_material(),
+        GeomInfo::Quadric(ground),
+    ));
+
+    // a column with a square slab on top on the left
+    let column_base = Vec3::new(80.0, 0.0, 350.0);
+    let column_height = 260.0;
+    let column = QuadricGeometry::cylinder(column_base, Vec3::Y * column_height, 35.0);
+    // closed, the glass tiles refract so rays have to get out of it again
+    let column_top = slab(
+        column_base + Vec3::new(-42.0, column_height - 8.0, -42.0),
+        column_base + Vec3::new(42.0, column_height, 42.0),
+    );
+    // checkers that wrap around it, 8 around and 8 up
+    let checker = TextureNode::checker(
+        TextureNode::Constant(Rgba::rgb(0.9, 0.9, 0.85)),
+        TextureNode::Constant(Rgba::rgb(0.1, 0.1, 0.1)),
+    )
+    .scaled(0.125);
+    store.add_geometry(Geometry::with_material(
+        textured(Texture::procedural(checker, TextureSpace::Uv)),
+        GeomInfo::Quadric(column),
+    ));
+    store.add_geometry(Geometry::with_material(
+        tiled_top_material(),
+        GeomInfo::Mesh(column_top),
+    ));
+
+    // a rock on the right: the cube smoothed into a blob, then roughened by a heightmap
+    let (cube_doc, cube_buff, _) =
+        gltf::import("assets/cube.glb").context("Error importing assets/cube.glb")?;
+    let cube = cornell::get_gltf_meshes(&cube_doc, &cube_buff)
+        .context("Error loading assets/cube.glb")?
+        .into_iter()
+        .next()
+        .context("assets/cube.glb has no meshes")?;
+    // the photo is also the heightmap, so the bumps follow what is painted on it.
+    // clamped, so that the edges of the faces do not read the other side of the photo
+    let canyon = store.textures.add_file(
+        "assets/textures/canyon1.jpg",
+        TextureOptions::DEFAULT.with_sampler(Sampler::CLAMP),
+    )?;
+    let mut rock = cube.subdivide(4);
+    rock.displace(store.textures.get(canyon), 0.25);
+    rock.transform(Mat4::from_scale_rotation_translation(
+        Vec3::splat(60.0),
+        Quat::from_rotation_y(0.6),
+        Vec3::new(450.0, 35.0, 200.0),
+    ));
+    store.add_geometry(Geometry::with_material(
+        rock_material(canyon),
+        GeomInfo::Mesh(rock),
+    ));
+
+    // a puff of smoke over the rock, lumpy and thinning out towards the edges
+    let puff = VoxelGrid::from_fn(UVec3::splat(32), |p| {
+        let falloff = (1.0 - (p - 0.5).length() * 2.0).max(0.0);
+        let lumps = (p.x * 19.0).sin() * (p.y * 23.0).sin() * (p.z * 17.0).sin();
+        falloff * (0.8 + 0.2 * lumps)
+    })?;
+    store.add_volume(Volume::new(
+        puff,
+        Vec3::new(410.0, 110.0, 160.0),
+        Vec3::new(500.0, 190.0, 250.0),
+        Medium::fog(0.05, 0.6),
+    ));
+
+    // seeded, so that every frame of an animation has the same grass
+    let mut rng = fastrand::Rng::with_seed(41);
+
+    // a patch of grass in front, flat blades that bend away from the camera
+    let blades: Vec<Vec<Vec4>> = (0..3000)
+        .map(|_| {
+            let base = Vec3::new(130.0 + rng.f32() * 300.0, 0.0, 60.0 + rng.f32() * 100.0);
+            let height = 15.0 + rng.f32() * 20.0;
+            let bend = Vec3::new(rng.f32() - 0.5, 0.0, rng.f32()) * height * 0.4;
+            vec![
+                base.extend(1.0),
+                (base + Vec3::Y * height * 0.6 + bend * 0.3).extend(0.6),
+                (base + Vec3::Y * height + bend).extend(0.1),
+            ]
+        })
+        .collect();
+    let grass = CurveGeometry::from_strands(&blades, CurveBasis::Linear, CurveShape::Flat)?;
+    let grass_material = Material {
+        hair: Some(Hair::from_color(Rgba::rgb(0.25, 0.5, 0.1), 0.5)),
+        ..Material::HAIR_MATERIAL
+    };
+    store.add_geometry(Geometry::with_material(
+        grass_material,
+        GeomInfo::Curves(grass),
+    ));
+
+    // a ball of red hair on the column, made around the origin and moved there
+    let strands: Vec<Vec<Vec4>> = (0..2000)
+        .map(|_| {
+            let dir = loop {
+                let dir = Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - 1.0;
+                if (0.1..1.0).contains(&dir.length_squared()) {
+                    break dir.normalize();
+                }
+            };
+            // the ends droop
+            (0..4)
+                .map(|i| {
+                    let i = i as f32;
+                    (dir * (0.5 + 0.25 * i) + Vec3::NEG_Y * 0.05 * i * i).extend(0.008)
+                })
+                .collect()
+        })
+        .collect();
+    let mut hair_ball =
+        CurveGeometry::from_strands(&strands, CurveBasis::BSpline, CurveShape::Round)?;
+    hair_ball.transform(Mat4::from_scale_rotation_translation(
+        Vec3::splat(40.0),
+        Quat::IDENTITY,
+        column_base + Vec3::Y * (column_height + 30.0),
+    ));
+    let hair_material = Material {
+        hair: Some(Hair::from_melanin(0.3, 2.0).with_roughness(0.25, 0.3)),
+        ..Material::HAIR_MATERIAL
+    };
+    store.add_geometry(Geometry::with_material(
+        hair_material,
+        GeomInfo::Curves(hair_ball),
+    ));
+
+    // colored marbles spiralling up around the egg, the same size so the radius is set after
+    let turns = 3.0;
+    let marbles: Vec<Vec4> = (0..240)
+        .map(|i| {
+            let t = i as f32 / 240.0;
+            let angle = t * turns * std::f32::consts::TAU;
+            let offset = Vec3::new(angle.cos() * 100.0, 10.0 + t * 200.0, angle.sin() * 100.0);
+            (OUTDOOR_CENTER + offset).extend(0.0)
+        })
+        .collect();
+    let rainbow = (0..marbles.len())
+        .map(|i| {
+            let t = i as f32 / marbles.len() as f32 * std::f32::consts::TAU;
+            let channel = |shift: f32| 0.5 + 0.5 * (t + shift).cos();
+            Rgba::rgb(channel(0.0), channel(2.1), channel(4.2))
+        })
+        .collect();
+    let marbles = PointCloud::new(marbles, rainbow, None, PointShape::Sphere)?.with_radius(5.0);
+    // spinning fast enough to streak, a turn is cut into keyframes so that they go around instead of across
+    let spin = (0..=8)
+        .map(|i| {
+            let angle = i as f32 / 8.0 * 0.3;
+            Mat4::from_translation(OUTDOOR_CENTER)
+                * Mat4::from_rotation_y(angle)
+                * Mat4::from_translation(-OUTDOOR_CENTER)
+        })
+        .collect();
+    store.add_geometry(
+        Geometry::with_material(Material::WHITE_MATERIAL, GeomInfo::Points(marbles))
+            .with_motion(Motion::new(spin)?),
+    );
+
+    // fallen leaves lying on the ground around the rock, discs tilted a bit
+    let leaves: Vec<Vec4> = (0..400)
+        .map(|_| {
+            let angle = rng.f32() * std::f32::consts::TAU;
+            let distance = 70.0 + rng.f32() * 60.0;
+            let offset = Vec3::new(angle.cos(), 0.0, angle.sin()) * distance;
+            (Vec3::new(450.0, 0.5, 200.0) + offset).extend(3.0 + rng.f32() * 3.0)
+        })
+        .collect();
+    let autumn = (0..leaves.len())
+        .map(|_| Rgba::rgb(0.5 + rng.f32() * 0.4, 0.15 + rng.f32() * 0.3, 0.05))
+        .collect();
+    let tilts = (0..leaves.len())
+        .map(|_| Vec3::new(rng.f32() - 0.5, 2.0, rng.f32() - 0.5).normalize())
+        .collect();
+    let leaves = PointCloud::new(leaves, autumn, Some(tilts), PointShape::Disc)?;
+    store.add_geometry(Geometry::with_material(
+        Material::WHITE_MATERIAL,
+        GeomInfo::Points(leaves),
+    ));
+
+    // a ball of crown glass in front of the column, splits the sun into colors when rendering spectrally
+    let ball = SphereGeometry {
+        center: Vec3::new(170.0, 30.0, 230.0),
+        radius: 30.0,
+    };
+    let crown_glass = Material {
+        refraction: 1.5168,
+        dispersion: Dispersion::BK7,
+        transmission: Rgba::WHITE,
+        // a little green in the middle, like thick bottle glass
+        medium: Some(Medium::from_color_at_distance(
+            Rgba::rgb(0.75, 0.9, 0.8),
+            60.0,
+        )),
+        ..Material::GLASS_MATERIAL
+    };
+    // rolling towards the rock
+    store.add_geometry(
+        Geometry::with_material(crown_glass, GeomInfo::Sphere(ball))
+            .with_motion(Motion::translation(Vec3::new(6.0, 0.0, 0.0))),
+    );
+
+    // a perforated screen at the back, the holes are cut by the alpha of an image made here
+    let hole = Rgba32FImage::from_fn(64, 64, |x, y| {
+        let d = Vec2::new(x as f32 - 31.5, y as f32 - 31.5).length();
+        image::Rgba([0.3, 0.3, 0.35, if d < 22.0 { 0.0 } else { 1.0 }])
+    });
+    let holes = Sampler::REPEAT.with_transform(UvTransform::tiling(Vec2::new(10.0, 5.0)));
+    let hole = store.textures.add_image(hole, holes);
+    let screen = quad(
+        Vec3::new(130.0, 0.0, 480.0),
+        Vec3::X * 300.0,
+        Vec3::Y * 150.0,
+        Vec2::ONE,
+    );
+    let screen_material = Material {
+        alpha_mode: AlphaMode::Mask(0.5),
+        ..textured(Texture::Image(hole))
+    };
+    store.add_geometry(Geometry::with_material(
+        screen_material,
+        GeomInfo::Mesh(screen),
+    ));
+
+    // a tinted pane between the rock and the egg, rays go through it two times out of three
+    let pane = quad(
+        Vec3::new(360.0, 0.0, 150.0),
+        Vec3::new(0.0, 0.0, 150.0),
+        Vec3::Y * 120.0,
+        Vec2::ONE,
+    );
+    let pane_material = Material {
+        alpha_mode: AlphaMode::Blend,
+        ..textured(Texture::Solid(Rgba::new(0.3, 0.6, 0.9, 0.35)))
+    };
+    store.add_geometry(Geometry::with_material(pane_material, GeomInfo::Mesh(pane)));
+
+    // a round slate under the egg
+    let slate = QuadricGeometry::disk(OUTDOOR_CENTER + Vec3::Y * 0.5, Vec3::Y, 80.0);
+    store.add_geometry(Geometry::with_material(
+        textured(Texture::Solid(Rgba::rgb(0.18, 0.19, 0.22))),
+        GeomInfo::Quadric(slate),
+    ));
+
+    // a marble egg in the middle, the veins turn with it
+    let start = store.geometry.len();
+    let egg =
+        QuadricGeometry::ellipsoid(OUTDOOR_CENTER + Vec3::Y * 90.0, Vec3::new(60.0, 90.0, 60.0));
+    let veins = TextureNode::Turbulence { octaves: 5 }
+        .transformed(Mat4::from_rotation_z(0.7))
+        .scaled(0.6)
+        .remap(0.0, 0.4)
+        .colored(Rgba::rgb(0.2, 0.22, 0.3), Rgba::rgb(0.95, 0.95, 0.92));
+    store.add_geometry(Geometry::with_material(
+        textured(Texture::procedural(veins, TextureSpace::Object)),
+        GeomInfo::Quadric(egg),
+    ));
+    let middle = start..store.geometry.len();
+
+    // a clear afternoon, the sun low enough behind the camera to throw long shadows
+    let sky = Sky::new(35f32.to_radians(), 200f32.to_radians(), 3.0)
+        .with_intensity(0.004)
+        .with_ground(Rgba::rgb(0.25, 0.3, 0.2));
+    store.set_sky(sky);
+
+    Ok(middle)
+}
+
+// two triangles from corner along u and v, the uvs go from 0 to uv_scale
+fn quad(corner: Vec3, u: Vec3, v: Vec3, uv_scale: Vec2) -> MeshGeometry {
+    let mut mesh = MeshGeometry::default();
+    for (x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+        mesh.verts.push((corner + u * x + v * y).into());
+        // v = 0 is the top of the image
+        mesh.tex_coords.push(Vec2::new(x, 1.0 - y) * uv_scale);
+    }
+    mesh.indices.push((0, 1, 2));
+    mesh.indices.push((0, 2, 3));
+    mesh
+}
+
+// a closed box between two corners, every face has the whole texture
+fn slab(min: Vec3, max: Vec3) -> MeshGeometry {
+    let size = max - min;
+    let (x, y, z) = (Vec3::X * size.x, Vec3::Y * size.y, Vec3::Z * size.z);
+    let faces = [
+        (min, x, z),
+        (min + y, x, z),
+        (min, x, y),
+        (min + z, x, y),
+        (min, z, y),
+        (min + x, z, y),
+    ];
+
+    let mut mesh = MeshGeometry::default();
+    for (corner, u, v) in faces {
+        let face = quad(corner, u, v, Vec2::ONE);
+        let offset = mesh.verts.len() as u32;
+        mesh.verts.extend(face.verts);
+        mesh.tex_coords.extend(face.tex_coords);
+        mesh.indices.extend(
+            face.indices
+                .iter()
+                .map(|(a, b, c)| (a + offset, b + offset, c + offset)),
+        );
+    }
+    mesh
+}
+
+fn textured(texture: Texture) -> Material {
+    Material {
+        texture,
+        ..Material::WHITE_MATERIAL
+    }
+}
+
+// paving stones with grout in between, and patches of lawn growing over them
+fn ground_material() -> Material {
+    let stones = TextureNode::Voronoi {
+        jitter: 0.8,
+        output: VoronoiOutput::Cell,
+    }
+    .colored(Rgba::rgb(0.35, 0.33, 0.3), Rgba::rgb(0.6, 0.58, 0.55));
+    let grout = TextureNode::Voronoi {
+        jitter: 0.8,
+        output: VoronoiOutput::Edge,
+    }
+    .remap(0.0, 0.08);
+    let paving = stones.multiply(grout).scaled(40.0);
+    let lawn = TextureNode::Noise { octaves: 4 }
+        .scaled(150.0)
+        .remap(0.55, 0.65);
+    let ground = TextureNode::mix(
+        paving,
+        TextureNode::Constant(Rgba::rgb(0.2, 0.4, 0.1)),
+        lawn,
+    );
+
+    textured(Texture::procedural(ground, TextureSpace::World))
+}
+
+// the photo, pitted, with moss on top
+fn rock_material(image: u32) -> Material {
+    let pits = TextureNode::Voronoi {
+        jitter: 1.0,
+        output: VoronoiOutput::Distance,
+    }
+    .scaled(10.0)
+    .remap(-0.5, 1.0);
+    let stone = TextureNode::Image(image).multiply(pits);
+    // the rock is about 40 to 80 high
+    let moss = TextureNode::Gradient(Vec3::Y * 80.0).remap(0.75, 0.9);
+    let rock = TextureNode::mix(
+        stone,
+        TextureNode::Constant(Rgba::rgb(0.2, 0.35, 0.1)),
+        moss,
+    );
+
+    textured(Texture::procedural(rock, TextureSpace::World))
+}
+
+// squares of brushed metal and clear glass, from one texture with a parameter in every channel
+fn tiled_top_material() -> Material {
+    let metal = TextureNode::Constant(Rgba::new(0.8, 0.3, 1.0, 1.0));
+    let glass = TextureNode::Constant(Rgba::new(0.1, 0.0, 0.0, 0.2));
+    let tiles = TextureNode::checker(
+        TextureNode::Constant(Rgba::BLACK),
+        TextureNode::Constant(Rgba::WHITE),
+    )
+    .scaled(0.25);
+    let packed = Texture::procedural(TextureNode::mix(metal, glass, tiles), TextureSpace::Uv);
+
+    // the constants are all the way up, the maps bring them down
+    Material {
+        reflectivity: 1.0,
+        roughness: 1.0,
+        metallic: 1.0,
+        refraction: 1.5,
+        transmission: Rgba::WHITE,
+        maps: MaterialMaps {
+            reflectivity: Some(ParamMap::new(packed.clone(), Channel::R)),
+            roughness: Some(ParamMap::new(packed.clone(), Channel::G)),
+            metallic: Some(ParamMap::new(packed.clone(), Channel::B)),
+            opacity: Some(ParamMap::new(packed, Channel::A)),
+            ..MaterialMaps::NONE
+        },
+        ..Material::WHITE_MATERIAL
+    }
+}
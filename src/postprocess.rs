@@ -0,0 +1,269 @@
+use crate::tonemap;
+use image::{Rgb, Rgb32FImage};
+use rayon::prelude::*;
+
+/// A single stage of the post processing chain, applied in order to the HDR image
+#[derive(Debug, Clone)]
+pub enum PostProcess {
+    /// scales the image so that the log-average luminance maps to `key` (0.18 is middle grey)
+    AutoExposure {
+        key: f32,
+        min_scale: f32,
+        max_scale: f32,
+    },
+    /// fixed exposure in stops
+    Exposure(f32),
+    /// glare around bright pixels, see `bloom`
+    Bloom(BloomConfig),
+    /// darkens the image towards the corners, using the cos^4 falloff of a real lens
+    Vignette { strength: f32 },
+    /// luminance dependent noise, strongest in the mid tones
+    FilmGrain { strength: f32 },
+    /// compresses the image to [0, 1], should usually be the last stage before grain
+    Tonemap,
+}
+
+#[derive(Debug, Clone)]
+pub struct BloomConfig {
+    /// only luminance above this value contributes to the glare
+    pub threshold: f32,
+    /// how much of the glare is added back to the image
+    pub intensity: f32,
+    /// standard deviation, in pixels, of the smallest gaussian in the point spread function
+    pub radius: f32,
+    /// number of gaussians in the point spread function, each one twice as wide as the last
+    pub octaves: u32,
+}
+
+pub fn apply(image: &mut Rgb32FImage, stages: &[PostProcess]) {
+    for stage in stages {
+        match stage {
+            PostProcess::AutoExposure {
+                key,
+                min_scale,
+                max_scale,
+            } => auto_exposure(image, *key, *min_scale, *max_scale),
+            PostProcess::Exposure(stops) => scale(image, 2.0_f32.powf(*stops)),
+            PostProcess::Bloom(config) => bloom(image, config),
+            PostProcess::Vignette { strength } => vignette(image, *strength),
+            PostProcess::FilmGrain { strength } => film_grain(image, *strength),
+            PostProcess::Tonemap => tonemap::tonemap(image),
+        }
+    }
+}
+
+#[inline]
+fn luminance(pixel: &Rgb<f32>) -> f32 {
+    0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]
+}
+
+fn scale(image: &mut Rgb32FImage, factor: f32) {
+    image.par_pixels_mut().for_each(|pixel| {
+        pixel[0] *= factor;
+        pixel[1] *= factor;
+        pixel[2] *= factor;
+    });
+}
+
+/// log-average luminance, the same "key" estimate used by Reinhard et al.
+pub fn log_average_luminance(image: &Rgb32FImage) -> f32 {
+    // avoids log(0) on black pixels
+    const DELTA: f32 = 1e-4;
+
+    let num_pixels = (image.width() * image.height()) as f64;
+    if num_pixels == 0.0 {
+        return 0.0;
+    }
+
+    let sum: f64 = image
+        .par_pixels()
+        .map(|pixel| (DELTA + luminance(pixel).max(0.0)).ln() as f64)
+        .sum();
+
+    (sum / num_pixels).exp() as f32
+}
+
+fn auto_exposure(image: &mut Rgb32FImage, key: f32, min_scale: f32, max_scale: f32) {
+    let average = log_average_luminance(image);
+    if average > 0.0 {
+        scale(image, (key / average).clamp(min_scale, max_scale));
+    }
+}
+
+// the glare of the eye/lens has a very sharp core and very long tails (Spencer et al. 1995)
+// this is approximated by a sum of gaussians, each one twice as wide and with a quarter of the weight of the last one
+// (so that the falloff is roughly 1 / r^2)
+fn bloom(image: &mut Rgb32FImage, config: &BloomConfig) {
+    let (w, h) = image.dimensions();
+
+    // bright pass
+    let bright: Vec<[f32; 3]> = image
+        .par_pixels()
+        .map(|pixel| {
+            let lum = luminance(pixel);
+            if lum > config.threshold {
+                let k = (lum - config.threshold) / lum;
+                [pixel[0] * k, pixel[1] * k, pixel[2] * k]
+            } else {
+                [0.0; 3]
+            }
+        })
+        .collect();
+
+    let mut glare = vec![[0.0_f32; 3]; bright.len()];
+    let mut sigma = config.radius;
+    let mut weight = 1.0;
+    let mut total_weight = 0.0;
+
+    for _ in 0..config.octaves {
+        let blurred = gaussian_blur(&bright, w as usize, h as usize, sigma);
+        glare
+            .par_iter_mut()
+            .zip(blurred.par_iter())
+            .for_each(|(acc, b)| {
+                acc[0] += b[0] * weight;
+                acc[1] += b[1] * weight;
+                acc[2] += b[2] * weight;
+            });
+
+        total_weight += weight;
+        sigma *= 2.0;
+        weight *= 0.25;
+    }
+
+    if total_weight <= 0.0 {
+        return;
+    }
+    let k = config.intensity / total_weight;
+
+    image
+        .par_pixels_mut()
+        .zip(glare.par_iter())
+        .for_each(|(pixel, g)| {
+            pixel[0] += g[0] * k;
+            pixel[1] += g[1] * k;
+            pixel[2] += g[2] * k;
+        });
+}
+
+// separable blur, horizontal then vertical. edges are clamped
+fn gaussian_blur(src: &[[f32; 3]], w: usize, h: usize, sigma: f32) -> Vec<[f32; 3]> {
+    if w == 0 || h == 0 {
+        return Vec::new();
+    }
+    let radius = (sigma * 3.0).ceil() as isize;
+    let kernel: Vec<f32> = {
+        let raw: Vec<f32> = (-radius..=radius)
+            .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let sum: f32 = raw.iter().sum();
+        raw.into_iter().map(|k| k / sum).collect()
+    };
+
+    let blur_1d = |get: &dyn Fn(isize) -> [f32; 3]| -> [f32; 3] {
+        let mut acc = [0.0_f32; 3];
+        for (i, k) in (-radius..=radius).zip(kernel.iter()) {
+            let p = get(i);
+            acc[0] += p[0] * k;
+            acc[1] += p[1] * k;
+            acc[2] += p[2] * k;
+        }
+        acc
+    };
+
+    let mut horizontal = vec![[0.0_f32; 3]; src.len()];
+    horizontal
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = blur_1d(&|i| {
+                    let sx = (x as isize + i).clamp(0, w as isize - 1) as usize;
+                    src[y * w + sx]
+                });
+            }
+        });
+
+    let mut vertical = vec![[0.0_f32; 3]; src.len()];
+    vertical.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = blur_1d(&|i| {
+                let sy = (y as isize + i).clamp(0, h as isize - 1) as usize;
+                horizontal[sy * w + x]
+            });
+        }
+    });
+
+    vertical
+}
+
+fn vignette(image: &mut Rgb32FImage, strength: f32) {
+    let (w, h) = image.dimensions();
+    let half_w = w as f32 / 2.0;
+    let half_h = h as f32 / 2.0;
+    let half_diagonal = (half_w * half_w + half_h * half_h).sqrt();
+
+    image.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        let dx = (x as f32 + 0.5 - half_w) / half_diagonal;
+        let dy = (y as f32 + 0.5 - half_h) / half_diagonal;
+        // angle to the optical axis, as if the corners were at 45 degrees
+        let cos_theta = 1.0 / (1.0 + dx * dx + dy * dy).sqrt();
+        let falloff = cos_theta.powi(4);
+        let factor = 1.0 - strength + strength * falloff;
+
+        pixel[0] *= factor;
+        pixel[1] *= factor;
+        pixel[2] *= factor;
+    });
+}
+
+fn film_grain(image: &mut Rgb32FImage, strength: f32) {
+    image.par_pixels_mut().for_each(|pixel| {
+        let lum = luminance(pixel).clamp(0.0, 1.0);
+        // grain is most visible in the mid tones, fades in the shadows and highlights
+        let amount = strength * 4.0 * lum * (1.0 - lum);
+        let noise = (fastrand::f32() + fastrand::f32() - 1.0) * amount;
+
+        pixel[0] = (pixel[0] + noise).max(0.0);
+        pixel[1] = (pixel[1] + noise).max(0.0);
+        pixel[2] = (pixel[2] + noise).max(0.0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::PostProcessConfig;
+
+    #[test]
+    fn exposure_in_stops() {
+        let mut image = Rgb32FImage::from_pixel(2, 2, Rgb([0.25, 0.5, 1.0]));
+        apply(&mut image, &[PostProcess::Exposure(2.0)]);
+        assert_eq!(image.get_pixel(1, 1).0, [1.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn log_average_of_a_flat_image() {
+        let image = Rgb32FImage::from_pixel(3, 2, Rgb([0.5, 0.5, 0.5]));
+        assert!((log_average_luminance(&image) - 0.5).abs() < 1e-3);
+        assert_eq!(log_average_luminance(&Rgb32FImage::new(0, 0)), 0.0);
+    }
+
+    #[test]
+    fn blur_keeps_the_energy_of_a_flat_image() {
+        let src = vec![[1.0, 2.0, 3.0]; 12];
+        for pixel in gaussian_blur(&src, 4, 3, 1.5) {
+            for (c, expected) in pixel.iter().zip([1.0, 2.0, 3.0]) {
+                assert!((c - expected).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_images_go_through_every_stage() {
+        let mut image = Rgb32FImage::new(0, 0);
+        apply(&mut image, &PostProcessConfig::cinematic().stages);
+        let mut image = Rgb32FImage::new(4, 0);
+        apply(&mut image, &PostProcessConfig::cinematic().stages);
+    }
+}
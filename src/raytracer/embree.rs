@@ -1,12 +1,40 @@
-use crate::geometry::{GeomInfo, Geometry};
+use crate::geometry::{
+    AlphaMask, CurveBasis, CurveGeometry, CurveShape, GeomInfo, Geometry, MeshGeometry,
+    MeshInstance, Motion, PointCloud, PointShape, QuadricGeometry, SphereGeometry,
+};
 use crate::raytracer::{GeometryId, Ray, RayHitResult, RayTracer, RayTracerBuilder};
-use embree4_rs::geometry::SphereGeometry;
-use embree4_sys::{RTCRay, RTCRayHit};
-use glam::Vec3;
+use anyhow::{Result, bail};
+use embree4_rs::geometry::Geometry as EmbreeGeometry;
+use embree4_sys::{
+    RTC_INVALID_GEOMETRY_ID, RTCBounds, RTCBoundsFunctionArguments, RTCBufferType,
+    RTCFilterFunctionNArguments, RTCFormat, RTCGeometry, RTCGeometryType, RTCHit,
+    RTCIntersectFunctionNArguments, RTCOccludedFunctionNArguments, RTCRay, RTCRayHit, RTCScene,
+    rtcAttachGeometry, rtcCommitGeometry, rtcCommitScene, rtcNewGeometry, rtcNewScene,
+    rtcReleaseGeometry, rtcReleaseScene, rtcSetGeometryBoundsFunction,
+    rtcSetGeometryInstancedScene, rtcSetGeometryIntersectFilterFunction,
+    rtcSetGeometryIntersectFunction, rtcSetGeometryOccludedFilterFunction,
+    rtcSetGeometryOccludedFunction, rtcSetGeometryTimeStepCount, rtcSetGeometryTransform,
+    rtcSetGeometryUserData, rtcSetGeometryUserPrimitiveCount, rtcSetNewGeometryBuffer,
+};
+use fxhash::FxHashMap;
+use glam::{Mat3, Mat4, Vec3};
+use std::ops::{Add, Mul};
+use std::sync::Arc;
 
 pub struct EmbreeRayTracerBuilder<'a> {
     scene: embree4_rs::Scene<'a>,
     device: &'a embree4_rs::Device,
+    // one embree scene per shared mesh, no matter how many times it is instanced
+    prototypes: FxHashMap<*const MeshGeometry, RTCScene>,
+    // normal matrix of every instance, one per motion keyframe
+    instance_normals: FxHashMap<u32, Vec<Mat3>>,
+    // embree keeps pointers to these for the callbacks, the boxes keep them from moving
+    #[allow(clippy::vec_box)]
+    quadrics: Vec<Box<QuadricData>>,
+    #[allow(clippy::vec_box)]
+    alpha_masks: Vec<Box<AlphaMask>>,
+    // prototypes of instances with alpha masks, the mask depends on the material so they are not shared
+    masked_prototypes: Vec<RTCScene>,
 }
 
 impl<'a> EmbreeRayTracerBuilder<'a> {
@@ -20,12 +48,188 @@ impl<'a> EmbreeRayTracerBuilder<'a> {
         )
         .unwrap();
 
-        EmbreeRayTracerBuilder { scene, device }
+        EmbreeRayTracerBuilder {
+            scene,
+            device,
+            prototypes: FxHashMap::default(),
+            instance_normals: FxHashMap::default(),
+            quadrics: Vec::new(),
+            alpha_masks: Vec::new(),
+            masked_prototypes: Vec::new(),
+        }
+    }
+
+    // the Arc keeps the mesh alive while it is in the scene, so its address identifies it
+    fn prototype(
+        &mut self,
+        mesh: &Arc<MeshGeometry>,
+        alpha: Option<AlphaMask>,
+    ) -> Result<RTCScene> {
+        if let Some(alpha) = alpha {
+            let embree_mesh = RawGeometry::mesh(self.device, mesh, &[Mat4::IDENTITY])?;
+            self.set_alpha_mask(&embree_mesh, alpha);
+            let prototype = Self::new_prototype(self.device, &embree_mesh)?;
+            self.masked_prototypes.push(prototype);
+            return Ok(prototype);
+        }
+
+        let key = Arc::as_ptr(mesh);
+        if let Some(prototype) = self.prototypes.get(&key) {
+            return Ok(*prototype);
+        }
+
+        let embree_mesh = embree4_rs::geometry::TriangleMeshGeometry::try_new(
+            self.device,
+            &mesh.verts,
+            &mesh.indices,
+        )?;
+        let prototype = Self::new_prototype(self.device, &embree_mesh)?;
+
+        self.prototypes.insert(key, prototype);
+        Ok(prototype)
+    }
+
+    fn new_prototype(device: &embree4_rs::Device, mesh: &impl EmbreeGeometry) -> Result<RTCScene> {
+        let prototype = unsafe { rtcNewScene(device.handle()) };
+        if prototype.is_null() {
+            bail!("Could not create embree scene for instanced mesh");
+        }
+        // the scene keeps its own reference to the geometry
+        unsafe {
+            rtcAttachGeometry(prototype, mesh.geometry());
+            rtcCommitScene(prototype);
+        }
+        Ok(prototype)
+    }
+
+    // embree keeps a pointer to the mask, so the builder has to keep it alive
+    fn set_alpha_mask(&mut self, geometry: &RawGeometry, alpha: AlphaMask) {
+        let alpha = Box::new(alpha);
+        geometry.set_alpha_mask(&alpha);
+        self.alpha_masks.push(alpha);
+    }
+
+    fn add_instance(
+        &mut self,
+        instance: &MeshInstance,
+        motion: Option<&Motion>,
+        alpha: Option<AlphaMask>,
+    ) -> Result<u32> {
+        let prototype = self.prototype(&instance.mesh, alpha)?;
+        let transforms: Vec<Mat4> = match motion {
+            Some(motion) => motion
+                .keyframes
+                .iter()
+                .map(|keyframe| *keyframe * instance.transform)
+                .collect(),
+            None => vec![instance.transform],
+        };
+
+        let embree_geom = RawGeometry::instance(self.device, prototype, &transforms)?;
+        let id = self.scene.attach_geometry(&embree_geom)?;
+
+        // embree gives the normal of instance hits in the space of the mesh
+        let normals = transforms
+            .iter()
+            .map(|transform| Mat3::from_mat4(*transform).inverse().transpose())
+            .collect();
+        self.instance_normals.insert(id, normals);
+
+        Ok(id)
+    }
+
+    fn add_quadric(&mut self, quadric: &QuadricGeometry, motion: Option<&Motion>) -> Result<u32> {
+        let transforms = match motion {
+            Some(motion) => motion
+                .keyframes
+                .iter()
+                .map(|keyframe| *keyframe * quadric.transform)
+                .collect(),
+            None => vec![quadric.transform],
+        };
+        let data = Box::new(QuadricData {
+            quadric: quadric.clone(),
+            transforms,
+        });
+
+        let embree_geom = RawGeometry::quadric(self.device, &data)?;
+        let id = self.scene.attach_geometry(&embree_geom)?;
+        self.quadrics.push(data);
+
+        Ok(id)
+    }
+}
+
+impl Drop for EmbreeRayTracerBuilder<'_> {
+    fn drop(&mut self) {
+        // instances keep their own reference to the prototype
+        for prototype in self.prototypes.values().chain(&self.masked_prototypes) {
+            unsafe { rtcReleaseScene(*prototype) };
+        }
     }
 }
 
 impl RayTracerBuilder for EmbreeRayTracerBuilder<'_> {
-    fn add_geometry(&mut self, geometry: &Geometry) -> anyhow::Result<GeometryId> {
+    fn add_geometry(
+        &mut self,
+        geometry: &Geometry,
+        alpha: Option<AlphaMask>,
+    ) -> anyhow::Result<GeometryId> {
+        // the filter needs barycentrics and triangles to find the uvs
+        if alpha.is_some() && !matches!(geometry.info, GeomInfo::Mesh(_) | GeomInfo::Instance(_)) {
+            bail!("Only meshes and instances can have an alpha mask");
+        }
+
+        match &geometry.info {
+            GeomInfo::Instance(instance) => {
+                return Ok(GeometryId(self.add_instance(
+                    instance,
+                    geometry.motion.as_ref(),
+                    alpha,
+                )?));
+            }
+            // the wrapper of embree4-rs can not take a filter, these go through the raw API
+            GeomInfo::Mesh(mesh) if alpha.is_some() => {
+                let keyframes = match &geometry.motion {
+                    Some(motion) => &motion.keyframes[..],
+                    None => &[Mat4::IDENTITY],
+                };
+                let embree_geom = RawGeometry::mesh(self.device, mesh, keyframes)?;
+                self.set_alpha_mask(&embree_geom, alpha.expect("Checked above"));
+                return Ok(GeometryId(self.scene.attach_geometry(&embree_geom)?));
+            }
+            GeomInfo::Quadric(quadric) => {
+                return Ok(GeometryId(
+                    self.add_quadric(quadric, geometry.motion.as_ref())?,
+                ));
+            }
+            GeomInfo::Curves(curves) => {
+                let embree_geom =
+                    RawGeometry::curves(self.device, curves, geometry.motion.as_ref())?;
+                return Ok(GeometryId(self.scene.attach_geometry(&embree_geom)?));
+            }
+            GeomInfo::Points(cloud) => {
+                let embree_geom =
+                    RawGeometry::points(self.device, cloud, geometry.motion.as_ref())?;
+                return Ok(GeometryId(self.scene.attach_geometry(&embree_geom)?));
+            }
+            _ => {}
+        }
+
+        if let Some(motion) = &geometry.motion {
+            let embree_geom = match &geometry.info {
+                GeomInfo::Mesh(mesh) => RawGeometry::mesh(self.device, mesh, &motion.keyframes)?,
+                GeomInfo::Sphere(sphere) => RawGeometry::sphere(self.device, sphere, motion)?,
+                GeomInfo::Instance(_)
+                | GeomInfo::Quadric(_)
+                | GeomInfo::Curves(_)
+                | GeomInfo::Points(_) => {
+                    unreachable!("Added above")
+                }
+            };
+            return Ok(GeometryId(self.scene.attach_geometry(&embree_geom)?));
+        }
+
         Ok(GeometryId(match &geometry.info {
             GeomInfo::Mesh(mesh) => {
                 let embree_mesh = embree4_rs::geometry::TriangleMeshGeometry::try_new(
@@ -36,24 +240,421 @@ impl RayTracerBuilder for EmbreeRayTracerBuilder<'_> {
                 self.scene.attach_geometry(&embree_mesh)?
             }
             GeomInfo::Sphere(sphere) => {
-                let embree_geom = SphereGeometry::try_new(
+                let embree_geom = embree4_rs::geometry::SphereGeometry::try_new(
                     &self.device,
                     (sphere.center.x, sphere.center.y, sphere.center.z),
                     sphere.radius,
                 )?;
                 self.scene.attach_geometry(&embree_geom)?
             }
+            GeomInfo::Instance(_)
+            | GeomInfo::Quadric(_)
+            | GeomInfo::Curves(_)
+            | GeomInfo::Points(_) => {
+                unreachable!("Added above")
+            }
         }))
     }
 
     fn build(&self) -> anyhow::Result<impl RayTracer> {
         let committed_scene = self.scene.commit()?;
-        Ok(EmbreeRayTracer { committed_scene })
+        Ok(EmbreeRayTracer {
+            committed_scene,
+            instance_normals: self.instance_normals.clone(),
+        })
     }
 }
 
+/// Geometry that embree4-rs does not wrap (moving geometry, instances and user geometry), made with the raw API.
+/// Moving geometry has one vertex buffer per keyframe, embree interpolates between them using the time of the ray
+struct RawGeometry {
+    handle: RTCGeometry,
+}
+
+impl RawGeometry {
+    // one keyframe is a mesh that does not move
+    fn mesh(device: &embree4_rs::Device, mesh: &MeshGeometry, keyframes: &[Mat4]) -> Result<Self> {
+        let geometry = Self::new(device, RTCGeometryType::TRIANGLE, keyframes.len() as u32)?;
+
+        for (step, matrix) in keyframes.iter().enumerate() {
+            let vertices: &mut [[f32; 3]] = geometry.new_buffer(
+                RTCBufferType::VERTEX,
+                step as u32,
+                RTCFormat::FLOAT3,
+                mesh.verts.len(),
+            )?;
+            for (vertex, pos) in vertices.iter_mut().zip(&mesh.verts) {
+                *vertex = matrix.transform_point3(Vec3::from(*pos)).to_array();
+            }
+        }
+
+        let indices: &mut [[u32; 3]] = geometry.new_buffer(
+            RTCBufferType::INDEX,
+            0,
+            RTCFormat::UINT3,
+            mesh.indices.len(),
+        )?;
+        for (index, triangle) in indices.iter_mut().zip(&mesh.indices) {
+            *index = [triangle.0, triangle.1, triangle.2];
+        }
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    // only the center moves, the radius stays the same
+    fn sphere(
+        device: &embree4_rs::Device,
+        sphere: &SphereGeometry,
+        motion: &Motion,
+    ) -> Result<Self> {
+        let geometry = Self::new(
+            device,
+            RTCGeometryType::SPHERE_POINT,
+            motion.keyframes.len() as u32,
+        )?;
+
+        for (step, matrix) in motion.keyframes.iter().enumerate() {
+            let points: &mut [[f32; 4]] =
+                geometry.new_buffer(RTCBufferType::VERTEX, step as u32, RTCFormat::FLOAT4, 1)?;
+            points[0] = matrix
+                .transform_point3(sphere.center)
+                .extend(sphere.radius)
+                .to_array();
+        }
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    // static curves too, embree4-rs has no curve geometry
+    fn curves(
+        device: &embree4_rs::Device,
+        curves: &CurveGeometry,
+        motion: Option<&Motion>,
+    ) -> Result<Self> {
+        let geometry_type = match (curves.basis, curves.shape) {
+            (CurveBasis::Linear, CurveShape::Flat) => RTCGeometryType::FLAT_LINEAR_CURVE,
+            (CurveBasis::Linear, CurveShape::Round) => RTCGeometryType::ROUND_LINEAR_CURVE,
+            (CurveBasis::BSpline, CurveShape::Flat) => RTCGeometryType::FLAT_BSPLINE_CURVE,
+            (CurveBasis::BSpline, CurveShape::Round) => RTCGeometryType::ROUND_BSPLINE_CURVE,
+        };
+        let keyframes = motion.map_or(&[Mat4::IDENTITY][..], |motion| &motion.keyframes);
+        let geometry = Self::new(device, geometry_type, keyframes.len() as u32)?;
+
+        for (step, matrix) in keyframes.iter().enumerate() {
+            let points: &mut [[f32; 4]] = geometry.new_buffer(
+                RTCBufferType::VERTEX,
+                step as u32,
+                RTCFormat::FLOAT4,
+                curves.points.len(),
+            )?;
+            for (point, control) in points.iter_mut().zip(&curves.points) {
+                *point = matrix
+                    .transform_point3(control.truncate())
+                    .extend(control.w)
+                    .to_array();
+            }
+        }
+
+        let indices: &mut [u32] = geometry.new_buffer(
+            RTCBufferType::INDEX,
+            0,
+            RTCFormat::UINT,
+            curves.segments.len(),
+        )?;
+        indices.copy_from_slice(&curves.segments);
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    // the primitive id of a hit is the index of the point
+    fn points(
+        device: &embree4_rs::Device,
+        cloud: &PointCloud,
+        motion: Option<&Motion>,
+    ) -> Result<Self> {
+        let geometry_type = match (cloud.shape, &cloud.normals) {
+            (PointShape::Sphere, _) => RTCGeometryType::SPHERE_POINT,
+            (PointShape::Disc, None) => RTCGeometryType::DISC_POINT,
+            (PointShape::Disc, Some(_)) => RTCGeometryType::ORIENTED_DISC_POINT,
+        };
+        let keyframes = motion.map_or(&[Mat4::IDENTITY][..], |motion| &motion.keyframes);
+        let geometry = Self::new(device, geometry_type, keyframes.len() as u32)?;
+
+        for (step, matrix) in keyframes.iter().enumerate() {
+            let points: &mut [[f32; 4]] = geometry.new_buffer(
+                RTCBufferType::VERTEX,
+                step as u32,
+                RTCFormat::FLOAT4,
+                cloud.points.len(),
+            )?;
+            for (point, source) in points.iter_mut().zip(&cloud.points) {
+                *point = matrix
+                    .transform_point3(source.truncate())
+                    .extend(source.w)
+                    .to_array();
+            }
+
+            // oriented discs
+            if cloud.shape == PointShape::Disc
+                && let Some(normals) = &cloud.normals
+            {
+                let normal_matrix = Mat3::from_mat4(*matrix).inverse().transpose();
+                let buffer: &mut [[f32; 3]] = geometry.new_buffer(
+                    RTCBufferType::NORMAL,
+                    step as u32,
+                    RTCFormat::FLOAT3,
+                    normals.len(),
+                )?;
+                for (dst, normal) in buffer.iter_mut().zip(normals) {
+                    *dst = (normal_matrix * *normal).normalize_or_zero().to_array();
+                }
+            }
+        }
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    // one transform per time step, embree interpolates them like the vertices of moving meshes
+    fn instance(
+        device: &embree4_rs::Device,
+        prototype: RTCScene,
+        transforms: &[Mat4],
+    ) -> Result<Self> {
+        let geometry = Self::new(device, RTCGeometryType::INSTANCE, transforms.len() as u32)?;
+
+        unsafe { rtcSetGeometryInstancedScene(geometry.handle, prototype) };
+        for (step, transform) in transforms.iter().enumerate() {
+            let columns = transform.to_cols_array();
+            unsafe {
+                rtcSetGeometryTransform(
+                    geometry.handle,
+                    step as u32,
+                    RTCFormat::FLOAT4X4_COLUMN_MAJOR,
+                    columns.as_ptr().cast(),
+                )
+            };
+        }
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    // a single primitive, intersected by the callbacks below
+    fn quadric(device: &embree4_rs::Device, data: &QuadricData) -> Result<Self> {
+        let geometry = Self::new(device, RTCGeometryType::USER, data.transforms.len() as u32)?;
+
+        unsafe {
+            rtcSetGeometryUserPrimitiveCount(geometry.handle, 1);
+            rtcSetGeometryUserData(geometry.handle, data as *const QuadricData as *mut _);
+            rtcSetGeometryBoundsFunction(
+                geometry.handle,
+                Some(quadric_bounds),
+                std::ptr::null_mut(),
+            );
+            rtcSetGeometryIntersectFunction(geometry.handle, Some(quadric_intersect));
+            rtcSetGeometryOccludedFunction(geometry.handle, Some(quadric_occluded));
+        }
+
+        geometry.commit();
+        Ok(geometry)
+    }
+
+    fn new(
+        device: &embree4_rs::Device,
+        geometry_type: RTCGeometryType,
+        time_steps: u32,
+    ) -> Result<Self> {
+        let handle = unsafe { rtcNewGeometry(device.handle(), geometry_type) };
+        if handle.is_null() {
+            bail!("Could not create embree geometry");
+        }
+        unsafe { rtcSetGeometryTimeStepCount(handle, time_steps) };
+
+        Ok(Self { handle })
+    }
+
+    // T has to match the format, embree owns the memory and keeps it alive as long as the geometry
+    #[allow(clippy::mut_from_ref)]
+    fn new_buffer<T>(
+        &self,
+        buffer_type: RTCBufferType,
+        slot: u32,
+        format: RTCFormat,
+        count: usize,
+    ) -> Result<&mut [T]> {
+        let ptr = unsafe {
+            rtcSetNewGeometryBuffer(
+                self.handle,
+                buffer_type,
+                slot,
+                format,
+                size_of::<T>(),
+                count,
+            )
+        };
+        if ptr.is_null() {
+            bail!("Could not allocate embree buffer of {count} elements");
+        }
+
+        Ok(unsafe { std::slice::from_raw_parts_mut(ptr as *mut T, count) })
+    }
+
+    fn commit(&self) {
+        unsafe { rtcCommitGeometry(self.handle) };
+    }
+
+    // the filter runs for every candidate hit, of closest hit and shadow rays alike
+    fn set_alpha_mask(&self, mask: &AlphaMask) {
+        unsafe {
+            rtcSetGeometryUserData(self.handle, mask as *const AlphaMask as *mut _);
+            rtcSetGeometryIntersectFilterFunction(self.handle, Some(alpha_filter));
+            rtcSetGeometryOccludedFilterFunction(self.handle, Some(alpha_filter));
+        }
+        self.commit();
+    }
+}
+
+impl EmbreeGeometry for RawGeometry {
+    fn geometry(&self) -> RTCGeometry {
+        self.handle
+    }
+}
+
+impl Drop for RawGeometry {
+    fn drop(&mut self) {
+        unsafe { rtcReleaseGeometry(self.handle) };
+    }
+}
+
+struct QuadricData {
+    quadric: QuadricGeometry,
+    // one per motion keyframe
+    transforms: Vec<Mat4>,
+}
+
+impl QuadricData {
+    // the hit in the space of the shape, and the transform at the time of the ray
+    fn intersect(&self, ray: &RTCRay) -> Option<(crate::geometry::QuadricHit, Mat4)> {
+        let matrix = interpolate_steps(&self.transforms, ray.time);
+        let inverse = matrix.inverse();
+        let origin = inverse.transform_point3(Vec3::new(ray.org_x, ray.org_y, ray.org_z));
+        let dir = inverse.transform_vector3(Vec3::new(ray.dir_x, ray.dir_y, ray.dir_z));
+
+        self.quadric
+            .intersect_local(origin, dir, ray.tnear, ray.tfar)
+            .map(|hit| (hit, matrix))
+    }
+}
+
+unsafe extern "C" fn quadric_bounds(args: *const RTCBoundsFunctionArguments) {
+    let args = unsafe { &*args };
+    let data = unsafe { &*(args.geometryUserPtr as *const QuadricData) };
+
+    let (lower, upper) = data.quadric.bounds(data.transforms[args.timeStep as usize]);
+    unsafe {
+        *args.bounds_o = RTCBounds {
+            lower_x: lower.x,
+            lower_y: lower.y,
+            lower_z: lower.z,
+            align0: 0.0,
+            upper_x: upper.x,
+            upper_y: upper.y,
+            upper_z: upper.z,
+            align1: 0.0,
+        }
+    };
+}
+
+// only rtcIntersect1 is used, so there is always a single ray
+unsafe extern "C" fn quadric_intersect(args: *const RTCIntersectFunctionNArguments) {
+    let args = unsafe { &*args };
+    debug_assert_eq!(args.N, 1, "Quadrics only support single rays");
+    if unsafe { *args.valid } == 0 {
+        return;
+    }
+    let data = unsafe { &*(args.geometryUserPtr as *const QuadricData) };
+    let rayhit = unsafe { &mut *(args.rayhit as *mut RTCRayHit) };
+
+    let Some((hit, matrix)) = data.intersect(&rayhit.ray) else {
+        return;
+    };
+    let normal = QuadricGeometry::normal_matrix(matrix) * hit.normal;
+
+    rayhit.ray.tfar = hit.t;
+    rayhit.hit.Ng_x = normal.x;
+    rayhit.hit.Ng_y = normal.y;
+    rayhit.hit.Ng_z = normal.z;
+    rayhit.hit.u = hit.uv.x;
+    rayhit.hit.v = hit.uv.y;
+    rayhit.hit.primID = args.primID;
+    rayhit.hit.geomID = args.geomID;
+    rayhit.hit.instID = unsafe { (*args.context).instID };
+}
+
+unsafe extern "C" fn quadric_occluded(args: *const RTCOccludedFunctionNArguments) {
+    let args = unsafe { &*args };
+    debug_assert_eq!(args.N, 1, "Quadrics only support single rays");
+    if unsafe { *args.valid } == 0 {
+        return;
+    }
+    let data = unsafe { &*(args.geometryUserPtr as *const QuadricData) };
+    let ray = unsafe { &mut *(args.ray as *mut RTCRay) };
+
+    // embree marks occluded rays this way
+    if data.intersect(ray).is_some() {
+        ray.tfar = f32::NEG_INFINITY;
+    }
+}
+
+// rejecting a hit makes embree keep looking further along the ray
+unsafe extern "C" fn alpha_filter(args: *const RTCFilterFunctionNArguments) {
+    let args = unsafe { &*args };
+    // rays are traced one at a time, a single hit is laid out like RTCHit
+    if args.N != 1 {
+        return;
+    }
+    let valid = unsafe { &mut *args.valid };
+    if *valid == 0 {
+        return;
+    }
+
+    let mask = unsafe { &*(args.geometryUserPtr as *const AlphaMask) };
+    let hit = unsafe { &*(args.hit as *const RTCHit) };
+    if !mask.passes(hit.primID, hit.u, hit.v) {
+        *valid = 0;
+    }
+}
+
+// moving geometry has one value per keyframe, spread evenly over the frame
+fn interpolate_steps<T>(steps: &[T], time: f32) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    if steps.len() == 1 {
+        return steps[0];
+    }
+
+    let t = time.clamp(0.0, 1.0) * (steps.len() - 1) as f32;
+    let step = (t as usize).min(steps.len() - 2);
+    let f = t - step as f32;
+    steps[step] * (1.0 - f) + steps[step + 1] * f
+}
+
 pub struct EmbreeRayTracer<'a> {
     committed_scene: embree4_rs::CommittedScene<'a>,
+    instance_normals: FxHashMap<u32, Vec<Mat3>>,
+}
+
+impl EmbreeRayTracer<'_> {
+    // moving instances interpolate their normal matrix like embree does their transform
+    fn instance_normal_matrix(&self, inst_id: u32, time: f32) -> Option<Mat3> {
+        let normals = self.instance_normals.get(&inst_id)?;
+        Some(interpolate_steps(normals, time))
+    }
 }
 
 impl Into<RTCRay> for Ray {
@@ -66,6 +667,7 @@ impl Into<RTCRay> for Ray {
             dir_y: self.direction.y,
             dir_z: self.direction.z,
             tfar: self.max_distance,
+            time: self.time,
             ..Default::default()
         }
     }
@@ -81,7 +683,12 @@ impl Into<RayHitResult> for RTCRayHit {
             normal: Vec3::new(self.hit.Ng_x, self.hit.Ng_y, self.hit.Ng_z).normalize(),
             u: self.hit.u,
             v: self.hit.v,
-            geometry_id: GeometryId(self.hit.geomID),
+            // hits on instances are reported with the id of the mesh inside the prototype scene
+            geometry_id: GeometryId(if self.hit.instID[0] != RTC_INVALID_GEOMETRY_ID {
+                self.hit.instID[0]
+            } else {
+                self.hit.geomID
+            }),
             triangle_id: self.hit.primID,
         }
     }
@@ -89,9 +696,16 @@ impl Into<RayHitResult> for RTCRayHit {
 
 impl RayTracer for EmbreeRayTracer<'_> {
     fn intersect(&self, ray: Ray) -> Option<RayHitResult> {
-        self.committed_scene
+        let hit = self
+            .committed_scene
             .intersect_1(ray.into())
-            .expect("Device error while intersecting ray")
-            .map(Into::into)
+            .expect("Device error while intersecting ray")?;
+
+        let mut result: RayHitResult = hit.into();
+        if let Some(normal_matrix) = self.instance_normal_matrix(hit.hit.instID[0], ray.time) {
+            let normal = Vec3::new(hit.hit.Ng_x, hit.hit.Ng_y, hit.hit.Ng_z);
+            result.normal = (normal_matrix * normal).normalize();
+        }
+        Some(result)
     }
 }
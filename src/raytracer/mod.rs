@@ -7,6 +7,35 @@ pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
     pub max_distance: f32,
+    /// when the ray is shot, in [0, 1] over the frame. moving geometry is intersected where it is at this time
+    pub time: f32,
+    /// how wide the ray is, for texture filtering
+    pub cone: RayCone,
+}
+
+/// Ray cone tracing (Akenine-Möller et al.), a cheaper stand-in for ray differentials.
+/// The ray is a cone `width` wide at its origin that grows by `spread` per unit of distance
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RayCone {
+    pub width: f32,
+    /// the angle of the cone, in radians (small angle approximation)
+    pub spread: f32,
+}
+
+impl RayCone {
+    pub fn new(width: f32, spread: f32) -> Self {
+        Self { width, spread }
+    }
+
+    pub fn width_at(&self, distance: f32) -> f32 {
+        self.width + self.spread * distance
+    }
+
+    /// the same cone starting `distance` further along, for rays bouncing off a hit.
+    /// curvature is ignored, so the cone of a reflection off a curved mirror is too narrow
+    pub fn advance(self, distance: f32) -> Self {
+        Self::new(self.width_at(distance), self.spread)
+    }
 }
 
 pub struct RayHitResult {
@@ -30,15 +59,41 @@ impl Ray {
             origin,
             direction,
             max_distance,
+            time: 0.0,
+            cone: RayCone::default(),
         }
     }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_cone(mut self, cone: RayCone) -> Self {
+        self.cone = cone;
+        self
+    }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+/// handed out in the order geometry is added to the builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct GeometryId(u32);
 
+#[cfg(test)]
+impl GeometryId {
+    /// for tests of code that keeps ids, without building a scene
+    pub fn from_index(index: u32) -> Self {
+        Self(index)
+    }
+}
+
 pub trait RayTracerBuilder {
-    fn add_geometry(&mut self, geometry: &crate::geometry::Geometry) -> anyhow::Result<GeometryId>;
+    /// hits the alpha mask rejects are skipped, by every kind of ray
+    fn add_geometry(
+        &mut self,
+        geometry: &crate::geometry::Geometry,
+        alpha: Option<crate::geometry::AlphaMask>,
+    ) -> anyhow::Result<GeometryId>;
     fn build(&self) -> anyhow::Result<impl RayTracer>;
 }
 
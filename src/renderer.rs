@@ -1,9 +1,15 @@
 use crate::camera::Camera;
 use crate::color::Rgba;
-use crate::common::compute_reflection_coeff;
+use crate::common::{compute_reflection_coeff, rand_dir, randu32};
 use crate::configs::{RayTransportConfig, RenderConfig};
-use crate::geometry::{BuiltScene, Geometry, Light, LightQuad, LightType, Material};
-use crate::raytracer::{Ray, RayTracer};
+use crate::geometry::{
+    BuiltScene, CurveShape, GeomInfo, Geometry, Light, LightQuad, LightType, Scene, SurfaceParams,
+    Volume, VolumeEvent,
+};
+use crate::hair::{Hair, HairFrame};
+use crate::medium::{Interface, Medium, MediumStack, transmittance};
+use crate::raytracer::{Ray, RayCone, RayHitResult, RayTracer};
+use crate::spectrum::{NUM_WAVELENGTHS, SampledWavelengths};
 use glam::Vec3;
 use image::{Rgb, Rgb32FImage};
 use std::sync::Arc;
@@ -12,6 +18,45 @@ use std::sync::atomic::AtomicUsize;
 pub const EPSILON: f32 = 1e-3;
 pub const AIR_REFRACT: f32 = 1.00029;
 
+/// State carried along a single path, copied into every ray spawned from it
+#[derive(Debug, Clone, Copy)]
+pub struct PathState {
+    /// only present when rendering spectrally
+    pub wavelengths: Option<SampledWavelengths>,
+    /// what the ray is currently inside of
+    pub media: MediumStack,
+    /// time of the camera ray, every bounce and shadow ray sees the scene at the same instant
+    pub time: f32,
+    /// footprint of the ray, moved to the origin of every bounce
+    pub cone: RayCone,
+}
+
+impl PathState {
+    /// converts an rgb color (albedo, emission, light power) into what the path is carrying
+    #[inline]
+    pub fn color(&self, color: Rgba) -> Rgba {
+        match &self.wavelengths {
+            Some(wavelengths) => wavelengths.uplift(color),
+            None => color,
+        }
+    }
+
+    #[inline]
+    pub fn hero_wavelength(&self) -> Option<f32> {
+        self.wavelengths.as_ref().map(SampledWavelengths::hero)
+    }
+
+    /// channels of a color that hold actual values (alpha is not one of them, unless rendering spectrally)
+    #[inline]
+    pub fn num_channels(&self) -> usize {
+        if self.wavelengths.is_some() {
+            NUM_WAVELENGTHS
+        } else {
+            3
+        }
+    }
+}
+
 pub struct Renderer<T: RayTracer> {
     scene: BuiltScene<T>,
     config: RenderConfig,
@@ -42,58 +87,84 @@ impl<T: RayTracer> Renderer<T> {
         Self { scene, config }
     }
 
+    pub fn into_scene(self) -> Scene {
+        self.scene.into_scene()
+    }
+
+    /// see `TextureRegistry::check_lazy_loads`, for after rendering
+    pub fn check_textures(&self) -> anyhow::Result<()> {
+        self.scene.textures.check_lazy_loads()
+    }
+
     pub fn render_pixel(&self, x: u32, y: u32, camera: &Camera) -> Rgb<f32> {
         let mut result = Rgba::BLACK;
 
         for _ in 0..self.config.rays_per_pixel {
-            let ray = camera.generate_ray(x, y, (fastrand::f32(), fastrand::f32()));
+            let Some(ray) = camera.generate_ray(
+                x,
+                y,
+                (fastrand::f32(), fastrand::f32()),
+                (fastrand::f32(), fastrand::f32()),
+                fastrand::f32(),
+            ) else {
+                continue;
+            };
 
-            result += self.trace(ray, AIR_REFRACT, 0) / self.config.rays_per_pixel as f32;
+            let state = PathState {
+                wavelengths: self
+                    .config
+                    .spectral
+                    .then(|| SampledWavelengths::sample_hero(fastrand::f32())),
+                media: MediumStack::new(self.scene.fog),
+                time: ray.time,
+                cone: ray.cone,
+            };
+
+            let mut radiance = self.trace(ray, 0, state);
+            if let Some(wavelengths) = &state.wavelengths {
+                radiance = wavelengths.to_rgb(radiance);
+            }
+
+            result += radiance / self.config.rays_per_pixel as f32;
         }
 
         result.into()
     }
 
     // TODO: repeated ifs, last division could be a multitplication
-    fn trace(&self, ray: Ray, refraction: f32, depth: u32) -> Rgba {
+    fn trace(&self, ray: Ray, depth: u32, mut state: PathState) -> Rgba {
         let mut color = Rgba::BLACK;
         let rand = fastrand::f32();
         if depth < self.config.min_depth || rand < self.config.over_depth_prob {
-            if let Some(hit) = self.scene.raytracer.intersect(ray) {
-                let geometry: &Geometry = self
-                    .scene
-                    .get_geometry(hit.geometry_id)
-                    .expect("Error getting geometry");
-                let material = &geometry.material;
-
-                let ray_dir = ray.direction;
-                let normal = hit.normal;
-                let hit_pos = hit.hit_point;
-                let u = hit.u;
-                let v = hit.v;
-                let triangle_id = hit.triangle_id;
-
-                let (diff, emissive) = self.scene.sample_color(geometry, triangle_id, u, v);
-
-                // lighting
-                color += self.direct_lighting(hit_pos, normal, &material, diff);
-
-                // diffuse and specular
-                match self.config.ray_transport {
-                    RayTransportConfig::MonteCarloSingle => {
-                        color += self.random_reflect_refract_scatter(
-                            hit_pos, ray_dir, normal, diff, refraction, material, depth,
-                        );
-                    }
-                    _ => {
-                        color += self.reflect_refract_scatter(
-                            hit_pos, ray_dir, normal, diff, refraction, material, depth,
-                        );
-                    }
+            let hit = self.intersect_surface(ray, &mut state);
+            let distance = hit.as_ref().map_or(f32::INFINITY, |(hit, _)| {
+                (hit.hit_point - ray.origin).length()
+            });
+
+            match self.sample_volumes(ray, distance, state) {
+                VolumeEvent::Absorbed => (),
+                VolumeEvent::Scatter {
+                    pos,
+                    weight,
+                    medium,
+                } => {
+                    let scatter_distance = (pos - ray.origin).length();
+                    color += self.scatter_in_medium(ray.direction, pos, &medium, depth, state)
+                        * weight
+                        * self.medium_transmittance(scatter_distance, state);
+                }
+                VolumeEvent::PassThrough(weight) => {
+                    color += weight
+                        * match state.media.current_medium() {
+                            Some(medium) => self.trace_medium(ray, hit, &medium, depth, state),
+                            None => hit.map_or_else(
+                                || self.background(ray, state),
+                                |(hit, interface)| {
+                                    self.shade_surface(ray, &hit, &interface, depth, state)
+                                },
+                            ),
+                        };
                 }
-
-                // emissive
-                color += emissive;
             }
         }
 
@@ -104,12 +175,457 @@ impl<T: RayTracer> Renderer<T> {
         color
     }
 
+    fn shade_surface(
+        &self,
+        ray: Ray,
+        hit: &RayHitResult,
+        interface: &Interface,
+        depth: u32,
+        state: PathState,
+    ) -> Rgba {
+        let mut color = Rgba::BLACK;
+
+        let geometry: &Geometry = self
+            .scene
+            .get_geometry(hit.geometry_id)
+            .expect("Error getting geometry");
+        let material = &geometry.material;
+
+        let ray_dir = ray.direction;
+        let normal = hit.normal;
+        let hit_pos = hit.hit_point;
+
+        let distance = (hit_pos - ray.origin).length();
+        let mut surface = self.scene.sample_surface(
+            geometry,
+            hit,
+            ray_dir,
+            state.cone.width_at(distance),
+            self.config.texture_filter,
+        );
+        // rays leaving the hit start as wide as the cone is here
+        let state = PathState {
+            cone: state.cone.advance(distance),
+            ..state
+        };
+        surface.diffuse = state.color(surface.diffuse);
+        let emissive = state.color(surface.emissive);
+
+        if let Some(hair) = &material.hair {
+            return self.shade_hair(ray, hit, geometry, hair, depth, state) + emissive;
+        }
+
+        // an ior map changes how much the ray bends here, and what it is inside of afterwards
+        let textured_interface;
+        let interface = if surface.ior_scale != 1.0 {
+            let ior = 1.0 + (material.ior(state.hero_wavelength()) - 1.0) * surface.ior_scale;
+            textured_interface = state.media.cross_with_ior(hit.geometry_id, material, ior);
+            &textured_interface
+        } else {
+            interface
+        };
+
+        // lighting
+        color += self.direct_lighting(hit_pos, normal, &surface, state);
+
+        // diffuse and specular
+        match self.config.ray_transport {
+            RayTransportConfig::MonteCarloSingle => {
+                color += self.random_reflect_refract_scatter(
+                    hit_pos, ray_dir, normal, interface, &surface, depth, state,
+                );
+            }
+            _ => {
+                color += self.reflect_refract_scatter(
+                    hit_pos, ray_dir, normal, interface, &surface, depth, state,
+                );
+            }
+        }
+
+        // emissive
+        color += emissive;
+
+        color
+    }
+
+    // for rays that leave the scene
+    fn background(&self, ray: Ray, state: PathState) -> Rgba {
+        let filter = self.config.texture_filter;
+        state.color(
+            self.scene
+                .background(ray.direction, state.cone.spread, filter),
+        )
+    }
+
+    // the hair model replaces diffuse, reflection and refraction
+    fn shade_hair(
+        &self,
+        ray: Ray,
+        hit: &RayHitResult,
+        geometry: &Geometry,
+        hair: &Hair,
+        depth: u32,
+        state: PathState,
+    ) -> Rgba {
+        let wo = -ray.direction;
+        let tangent = geometry
+            .tangent(hit.triangle_id, hit.u)
+            .unwrap_or_else(|| orthonormal_basis(hit.normal).0);
+        let frame = HairFrame::new(tangent, wo);
+
+        // flat curves have no real normal, they stand for a fiber seen from anywhere across it
+        let h = match &geometry.info {
+            GeomInfo::Curves(curves) if curves.shape == CurveShape::Flat => {
+                crate::common::randf32_normalized()
+            }
+            _ => frame.h_from_normal(hit.normal),
+        };
+        let bsdf = hair.bsdf(h, state.color(hair.sigma_a));
+        let wo_local = frame.to_local(wo);
+
+        // light goes through hair, so shadow rays leave on the side of the light
+        let eval = |dir: Vec3| bsdf.eval(wo_local, frame.to_local(dir));
+        let ambient = state.color(geometry.material.color);
+        let mut color =
+            self.weighted_direct_lighting(hit.hit_point, EPSILON, ambient, &eval, state);
+
+        let (wi, weight) = bsdf.sample(wo_local, state.num_channels());
+        if weight.to_array().iter().any(|w| *w > 0.0) {
+            let dir = frame.to_world(wi).normalize();
+            let bounce = Ray::new(hit.hit_point + dir * EPSILON, dir);
+            color += self.trace(bounce, depth + 1, state) * weight;
+        }
+
+        color
+    }
+
+    /// The ray travels through a participating medium until it reaches `hit`, or forever if there is none.
+    /// It can either get there (attenuated by beer's law) or scatter somewhere along the way
+    fn trace_medium(
+        &self,
+        ray: Ray,
+        hit: Option<(RayHitResult, Interface)>,
+        medium: &Medium,
+        depth: u32,
+        state: PathState,
+    ) -> Rgba {
+        let distance = hit.as_ref().map_or(f32::INFINITY, |(hit, _)| {
+            (hit.hit_point - ray.origin).length()
+        });
+        let sigma_t = state.color(medium.sigma_t());
+
+        let shade = |hit: Option<(RayHitResult, Interface)>| {
+            hit.map_or_else(
+                || self.background(ray, state),
+                |(hit, interface)| self.shade_surface(ray, &hit, &interface, depth, state),
+            )
+        };
+
+        if !medium.scatters() {
+            // only absorbs, no need to sample anything
+            return shade(hit) * transmittance(sigma_t, distance);
+        }
+
+        // the distance is sampled using a random channel, and weighted by the average pdf of all of them
+        // otherwise channels with very different coefficients would be very noisy
+        let num_channels = state.num_channels();
+        let channel_sigma_t = sigma_t.to_array()[randu32(0..num_channels as u32) as usize];
+        let scatter_distance = if channel_sigma_t > 0.0 {
+            -(1.0 - fastrand::f32()).ln() / channel_sigma_t
+        } else {
+            f32::INFINITY
+        };
+
+        if scatter_distance < distance {
+            let tr = transmittance(sigma_t, scatter_distance);
+            let pdf = channel_average(sigma_t * tr, num_channels);
+            let scatter_pos = ray.origin + ray.direction * scatter_distance;
+
+            self.scatter_in_medium(ray.direction, scatter_pos, medium, depth, state)
+                * tr
+                * state.color(medium.sigma_s)
+                / pdf
+        } else {
+            let tr = transmittance(sigma_t, distance);
+            let pdf = channel_average(tr, num_channels);
+            if pdf <= 0.0 {
+                return Rgba::BLACK;
+            }
+
+            shade(hit) * tr / pdf
+        }
+    }
+
+    /// light arriving at a point inside a medium where the ray scattered, not yet multiplied by sigma_s
+    fn scatter_in_medium(
+        &self,
+        ray_dir: Vec3,
+        pos: Vec3,
+        medium: &Medium,
+        depth: u32,
+        state: PathState,
+    ) -> Rgba {
+        // the phase function integrates to 1 over the sphere, so ambient light is left as is
+        let phase = |dir: Vec3| Rgba::WHITE * medium.phase(ray_dir.dot(dir));
+        let mut color = self.weighted_direct_lighting(pos, 0.0, Rgba::WHITE, &phase, state);
+
+        // the phase function is sampled exactly, its value and pdf cancel out
+        let scatter_ray = Ray::new(pos, sample_henyey_greenstein(ray_dir, medium.g));
+        color += self.trace(scatter_ray, depth + 1, state);
+
+        color
+    }
+
+    /// Delta tracking through the voxel grids, up to max_distance.
+    /// Uses spectral tracking (Kutz et al. 2017) so that coloured media do not need one path per channel
+    fn sample_volumes(&self, ray: Ray, max_distance: f32, state: PathState) -> VolumeEvent {
+        let mut weight = Rgba::WHITE;
+        if self.scene.volumes.is_empty() {
+            return VolumeEvent::PassThrough(weight);
+        }
+
+        let mut segments: Vec<(f32, f32, &Volume)> = self
+            .scene
+            .volumes
+            .iter()
+            .filter_map(|volume| {
+                volume
+                    .intersect(ray.origin, ray.direction, max_distance)
+                    .map(|(t0, t1)| (t0, t1, volume))
+            })
+            .collect();
+        segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let num_channels = state.num_channels();
+        for (t0, t1, volume) in segments {
+            let sigma = (
+                state.color(volume.medium.sigma_a),
+                state.color(volume.medium.sigma_s),
+            );
+            match volume.delta_tracking(
+                ray.origin,
+                ray.direction,
+                (t0, t1),
+                sigma,
+                num_channels,
+                weight,
+            ) {
+                VolumeEvent::PassThrough(new_weight) => weight = new_weight,
+                event => return event,
+            }
+        }
+
+        VolumeEvent::PassThrough(weight)
+    }
+
+    /// ratio tracking through the voxel grids
+    fn volume_transmittance(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        distance: f32,
+        state: PathState,
+    ) -> Rgba {
+        let mut tr = Rgba::WHITE;
+        let num_channels = state.num_channels();
+
+        for volume in &self.scene.volumes {
+            if let Some(range) = volume.intersect(origin, dir, distance) {
+                let sigma_t = state.color(volume.medium.sigma_t());
+                tr = tr * volume.ratio_tracking(origin, dir, range, sigma_t, num_channels);
+            }
+        }
+
+        tr
+    }
+
+    /// beer's law for the homogeneous medium the path is in, if any
+    fn medium_transmittance(&self, distance: f32, state: PathState) -> Rgba {
+        match state.media.current_medium() {
+            Some(medium) => transmittance(state.color(medium.sigma_t()), distance),
+            None => Rgba::WHITE,
+        }
+    }
+
+    /// how much light makes it along a shadow ray that was not blocked by any surface
+    fn shadow_transmittance(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        distance: f32,
+        state: PathState,
+    ) -> Rgba {
+        let mut tr = self.medium_transmittance(distance, state);
+        if !self.scene.volumes.is_empty() {
+            tr = tr * self.volume_transmittance(origin, dir, distance, state);
+        }
+        tr
+    }
+
+    // same as direct_lighting, but `weight` (a phase function, a bsdf times the cosine)
+    // takes the place of the diffuse color and cosine. it gets the direction to the light.
+    // shadow rays start `offset` towards the light, ambient light is multiplied by `ambient`
+    fn weighted_direct_lighting(
+        &self,
+        pos: Vec3,
+        offset: f32,
+        ambient: Rgba,
+        weight: &impl Fn(Vec3) -> Rgba,
+        state: PathState,
+    ) -> Rgba {
+        let lights = &self.scene.lights;
+        let mut color = Rgba::BLACK;
+
+        if self.config.compare_all_lights {
+            for light in lights.iter() {
+                color += self.weighted_handle_light(light, pos, offset, ambient, weight, state);
+            }
+        } else if let Some(light) = fastrand::choice(lights) {
+            color += self.weighted_handle_light(light, pos, offset, ambient, weight, state);
+            color *= lights.len() as f32;
+        }
+
+        color
+    }
+
+    fn weighted_handle_light(
+        &self,
+        light: &Light,
+        pos: Vec3,
+        offset: f32,
+        ambient: Rgba,
+        weight: &impl Fn(Vec3) -> Rgba,
+        state: PathState,
+    ) -> Rgba {
+        let light_color = state.color(light.color);
+
+        let visible = |light_pos: Vec3| -> Option<Rgba> {
+            let to_light = light_pos - pos;
+            let distance_to_light = to_light.length();
+            if distance_to_light <= 0.0 {
+                return None;
+            }
+            let dir_to_light = to_light / distance_to_light;
+            let origin = pos + dir_to_light * offset;
+
+            let shadow_ray =
+                Ray::new_with_max_distance(origin, dir_to_light, distance_to_light - EPSILON);
+            if self
+                .scene
+                .raytracer
+                .intersect(shadow_ray.with_time(state.time))
+                .is_some()
+            {
+                return None;
+            }
+
+            Some(
+                light_color
+                    * self.shadow_transmittance(origin, dir_to_light, distance_to_light, state)
+                    * weight(dir_to_light)
+                    / (distance_to_light * distance_to_light),
+            )
+        };
+
+        match &light.light_type {
+            LightType::Ambient => light_color * ambient,
+            LightType::Point(light_pos) => visible(*light_pos).unwrap_or(Rgba::BLACK),
+            LightType::Spot {
+                pos: light_pos,
+                direction,
+                inner_cone,
+                outer_cone,
+            } => {
+                let falloff = spot_falloff(*direction, *inner_cone, *outer_cone, pos - *light_pos);
+                if falloff > 0.0 {
+                    visible(*light_pos).map_or(Rgba::BLACK, |color| color * falloff)
+                } else {
+                    Rgba::BLACK
+                }
+            }
+            LightType::Directional(direction) => {
+                let dir_to_light = -direction.normalize();
+                let origin = pos + dir_to_light * offset;
+                let shadow_ray = Ray::new(origin, dir_to_light);
+                if self
+                    .scene
+                    .raytracer
+                    .intersect(shadow_ray.with_time(state.time))
+                    .is_some()
+                {
+                    return Rgba::BLACK;
+                }
+
+                light_color
+                    * self.shadow_transmittance(origin, dir_to_light, f32::INFINITY, state)
+                    * weight(dir_to_light)
+            }
+            LightType::AreaQuad(square) => {
+                let mut color = Rgba::BLACK;
+                for _ in 0..self.config.num_area_light_tests {
+                    let light_pos = square.bottom_left
+                        + (fastrand::f32() * square.u_vec)
+                        + (fastrand::f32() * square.v_vec);
+                    if let Some(contribution) = visible(light_pos) {
+                        let light_cos = (light_pos - pos).normalize().dot(square.normal).abs();
+                        color += contribution * light_cos / self.config.num_area_light_tests as f32;
+                    }
+                }
+                color
+            }
+        }
+    }
+
+    /// Closest surface that actually exists optically, skipping the ones `MediumStack` says to ignore.
+    /// Updates the media of `state` with every skipped surface
+    fn intersect_surface(
+        &self,
+        mut ray: Ray,
+        state: &mut PathState,
+    ) -> Option<(RayHitResult, Interface)> {
+        loop {
+            let hit = self.scene.raytracer.intersect(ray.with_time(state.time))?;
+            let geometry = self
+                .scene
+                .get_geometry(hit.geometry_id)
+                .expect("Error getting geometry");
+
+            let interface =
+                state
+                    .media
+                    .cross(hit.geometry_id, &geometry.material, state.hero_wavelength());
+
+            // the transparency of this spot, a map can make parts of the surface opaque
+            let see_through = || {
+                let distance = (hit.hit_point - ray.origin).length();
+                let surface = self.scene.sample_surface(
+                    geometry,
+                    &hit,
+                    ray.direction,
+                    state.cone.width_at(distance),
+                    self.config.texture_filter,
+                );
+                surface.transparency > 0.0
+            };
+
+            if interface.ignored && see_through() {
+                state.media = interface.refracted;
+                let new_origin = hit.hit_point + ray.direction * EPSILON;
+                ray.max_distance -= (new_origin - ray.origin).length();
+                ray.origin = new_origin;
+                continue;
+            }
+
+            return Some((hit, interface));
+        }
+    }
+
     pub fn direct_lighting(
         &self,
         hit_pos: Vec3,
         normal: Vec3,
-        material: &Material,
-        diffuse: Rgba,
+        surface: &SurfaceParams,
+        state: PathState,
     ) -> Rgba {
         let mut color = Rgba::BLACK;
 
@@ -118,10 +634,10 @@ impl<T: RayTracer> Renderer<T> {
         if self.config.compare_all_lights {
             // loop over all light sources
             for light in lights.iter() {
-                color += self.handle_light(light, hit_pos, normal, material, diffuse);
+                color += self.handle_light(light, hit_pos, normal, surface, state);
             }
         } else if let Some(light) = fastrand::choice(lights) {
-            color += self.handle_light(light, hit_pos, normal, material, diffuse);
+            color += self.handle_light(light, hit_pos, normal, surface, state);
             color *= lights.len() as f32;
         }
 
@@ -133,15 +649,15 @@ impl<T: RayTracer> Renderer<T> {
         hit: Vec3,
         incident_dir: Vec3,
         normal: Vec3,
-        material: &Material,
+        surface: &SurfaceParams,
         depth: u32,
-        n1: f32,
         reflect: f32,
+        state: PathState,
     ) -> Rgba {
         let mut color = Rgba::BLACK;
 
-        if reflect > 0.0 && material.reflectivity > 0.0 {
-            let refdir = incident_dir.reflect(normal);
+        if reflect > 0.0 && surface.reflectivity > 0.0 {
+            let refdir = roughen(incident_dir.reflect(normal), normal, surface.roughness);
 
             let mut offset = EPSILON * normal;
             if refdir.dot(normal) < 0.0 {
@@ -149,13 +665,18 @@ impl<T: RayTracer> Renderer<T> {
             }
             let origin = hit + offset;
 
-            let refraction = n1; // medium did not change
-
-            let reflection_ray = Ray::new(origin, refdir);
+            if let Some(probe) = surface.reflection_probe {
+                let filter = self.config.texture_filter;
+                let probe = self.scene.textures.cube_map(probe);
+                color += state.color(probe.sample(refdir, state.cone.spread, filter));
+            } else {
+                let reflection_ray = Ray::new(origin, refdir);
 
-            color += self.trace(reflection_ray, refraction, depth + 1);
+                // medium did not change
+                color += self.trace(reflection_ray, depth + 1, state);
+            }
 
-            color = material.specular * color * reflect; // no need to multiply by reflectivity of the material, fresnel already takes it into account
+            color = state.color(surface.specular) * color * reflect; // no need to multiply by reflectivity of the material, fresnel already takes it into account
         }
 
         color
@@ -166,22 +687,28 @@ impl<T: RayTracer> Renderer<T> {
         hit: Vec3,
         incident_dir: Vec3,
         normal: Vec3,
-        material: &Material,
+        surface: &SurfaceParams,
         depth: u32,
         n1: f32,
         n2: f32,
         refract: f32,
+        state: PathState,
+        refracted_media: MediumStack,
     ) -> Rgba {
         let mut color = Rgba::BLACK;
 
-        if refract > 0.0 && material.transparency > 0.0 {
+        if refract > 0.0 && surface.transparency > 0.0 {
             let eta = n1 / n2; // Ratio of IORs
             let facing_normal = if incident_dir.dot(normal) < 0.0 {
                 normal
             } else {
                 -normal
             };
-            let refract_dir = incident_dir.refract(facing_normal, eta);
+            let refract_dir = roughen(
+                incident_dir.refract(facing_normal, eta),
+                facing_normal,
+                surface.roughness,
+            );
 
             if refract_dir != Vec3::ZERO {
                 // Refraction succeeded (no TIR)
@@ -192,15 +719,28 @@ impl<T: RayTracer> Renderer<T> {
                 }
                 let origin = hit + offset;
 
-                let refraction = n2; // New medium’s IOR
-
                 let refraction_ray = Ray::new(origin, refract_dir);
 
-                color += self.trace(refraction_ray, refraction, depth + 1);
+                let mut refracted_state = state;
+                refracted_state.media = refracted_media;
+
+                // the direction now depends on the wavelength, only the hero one can keep going
+                let mut dispersed = false;
+                if surface.dispersive
+                    && let Some(wavelengths) = &mut refracted_state.wavelengths
+                {
+                    dispersed = !wavelengths.secondary_terminated();
+                    wavelengths.terminate_secondary();
+                }
 
-                color = material.transmission * color * material.transparency * refract;
-                // here I use transparency to imply some of the light is lost/absorbed when refracting
-                // this is technically not needed and not correct, but beer's law is not yet implemented so this will have to do for now
+                color += self.trace(refraction_ray, depth + 1, refracted_state);
+                if dispersed {
+                    color = SampledWavelengths::collapse_to_hero(color);
+                }
+
+                color = state.color(surface.transmission) * color * surface.transparency * refract;
+                // transparency is how much of the light goes through the surface at all
+                // absorption inside the object is done by its medium (beer's law), see trace_medium
             }
         }
 
@@ -214,16 +754,15 @@ impl<T: RayTracer> Renderer<T> {
         &self,
         hit: Vec3,
         normal: Vec3,
-        material: &Material,
+        surface: &SurfaceParams,
         depth: u32,
-        n1: f32,
         refract: f32,
-        diff: Rgba,
         num: u32,
+        state: PathState,
     ) -> Rgba {
         let mut color = Rgba::BLACK;
 
-        if depth == 0 && material.transparency <= 0.0 {
+        if depth == 0 && surface.transparency <= 0.0 {
             // takes what's left after reflection
             let diffuse_weight = refract * self.config.diffuse_strength;
 
@@ -233,10 +772,11 @@ impl<T: RayTracer> Renderer<T> {
                     let offset = EPSILON * normal;
                     let origin = hit + offset;
                     let scatter_ray = Ray::new(origin, scatter_dir);
-                    let scattered_color = self.trace(scatter_ray, n1, depth + 1);
+                    let scattered_color = self.trace(scatter_ray, depth + 1, state);
                     let cos_theta = scatter_dir.dot(normal).max(0.0);
 
-                    color += (scattered_color * diff * diffuse_weight * cos_theta) / num as f32;
+                    color += (scattered_color * surface.diffuse * diffuse_weight * cos_theta)
+                        / num as f32;
                 }
             }
         }
@@ -250,18 +790,17 @@ impl<T: RayTracer> Renderer<T> {
         &self,
         hit: Vec3,
         normal: Vec3,
-        material: &Material,
+        surface: &SurfaceParams,
         depth: u32,
-        n1: f32,
         refract: f32,
-        diff: Rgba,
         prob: f32,
+        state: PathState,
     ) -> Rgba {
         let mut color = Rgba::BLACK;
 
         // TODO: check if diff != 0 before starting?
 
-        if material.transparency <= 0.0 {
+        if surface.transparency <= 0.0 {
             // randomly choose if we scatter
             if fastrand::f32() <= prob {
                 // takes what's left after reflection
@@ -272,10 +811,11 @@ impl<T: RayTracer> Renderer<T> {
                     let offset = EPSILON * normal;
                     let origin = hit + offset;
                     let scatter_ray = Ray::new(origin, scatter_dir);
-                    let scattered_color = self.trace(scatter_ray, n1, depth + 1);
+                    let scattered_color = self.trace(scatter_ray, depth + 1, state);
                     let cos_theta = scatter_dir.dot(normal).max(0.0);
 
-                    color += (scattered_color * diff * diffuse_weight * cos_theta) / prob;
+                    color +=
+                        (scattered_color * surface.diffuse * diffuse_weight * cos_theta) / prob;
                 }
             }
         }
@@ -288,46 +828,44 @@ impl<T: RayTracer> Renderer<T> {
         hit: Vec3,
         incident_dir: Vec3,
         normal: Vec3,
-        diff: Rgba,
-        ray_eta: f32,
-        material: &Material,
+        interface: &Interface,
+        surface: &SurfaceParams,
         depth: u32,
+        state: PathState,
     ) -> Rgba {
         // n1 is refraction being left
         // n2 is refraction being entered
+        let n1 = interface.n1;
+        let n2 = interface.n2;
 
-        let n1: f32;
-        let n2: f32;
-
-        // WARNING nao tenho como saber se o raio esta a sair ou entrar no material,
-        // nem qual o indice de refracao que devo usar ao sair, entao tive de fazer esta manhosice
-        if ray_eta == AIR_REFRACT {
-            // raytracer is coming from outside, entering
-            n1 = AIR_REFRACT;
-            n2 = material.refraction;
-        } else {
-            // raytracer is leaving
-            n1 = material.refraction;
-            n2 = AIR_REFRACT;
-        }
-
-        let reflect = compute_reflection_coeff(incident_dir, normal, n1, n2, material.reflectivity);
+        let reflect = compute_reflection_coeff(incident_dir, normal, n1, n2, surface.reflectivity);
         let refract = 1.0 - reflect;
 
         // reflection, for materials with reflectivity
-        let mut color = self.reflect(hit, incident_dir, normal, material, depth, n1, reflect);
+        let mut color = self.reflect(hit, incident_dir, normal, surface, depth, reflect, state);
 
         // refraction, for materials with transparency
-        color += self.refract(hit, incident_dir, normal, material, depth, n1, n2, refract);
+        color += self.refract(
+            hit,
+            incident_dir,
+            normal,
+            surface,
+            depth,
+            n1,
+            n2,
+            refract,
+            state,
+            interface.refracted,
+        );
 
         // scattering
         // uses what's left after reflection (like refraction), but assumes the material does not refract
         match self.config.ray_transport {
             RayTransportConfig::MonteCarloScatter(prob) => {
-                color += self.scatter_rand(hit, normal, material, depth, n1, refract, diff, prob);
+                color += self.scatter_rand(hit, normal, surface, depth, refract, prob, state);
             }
             RayTransportConfig::LoopScatter(num) => {
-                color += self.loop_scatter(hit, normal, material, depth, n1, refract, diff, num);
+                color += self.loop_scatter(hit, normal, surface, depth, refract, num, state);
             }
             _ => (),
         }
@@ -341,30 +879,17 @@ impl<T: RayTracer> Renderer<T> {
         hit: Vec3,
         incident_dir: Vec3,
         normal: Vec3,
-        diff: Rgba,
-        ray_eta: f32,
-        material: &Material,
+        interface: &Interface,
+        surface: &SurfaceParams,
         depth: u32,
+        state: PathState,
     ) -> Rgba {
         // n1 is refraction being left
         // n2 is refraction being entered
+        let n1 = interface.n1;
+        let n2 = interface.n2;
 
-        let n1: f32;
-        let n2: f32;
-
-        // WARNING nao tenho como saber se o raio esta a sair ou entrar no material,
-        // nem qual o indice de refracao que devo usar ao sair, entao tive de fazer esta manhosice
-        if ray_eta == AIR_REFRACT {
-            // raytracer is coming from outside, entering
-            n1 = AIR_REFRACT;
-            n2 = material.refraction;
-        } else {
-            // raytracer is leaving
-            n1 = material.refraction;
-            n2 = AIR_REFRACT;
-        }
-
-        let reflect = compute_reflection_coeff(incident_dir, normal, n1, n2, material.reflectivity);
+        let reflect = compute_reflection_coeff(incident_dir, normal, n1, n2, surface.reflectivity);
         let refract = 1.0 - reflect;
 
         let rand = fastrand::f32();
@@ -372,16 +897,27 @@ impl<T: RayTracer> Renderer<T> {
 
         if rand < 1.0 / NUM_CHOICES {
             // reflection, for materials with reflectivity
-            self.reflect(hit, incident_dir, normal, material, depth, n1, reflect) * NUM_CHOICES
+            self.reflect(hit, incident_dir, normal, surface, depth, reflect, state) * NUM_CHOICES
         } else if rand < 2.0 / NUM_CHOICES {
             // refraction, for materials with transparency
-            self.refract(hit, incident_dir, normal, material, depth, n1, n2, refract) * NUM_CHOICES
+            self.refract(
+                hit,
+                incident_dir,
+                normal,
+                surface,
+                depth,
+                n1,
+                n2,
+                refract,
+                state,
+                interface.refracted,
+            ) * NUM_CHOICES
         } else {
             // scatter
             // scattering
             // uses what's left after reflection (like refraction), but assumes the material does not refract
             // no loop scattering for now
-            self.loop_scatter(hit, normal, material, depth, n1, refract, diff, 1)
+            self.loop_scatter(hit, normal, surface, depth, refract, 1, state)
         }
     }
 
@@ -390,31 +926,92 @@ impl<T: RayTracer> Renderer<T> {
         light: &Light,
         hit_pos: Vec3,
         normal: Vec3,
-        material: &Material,
-        diffuse: Rgba,
+        surface: &SurfaceParams,
+        state: PathState,
     ) -> Rgba {
+        let light_color = state.color(light.color);
+        let diffuse = surface.diffuse;
         match &light.light_type {
-            LightType::Ambient => Self::handle_ambient_light(light, material),
+            LightType::Ambient => light_color * state.color(surface.ambient),
             LightType::Point(light_pos) => {
-                self.handle_point_light(light, diffuse, hit_pos, normal, *light_pos)
+                self.handle_point_light(light_color, diffuse, hit_pos, normal, *light_pos, state)
             }
             LightType::AreaQuad(square) => {
-                self.handle_square_light(light, diffuse, square, hit_pos, normal)
+                self.handle_square_light(light_color, diffuse, square, hit_pos, normal, state)
+            }
+            LightType::Spot {
+                pos,
+                direction,
+                inner_cone,
+                outer_cone,
+            } => {
+                let falloff = spot_falloff(*direction, *inner_cone, *outer_cone, hit_pos - *pos);
+                if falloff > 0.0 {
+                    self.handle_point_light(
+                        light_color * falloff,
+                        diffuse,
+                        hit_pos,
+                        normal,
+                        *pos,
+                        state,
+                    )
+                } else {
+                    Rgba::BLACK
+                }
             }
+            LightType::Directional(direction) => self.handle_directional_light(
+                light_color,
+                diffuse,
+                hit_pos,
+                normal,
+                *direction,
+                state,
+            ),
         }
     }
 
-    fn handle_ambient_light(light: &Light, material: &Material) -> Rgba {
-        light.color * material.color
+    // like a point light infinitely far away, so no falloff
+    fn handle_directional_light(
+        &self,
+        light_color: Rgba,
+        diffuse: Rgba,
+        hit_pos: Vec3,
+        normal: Vec3,
+        direction: Vec3,
+        state: PathState,
+    ) -> Rgba {
+        let dir_to_light = -direction.normalize();
+
+        let light_cos = dir_to_light.dot(normal);
+        if light_cos <= 0.0 {
+            return Rgba::BLACK;
+        }
+
+        let shadow_ray_origin = hit_pos + EPSILON * normal;
+        let shadow_ray = Ray::new(shadow_ray_origin, dir_to_light);
+        if self
+            .scene
+            .raytracer
+            .intersect(shadow_ray.with_time(state.time))
+            .is_some()
+        {
+            return Rgba::BLACK;
+        }
+
+        light_color
+            * diffuse
+            * light_cos
+            * self.shadow_transmittance(shadow_ray_origin, dir_to_light, f32::INFINITY, state)
     }
 
     fn handle_point_light(
         &self,
-        light: &Light,
+        light_color: Rgba,
         diffuse: Rgba,
         hit_pos: Vec3,
         normal: Vec3,
         light_pos: Vec3,
+        state: PathState,
     ) -> Rgba {
         // if material.diffuse.red > 0.0 || material.diffuse.green > 0.0 || material.diffuse.blue > 0.0
         // {
@@ -440,11 +1037,22 @@ impl<T: RayTracer> Renderer<T> {
             );
 
             // we have a direct path to the light, can add direct illumination
-            if let None = self.scene.raytracer.intersect(shadow_ray) {
-                let mut color = light.color * diffuse * light_cos;
+            if let None = self
+                .scene
+                .raytracer
+                .intersect(shadow_ray.with_time(state.time))
+            {
+                let mut color = light_color * diffuse * light_cos;
                 if distance_to_light > 0.0 {
                     color /= distance_to_light * distance_to_light;
                 }
+                color = color
+                    * self.shadow_transmittance(
+                        shadow_ray_origin,
+                        dir_to_light,
+                        distance_to_light,
+                        state,
+                    );
 
                 return color;
             }
@@ -457,11 +1065,12 @@ impl<T: RayTracer> Renderer<T> {
     // randomly select N points on the light and make them act as individual point lights
     fn handle_square_light(
         &self,
-        light: &Light,
+        light_color: Rgba,
         diff: Rgba,
         square: &LightQuad,
         hit_pos: Vec3,
         normal: Vec3,
+        state: PathState,
     ) -> Rgba {
         // if material.diffuse.red > 0.0 || material.diffuse.green > 0.0 || material.diffuse.blue > 0.0
         // {
@@ -498,15 +1107,27 @@ impl<T: RayTracer> Renderer<T> {
 
                 // we have a direct path to the light, can add direct illumination
                 if distance_to_light > 0.0 {
-                    if let None = self.scene.raytracer.intersect(shadow_ray) {
+                    if let None = self
+                        .scene
+                        .raytracer
+                        .intersect(shadow_ray.with_time(state.time))
+                    {
                         // color of the light * color of object * cos from the object to the light
                         // attenuation based on distance^2
                         // since N random points are sampled, monte carlo
                         // FOR SOME REASON?? also * the cos between the geometric normal of the light and dir to light
-                        color += (light.color * diff * light_cos)
+                        let mut contribution = (light_color * diff * light_cos)
                             / (distance_to_light * distance_to_light)
                             / self.config.num_area_light_tests as f32
                             * (dir_to_light.dot(square.normal)).abs();
+                        contribution = contribution
+                            * self.shadow_transmittance(
+                                shadow_ray_origin,
+                                dir_to_light,
+                                distance_to_light,
+                                state,
+                            );
+                        color += contribution;
                     }
                 }
             }
@@ -516,6 +1137,30 @@ impl<T: RayTracer> Renderer<T> {
     }
 }
 
+// blurs a mirror or refraction direction, by roughness squared so that the low values are more useful.
+// directions pushed to the other side of the surface stay sharp
+fn roughen(dir: Vec3, normal: Vec3, roughness: f32) -> Vec3 {
+    if roughness <= 0.0 || dir == Vec3::ZERO {
+        return dir;
+    }
+    let rough = (dir + rand_dir() * roughness * roughness).normalize_or(dir);
+    if (rough.dot(normal) < 0.0) == (dir.dot(normal) < 0.0) {
+        rough
+    } else {
+        dir
+    }
+}
+
+// smooth falloff between the inner and outer cone, same as the glTF spec
+// to_point goes from the light to the point being lit
+fn spot_falloff(direction: Vec3, inner_cone: f32, outer_cone: f32, to_point: Vec3) -> f32 {
+    let cos_outer = outer_cone.cos();
+    let scale = 1.0 / (inner_cone.cos() - cos_outer).max(0.001);
+    let cd = direction.normalize().dot(to_point.normalize());
+    let falloff = ((cd - cos_outer) * scale).clamp(0.0, 1.0);
+    falloff * falloff
+}
+
 fn sample_cos_hemisphere(normal: Vec3) -> Vec3 {
     // Two random numbers in [0, 1)
     let e1 = fastrand::f32();
@@ -536,6 +1181,37 @@ fn sample_cos_hemisphere(normal: Vec3) -> Vec3 {
     u * local_dir.x + v * local_dir.y + w * local_dir.z
 }
 
+// cos_theta is relative to the direction the ray was already travelling in, so g > 0 scatters forward
+fn sample_henyey_greenstein(dir: Vec3, g: f32) -> Vec3 {
+    let e1 = fastrand::f32();
+    let e2 = fastrand::f32();
+
+    let cos_theta = if g.abs() < 1e-3 {
+        // isotropic
+        1.0 - 2.0 * e1
+    } else {
+        let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * e1);
+        (1.0 + g * g - sq * sq) / (2.0 * g)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * e2;
+
+    let (u, v, w) = orthonormal_basis(dir);
+    u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta
+}
+
+// see PathState::num_channels
+pub fn channel_average(color: Rgba, num_channels: usize) -> f32 {
+    color.to_array()[..num_channels].iter().sum::<f32>() / num_channels as f32
+}
+
+pub fn channel_max(color: Rgba, num_channels: usize) -> f32 {
+    color.to_array()[..num_channels]
+        .iter()
+        .copied()
+        .fold(0.0, f32::max)
+}
+
 fn orthonormal_basis(normal: Vec3) -> (Vec3, Vec3, Vec3) {
     let w = normal; // Normal is already normalized
     let a = if w.x.abs() > 0.9 { Vec3::Y } else { Vec3::X }; // Avoid parallel vectors
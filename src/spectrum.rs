@@ -0,0 +1,233 @@
+use crate::color::Rgba;
+
+// visible range that is sampled, CIE 1931 colour matching functions are ~0 outside of it
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+/// one wavelength per `Rgba` channel. while in spectral mode, each channel of every color
+/// the renderer deals with holds the value at the corresponding wavelength instead of r, g, b, a
+pub const NUM_WAVELENGTHS: usize = 4;
+
+// integral of y bar over the visible range, used to normalize so that a constant spectrum of 1.0 has Y = 1.0
+const CIE_Y_INTEGRAL: f32 = 106.856895;
+
+/// Hero wavelength sampling (Wilkie et al. 2014):
+/// the hero wavelength is uniformly sampled and the others are evenly spaced from it, wrapping around the range
+#[derive(Debug, Clone, Copy)]
+pub struct SampledWavelengths {
+    pub lambda: [f32; NUM_WAVELENGTHS],
+    secondary_terminated: bool,
+}
+
+impl SampledWavelengths {
+    pub fn sample_hero(u: f32) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = LAMBDA_MIN + u * range;
+        let delta = range / NUM_WAVELENGTHS as f32;
+
+        let mut lambda = [hero; NUM_WAVELENGTHS];
+        for (i, l) in lambda.iter_mut().enumerate().skip(1) {
+            *l = hero + delta * i as f32;
+            if *l > LAMBDA_MAX {
+                *l -= range;
+            }
+        }
+
+        Self {
+            lambda,
+            secondary_terminated: false,
+        }
+    }
+
+    #[inline]
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.secondary_terminated
+    }
+
+    /// Used when the path direction starts depending on the wavelength (dispersion).
+    /// Radiance that comes back through a terminated path should go through `collapse_to_hero`
+    pub fn terminate_secondary(&mut self) {
+        self.secondary_terminated = true;
+    }
+
+    /// only the hero wavelength is valid, it now has to account for all of them
+    pub fn collapse_to_hero(radiance: Rgba) -> Rgba {
+        let [hero, ..] = radiance.to_array();
+        Rgba::from_array([hero * NUM_WAVELENGTHS as f32, 0.0, 0.0, 0.0])
+    }
+
+    /// RGB albedo or emission to the value of its spectrum at each wavelength
+    pub fn uplift(&self, color: Rgba) -> Rgba {
+        let [r, g, b, _] = color.to_array();
+        Rgba::from_array(self.lambda.map(|l| rgb_to_spectrum(r, g, b, l)))
+    }
+
+    /// spectral radiance at each wavelength to linear sRGB, through CIE XYZ
+    pub fn to_rgb(self, radiance: Rgba) -> Rgba {
+        let values = radiance.to_array();
+
+        // monte carlo estimate of the integral of L * cmf with a uniform pdf
+        let inv_pdf = LAMBDA_MAX - LAMBDA_MIN;
+        let mut xyz = [0.0_f32; 3];
+        for (l, value) in self.lambda.iter().zip(values) {
+            let (x, y, z) = cie_xyz(*l);
+            xyz[0] += value * x;
+            xyz[1] += value * y;
+            xyz[2] += value * z;
+        }
+        let k = inv_pdf / (NUM_WAVELENGTHS as f32 * CIE_Y_INTEGRAL);
+
+        let [r, g, b] = xyz_to_linear_srgb(xyz[0] * k, xyz[1] * k, xyz[2] * k);
+
+        // white balance, so that a constant spectrum (illuminant E) stays white
+        Rgba::rgb(
+            r / WHITE_BALANCE[0],
+            g / WHITE_BALANCE[1],
+            b / WHITE_BALANCE[2],
+        )
+    }
+}
+
+// sRGB of XYZ = (1, 1, 1), which is what a constant spectrum integrates to
+const WHITE_BALANCE: [f32; 3] = [1.2048, 0.9484, 0.9087];
+
+fn xyz_to_linear_srgb(x: f32, y: f32, z: f32) -> [f32; 3] {
+    [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ]
+}
+
+#[inline]
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// smooth basis functions that always add up to 1, so (1, 1, 1) is a constant spectrum
+// and any rgb in [0, 1] is a valid reflectance. not an exact inverse of to_rgb, but close enough for albedos
+fn rgb_to_spectrum(r: f32, g: f32, b: f32, lambda: f32) -> f32 {
+    let blue = 1.0 - smoothstep(470.0, 510.0, lambda);
+    let red = smoothstep(565.0, 605.0, lambda);
+    let green = 1.0 - blue - red;
+
+    r * red + g * green + b * blue
+}
+
+#[inline]
+fn piecewise_gaussian(x: f32, mu: f32, sigma1: f32, sigma2: f32) -> f32 {
+    let t = (x - mu) / if x < mu { sigma1 } else { sigma2 };
+    (-0.5 * t * t).exp()
+}
+
+/// analytic multi-lobe fit of the CIE 1931 2 degree observer (Wyman, Sloan and Shirley 2013)
+pub fn cie_xyz(lambda: f32) -> (f32, f32, f32) {
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+
+    (x, y, z)
+}
+
+/// How the index of refraction of a material changes with the wavelength.
+/// Only used in spectral mode, otherwise `Material::refraction` is used for everything
+#[derive(Debug, Clone, Copy)]
+pub enum Dispersion {
+    None,
+    /// n = a + b / λ², λ in micrometers
+    Cauchy {
+        a: f32,
+        b: f32,
+    },
+    /// n² = 1 + Σ b_i λ² / (λ² - c_i), λ in micrometers
+    Sellmeier {
+        b: [f32; 3],
+        c: [f32; 3],
+    },
+}
+
+impl Dispersion {
+    /// BK7 crown glass
+    pub const BK7: Self = Self::Sellmeier {
+        b: [1.039_612, 0.231_792_35, 1.010_469_5],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    /// None if the index does not depend on the wavelength
+    pub fn ior(&self, lambda_nm: f32) -> Option<f32> {
+        let l = lambda_nm / 1000.0;
+        let l2 = l * l;
+        match self {
+            Self::None => None,
+            Self::Cauchy { a, b } => Some(a + b / l2),
+            Self::Sellmeier { b, c } => {
+                let n2 = 1.0 + b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum::<f32>();
+                Some(n2.max(1.0).sqrt())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hero_wavelengths_are_evenly_spaced() {
+        let wavelengths = SampledWavelengths::sample_hero(0.9);
+        let mut lambda = wavelengths.lambda;
+        lambda.sort_by(f32::total_cmp);
+        let delta = (LAMBDA_MAX - LAMBDA_MIN) / NUM_WAVELENGTHS as f32;
+        for pair in lambda.windows(2) {
+            assert!((pair[1] - pair[0] - delta).abs() < 1e-3);
+        }
+        assert!(lambda.iter().all(|l| (LAMBDA_MIN..=LAMBDA_MAX).contains(l)));
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let n = 1000;
+        let mut sum = [0.0; 3];
+        for i in 0..n {
+            let wavelengths = SampledWavelengths::sample_hero((i as f32 + 0.5) / n as f32);
+            let [r, g, b, _] = wavelengths
+                .to_rgb(wavelengths.uplift(Rgba::WHITE))
+                .to_array();
+            sum = [sum[0] + r, sum[1] + g, sum[2] + b];
+        }
+        for c in sum {
+            assert!((c / n as f32 - 1.0).abs() < 0.02, "{sum:?}");
+        }
+    }
+
+    #[test]
+    fn bk7_at_the_fraunhofer_lines() {
+        let ior = |lambda| Dispersion::BK7.ior(lambda).unwrap();
+        // from the Schott datasheet
+        assert!((ior(486.1) - 1.5224).abs() < 1e-3);
+        assert!((ior(587.6) - 1.5168).abs() < 1e-3);
+        assert!((ior(656.3) - 1.5143).abs() < 1e-3);
+    }
+
+    #[test]
+    fn cauchy_and_none() {
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.01 };
+        assert!((cauchy.ior(500.0).unwrap() - 1.54).abs() < 1e-5);
+        assert!(cauchy.is_dispersive());
+        assert_eq!(Dispersion::None.ior(500.0), None);
+        assert!(!Dispersion::None.is_dispersive());
+    }
+}